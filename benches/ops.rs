@@ -0,0 +1,81 @@
+//! Rough timings of the basic operations, printed by `cargo bench`.
+//!
+//! Nothing here is asserted; the workload tests in the crate's own test suite
+//! are what catch a read or a write suddenly doing far more work. These are
+//! for comparing one build against another on the same machine.
+
+use std::{
+    fs,
+    hint::black_box,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use anyhow::Result;
+use mini_lsm::{Lsm, Options};
+
+const KEYS: u64 = 20_000;
+
+/// Runs `op` on each of `0..iterations` and prints the mean time per call.
+fn bench(name: &str, iterations: u64, mut op: impl FnMut(u64) -> Result<()>) -> Result<()> {
+    let start = Instant::now();
+    for i in 0..iterations {
+        op(i)?;
+    }
+    let per_op = start.elapsed().as_nanos() as f64 / iterations as f64;
+    println!("{name:<16} {per_op:>12.0} ns/op");
+    Ok(())
+}
+
+/// A fresh directory for one database, emptied first.
+fn scratch(name: &str) -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("mini-lsm-bench-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn open(dir: &Path) -> Result<Lsm<u64, u64>> {
+    let options = Options {
+        max_size: Some(1000),
+        l0_compaction_trigger: Some(4),
+        ..Default::default()
+    };
+    Lsm::open_or_create_with(&dir.join("bench.lsm"), options)
+}
+
+fn main() -> Result<()> {
+    let dir = scratch("ops")?;
+    let mut lsm = open(&dir)?;
+
+    bench("put", KEYS, |i| {
+        lsm.put(i, i)?;
+        Ok(())
+    })?;
+    lsm.flush()?;
+    // the newest keys again, so they're in the memtable
+    for i in KEYS - 500..KEYS {
+        lsm.put(i, i + 1)?;
+    }
+
+    bench("get memtable", 500, |i| {
+        black_box(lsm.get(&(KEYS - 500 + i))?);
+        Ok(())
+    })?;
+    bench("get sst", 1000, |i| {
+        black_box(lsm.get(&(i * 7 % (KEYS - 500)))?);
+        Ok(())
+    })?;
+    bench("get miss", 1000, |i| {
+        black_box(lsm.get(&(KEYS + i))?);
+        Ok(())
+    })?;
+    bench("scan", 10, |_| {
+        black_box(lsm.iter()?.count());
+        Ok(())
+    })?;
+
+    drop(lsm);
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,55 @@
+//! Writes gathered up to be applied together.
+
+/// Puts and deletes applied as one by `Lsm::commit`.
+///
+/// Nothing reaches the database until then, so part of a batch can be undone
+/// with `rollback_to` while it's being built. Later operations on a key win
+/// over earlier ones in the same batch.
+///
+#[derive(Debug, Clone)]
+pub struct WriteBatch<K, V> {
+    // None is a delete
+    pub(crate) ops: Vec<(K, Option<V>)>,
+}
+
+/// A point in a `WriteBatch` to roll back to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint(usize);
+
+impl<K, V> Default for WriteBatch<K, V> {
+    fn default() -> WriteBatch<K, V> {
+        WriteBatch { ops: vec![] }
+    }
+}
+
+impl<K, V> WriteBatch<K, V> {
+    pub fn new() -> WriteBatch<K, V> {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, key: K, value: V) {
+        self.ops.push((key, Some(value)));
+    }
+
+    pub fn delete(&mut self, key: K) {
+        self.ops.push((key, None));
+    }
+
+    /// Marks the operations so far, for undoing any that come after.
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint(self.ops.len())
+    }
+
+    /// Drops every operation added since `savepoint` was taken.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        self.ops.truncate(savepoint.0);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
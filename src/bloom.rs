@@ -0,0 +1,111 @@
+//! Bloom filters over key prefixes, so a prefix scan can pass over SSTs that
+//! hold no key with its prefix without reading them.
+
+use bincode::{Decode, Encode};
+
+use crate::BloomHash;
+
+// about a 1% false positive rate at 7 probes
+const BITS_PER_PREFIX: usize = 10;
+const PROBES: u32 = 7;
+
+/// Which `len`-byte key prefixes an SST holds. May say yes for one it
+/// doesn't, never no for one it does.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PrefixBloom {
+    pub(crate) len: usize,
+    pub(crate) hash: BloomHash,
+    bits: Vec<u64>,
+}
+
+impl PrefixBloom {
+    /// A filter over `prefixes`, each `len` bytes long, setting bits by `hash`.
+    pub(crate) fn new<'a>(
+        len: usize,
+        hash: BloomHash,
+        prefixes: impl ExactSizeIterator<Item = &'a [u8]>,
+    ) -> PrefixBloom {
+        let words = (prefixes.len() * BITS_PER_PREFIX).div_ceil(64).max(1);
+        let mut bloom = PrefixBloom {
+            len,
+            hash,
+            bits: vec![0; words],
+        };
+        for prefix in prefixes {
+            for bit in bloom.probes(prefix) {
+                bloom.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        bloom
+    }
+
+    /// Whether any key starting with `prefix` might be there. A prefix shorter
+    /// than the filter's can't be checked, so it always might.
+    pub(crate) fn may_contain(&self, prefix: &[u8]) -> bool {
+        let Some(prefix) = prefix.get(..self.len) else {
+            return true;
+        };
+        self.probes(prefix)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    // double hashing off two hashes that are the same in every build, since this is persisted
+    fn probes(&self, prefix: &[u8]) -> impl Iterator<Item = usize> {
+        let (first, second) = match self.hash {
+            BloomHash::Crc32 => {
+                let mut hasher = crc32fast::Hasher::new_with_initial(0x9E37_79B9);
+                hasher.update(prefix);
+                (crc32fast::hash(prefix) as u64, hasher.finalize() as u64)
+            }
+            BloomHash::Fnv1a => {
+                let hash = mix(fnv1a(prefix));
+                (hash & 0xFFFF_FFFF, hash >> 32)
+            }
+        };
+        // odd, so it steps onto a new bit every probe
+        let second = second | 1;
+        let bits = self.bits.len() as u64 * 64;
+        (0..PROBES as u64).map(move |i| (first.wrapping_add(i * second) % bits) as usize)
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+// the splitmix64 finalizer, so the high half depends on every byte as much as the low one
+fn mix(mut hash: u64) -> u64 {
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the share of `absent` a filter over `present` says might be there
+    fn false_positive_rate(hash: BloomHash, present: &[Vec<u8>], absent: &[Vec<u8>]) -> f64 {
+        let bloom = PrefixBloom::new(8, hash, present.iter().map(|prefix| &prefix[..]));
+        let hits = absent
+            .iter()
+            .filter(|prefix| bloom.may_contain(prefix))
+            .count();
+        hits as f64 / absent.len() as f64
+    }
+
+    #[test]
+    fn hashes_differ_in_false_positive_rate() {
+        // sequential ids, as tenant or table prefixes often are
+        let prefixes: Vec<Vec<u8>> = (0..200_000u64).map(|i| i.to_be_bytes().to_vec()).collect();
+        let (present, absent) = prefixes.split_at(100_000);
+
+        let crc = false_positive_rate(BloomHash::Crc32, present, absent);
+        let fnv = false_positive_rate(BloomHash::Fnv1a, present, absent);
+        // about what ten bits and seven probes come to, 0.8%, against 1.1%
+        assert!(fnv < 0.009, "{fnv}");
+        assert!(crc > fnv * 1.2, "{crc} against {fnv}");
+    }
+}
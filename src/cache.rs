@@ -0,0 +1,90 @@
+//! Values recently read out of SSTs, and keys found in none of them, so a hot
+//! key's lookups don't decode an SST every time.
+
+use std::collections::BTreeMap;
+
+/// Items by key, evicting the least recently used once their sizes add up to
+/// more than the capacity.
+pub(crate) struct ValueCache<K, T> {
+    capacity: usize,
+    // sum of the sizes of what's cached
+    used: usize,
+    items: BTreeMap<K, Cached<T>>,
+    // keys by when they were last used, least recent first
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+struct Cached<T> {
+    item: T,
+    size: usize,
+    used_at: u64,
+}
+
+impl<K: Ord + Clone, T> ValueCache<K, T> {
+    /// A cache holding up to `capacity` bytes; 0 holds nothing.
+    pub(crate) fn new(capacity: usize) -> ValueCache<K, T> {
+        ValueCache {
+            capacity,
+            used: 0,
+            items: BTreeMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub(crate) fn get(&mut self, key: &K) -> Option<&T> {
+        let cached = self.items.get_mut(key)?;
+        self.tick += 1;
+        let key = self
+            .order
+            .remove(&cached.used_at)
+            .expect("cached keys are ordered");
+        self.order.insert(self.tick, key);
+        cached.used_at = self.tick;
+        Some(&cached.item)
+    }
+
+    /// Caches `item` as `size` bytes, evicting as much as it takes to fit. An
+    /// item bigger than the whole cache isn't kept.
+    pub(crate) fn insert(&mut self, key: K, item: T, size: usize) {
+        self.remove(&key);
+        if size > self.capacity {
+            return;
+        }
+        while self.used + size > self.capacity {
+            let (_, oldest) = self.order.pop_first().expect("what's used is cached");
+            let evicted = self.items.remove(&oldest).expect("ordered keys are cached");
+            self.used -= evicted.size;
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.items.insert(
+            key,
+            Cached {
+                item,
+                size,
+                used_at: self.tick,
+            },
+        );
+        self.used += size;
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) {
+        if let Some(cached) = self.items.remove(key) {
+            self.order.remove(&cached.used_at);
+            self.used -= cached.size;
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.items.clear();
+        self.order.clear();
+        self.used = 0;
+    }
+}
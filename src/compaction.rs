@@ -0,0 +1,221 @@
+//! Picking which compaction to run when more than one is due.
+//!
+//! Every candidate is a run of adjacent SSTs merged into one in their place,
+//! so lookups still find the newest version of each key first. Candidates are
+//! scored by `CompactionPriority` and only the best one runs.
+//!
+//! What a compaction reads and writes can be held to a byte rate, so it
+//! doesn't take all the disk's bandwidth from the reads and writes around it.
+
+use std::{
+    ops::RangeInclusive,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{manifest::TableMeta, CompactionPriority};
+
+/// Adjacent SSTs of about the same size that are worth merging into one.
+pub(crate) const SIZE_TIER_FAN_OUT: usize = 4;
+/// Share of an SST's entries that have to be tombstones to rewrite it.
+pub(crate) const TOMBSTONE_RATIO: f64 = 0.5;
+
+/// Why a run of SSTs is due for compaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reason {
+    // more L0 SSTs than the trigger allows; merges everything into L1
+    L0Overflow,
+    // `SIZE_TIER_FAN_OUT` adjacent L0 SSTs, the largest at most twice the
+    // smallest; compacted SSTs are already split to size, so they're left out
+    SizeTier,
+    // one SST that's mostly tombstones, with everything older so they can go
+    Tombstones,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Candidate {
+    pub(crate) reason: Reason,
+    // indexes into the SSTs, oldest first
+    pub(crate) run: RangeInclusive<usize>,
+    // level the merged SST goes in
+    pub(crate) level: usize,
+}
+
+/// A token bucket over compaction I/O: bytes go through at `bytes_per_sec`,
+/// with at most a tenth of a second's worth let through at once after a pause.
+#[derive(Debug)]
+pub(crate) struct RateLimit {
+    bytes_per_sec: u64,
+    // bytes that can go through now, below zero once a take has run past them
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimit {
+    pub(crate) fn new(bytes_per_sec: u64) -> RateLimit {
+        RateLimit {
+            bytes_per_sec,
+            tokens: 0.0,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes `bytes` out of the bucket, sleeping until they're paid for.
+    pub(crate) fn take(&mut self, bytes: u64) {
+        let rate = self.bytes_per_sec as f64;
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(rate / 10.0) - bytes as f64;
+        self.refilled = now;
+        if self.tokens < 0.0 {
+            thread::sleep(Duration::from_secs_f64(-self.tokens / rate));
+        }
+    }
+}
+
+/// Every compaction that's due over `tables`, oldest first.
+pub(crate) fn candidates(tables: &[TableMeta], l0_trigger: Option<usize>) -> Vec<Candidate> {
+    let mut candidates = vec![];
+
+    let l0 = tables.iter().filter(|table| table.level == 0).count();
+    if l0_trigger.is_some_and(|trigger| l0 > trigger) {
+        candidates.push(Candidate {
+            reason: Reason::L0Overflow,
+            run: 0..=tables.len() - 1,
+            level: 1,
+        });
+    }
+
+    let mut start = 0;
+    while start + SIZE_TIER_FAN_OUT <= tables.len() {
+        let tier = &tables[start..start + SIZE_TIER_FAN_OUT];
+        let smallest = tier.iter().map(|table| table.bytes).min().unwrap_or(0);
+        let largest = tier.iter().map(|table| table.bytes).max().unwrap_or(0);
+        if tier.iter().all(|table| table.level == 0) && largest <= smallest * 2 {
+            candidates.push(Candidate {
+                reason: Reason::SizeTier,
+                run: start..=start + SIZE_TIER_FAN_OUT - 1,
+                level: tier[0].level,
+            });
+            start += SIZE_TIER_FAN_OUT;
+        } else {
+            start += 1;
+        }
+    }
+
+    for (i, table) in tables.iter().enumerate() {
+        if table.entries > 0 && table.tombstones as f64 / table.entries as f64 > TOMBSTONE_RATIO {
+            candidates.push(Candidate {
+                reason: Reason::Tombstones,
+                run: 0..=i,
+                level: tables[0].level,
+            });
+        }
+    }
+
+    candidates
+}
+
+/// The candidate that scores highest under `priority`, the earliest of any
+/// that tie.
+pub(crate) fn pick(
+    tables: &[TableMeta],
+    candidates: Vec<Candidate>,
+    priority: CompactionPriority,
+) -> Option<Candidate> {
+    let mut best: Option<(f64, Candidate)> = None;
+    for candidate in candidates {
+        let score = candidate.score(tables, priority);
+        if best.as_ref().is_none_or(|(top, _)| score > *top) {
+            best = Some((score, candidate));
+        }
+    }
+    best.map(|(_, candidate)| candidate)
+}
+
+impl Candidate {
+    fn score(&self, tables: &[TableMeta], priority: CompactionPriority) -> f64 {
+        let inputs = &tables[self.run.clone()];
+        // at least a byte, so empty SSTs still score
+        let rewritten = inputs.iter().map(|table| table.bytes).sum::<u64>().max(1) as f64;
+        match priority {
+            // SSTs taken off the read path, per byte merged
+            CompactionPriority::ReadAmp => (inputs.len() - 1) as f64 / rewritten,
+            // tombstone bytes dropped, per byte merged; they only go when
+            // nothing older is left underneath
+            CompactionPriority::SpaceReclaimed if *self.run.start() == 0 => {
+                let dropped: f64 = inputs
+                    .iter()
+                    .filter(|table| table.entries > 0)
+                    .map(|table| {
+                        table.bytes as f64 * table.tombstones as f64 / table.entries as f64
+                    })
+                    .sum();
+                dropped / rewritten
+            }
+            CompactionPriority::SpaceReclaimed => 0.0,
+            // SSTs written since the newest input
+            CompactionPriority::Staleness => (tables.len() - 1 - self.run.end()) as f64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_by_priority() {
+        let table = |level, bytes, entries, tombstones| TableMeta {
+            level,
+            bytes,
+            entries,
+            tombstones,
+            ..Default::default()
+        };
+        // oldest first: a big L1, a mostly deleted L0, a tier of four, a small newest one
+        let tables = vec![
+            table(1, 100_000, 1000, 0),
+            table(0, 3000, 100, 80),
+            table(0, 1000, 10, 0),
+            table(0, 1200, 12, 0),
+            table(0, 900, 9, 0),
+            table(0, 1100, 11, 0),
+            table(0, 100, 1, 0),
+        ];
+
+        let due = candidates(&tables, Some(5));
+        let reasons: Vec<(Reason, RangeInclusive<usize>)> = due
+            .iter()
+            .map(|candidate| (candidate.reason, candidate.run.clone()))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (Reason::L0Overflow, 0..=6),
+                (Reason::SizeTier, 2..=5),
+                (Reason::Tombstones, 0..=1),
+            ]
+        );
+
+        let picked = |priority| pick(&tables, due.clone(), priority).unwrap().reason;
+        // four small SSTs for the price of merging them
+        assert_eq!(picked(CompactionPriority::ReadAmp), Reason::SizeTier);
+        // the tombstones without rewriting the rest of L0 too
+        assert_eq!(
+            picked(CompactionPriority::SpaceReclaimed),
+            Reason::Tombstones
+        );
+        // nothing newer has touched those two in a while
+        assert_eq!(picked(CompactionPriority::Staleness), Reason::Tombstones);
+
+        // a run the latest flush isn't part of is staler than one it is
+        let overflow = candidates(&tables[2..], Some(3));
+        assert_eq!(overflow.len(), 2);
+        assert_eq!(
+            pick(&tables[2..], overflow, CompactionPriority::Staleness).map(|c| c.reason),
+            Some(Reason::SizeTier)
+        );
+        assert_eq!(pick(&tables, vec![], CompactionPriority::ReadAmp), None);
+    }
+}
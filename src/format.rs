@@ -0,0 +1,52 @@
+//! How every file is encoded.
+//!
+//! All files use one pinned bincode configuration: little-endian, variable
+//! length integers, and at most `DECODE_LIMIT` bytes claimed by any one decode.
+//! Each file starts with a header holding a tag for the kind of file and the id
+//! of that configuration, so a file written any other way is rejected rather
+//! than decoded as garbage.
+
+use std::path::Path;
+
+use anyhow::Result;
+use bincode::config::{Configuration, Limit, LittleEndian, Varint};
+
+use crate::Error;
+
+/// Id of the configuration `config` returns. Bump it whenever that changes.
+pub(crate) const CONFIG_ID: u8 = 1;
+
+pub(crate) const HEADER_LEN: usize = 5;
+
+/// Most bytes one decode can claim for what it reads, a whole SST included.
+/// A length prefix past it fails to decode instead of being allocated, so a
+/// damaged one can't take the process down before its checksum is checked.
+/// It doesn't change how anything is encoded, so files keep `CONFIG_ID`.
+pub(crate) const DECODE_LIMIT: usize = 1 << 30;
+
+pub(crate) const SST: &[u8; 4] = b"LSMS";
+pub(crate) const WAL: &[u8; 4] = b"LSMW";
+pub(crate) const MANIFEST: &[u8; 4] = b"LSMM";
+
+pub(crate) fn config() -> Configuration<LittleEndian, Varint, Limit<DECODE_LIMIT>> {
+    bincode::config::standard()
+        .with_little_endian()
+        .with_variable_int_encoding()
+        .with_limit::<DECODE_LIMIT>()
+}
+
+pub(crate) fn header(kind: &[u8; 4]) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(kind);
+    header[4] = CONFIG_ID;
+    header
+}
+
+/// Checks that `bytes` start with the header for `kind`, failing with
+/// `Error::UnsupportedFormat` if they don't.
+pub(crate) fn check_header(kind: &[u8; 4], bytes: &[u8], file: &Path) -> Result<()> {
+    if bytes.get(..HEADER_LEN) != Some(&header(kind)[..]) {
+        return Err(Error::UnsupportedFormat(file.to_path_buf()).into());
+    }
+    Ok(())
+}
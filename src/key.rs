@@ -0,0 +1,146 @@
+//! Order-preserving encodings for composite keys.
+//!
+//! `Vec<u8>` keys sort byte by byte, so a composite key like `(tenant, timestamp)`
+//! only scans in logical order if each part is encoded so that byte order
+//! matches its own order. `OrderedKey` builds such keys part by part.
+
+use std::ops::Bound;
+
+/// Builder for a composite `Vec<u8>` key whose byte order matches the order of
+/// its parts, compared left to right.
+///
+/// Integers are big-endian (signed ones with the sign bit flipped). Strings and
+/// byte strings end in a `0x00 0x01` terminator with any `0x00` inside escaped
+/// as `0x00 0xFF`, so a shorter string sorts before any string it's a prefix
+/// of, whatever part comes after it. A plain length prefix wouldn't work here:
+/// it would sort `"b"` before `"aa"`.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OrderedKey {
+    bytes: Vec<u8>,
+}
+
+impl OrderedKey {
+    pub fn new() -> OrderedKey {
+        OrderedKey::default()
+    }
+
+    pub fn u32(mut self, value: u32) -> OrderedKey {
+        self.bytes.extend(value.to_be_bytes());
+        self
+    }
+
+    pub fn u64(mut self, value: u64) -> OrderedKey {
+        self.bytes.extend(value.to_be_bytes());
+        self
+    }
+
+    pub fn i64(mut self, value: i64) -> OrderedKey {
+        // flipping the sign bit puts negatives below positives
+        self.bytes
+            .extend(((value as u64) ^ (1 << 63)).to_be_bytes());
+        self
+    }
+
+    pub fn str(self, value: &str) -> OrderedKey {
+        self.bytes(value.as_bytes())
+    }
+
+    pub fn bytes(mut self, value: &[u8]) -> OrderedKey {
+        for &byte in value {
+            self.bytes.push(byte);
+            if byte == 0 {
+                self.bytes.push(0xFF);
+            }
+        }
+        self.bytes.extend([0, 1]);
+        self
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+///
+/// The smallest byte string greater than every string starting with `prefix`,
+/// so keys with the prefix are exactly `prefix..successor`.
+///
+/// Trailing `0xFF` bytes can't be bumped and are dropped first. `None` means
+/// there's no such string (the prefix is empty or all `0xFF`), so the range
+/// has no upper bound.
+///
+pub fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|&byte| byte != 0xFF)?;
+    let mut successor = prefix[..=last].to_vec();
+    successor[last] += 1;
+    Some(successor)
+}
+
+/// The half-open range of keys starting with `prefix`, for passing to `scan`.
+pub fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let end = match prefix_successor(prefix) {
+        Some(successor) => Bound::Excluded(successor),
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix.to_vec()), end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_preserves_order() {
+        let strings = ["", "\0", "\0\0", "a", "a\0", "a\0b", "aa", "ab", "b"];
+        let encoded: Vec<_> = strings
+            .iter()
+            .map(|s| OrderedKey::new().str(s).u64(0).finish())
+            .collect();
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // the part after a string can't be mistaken for an escaped 0x00
+        let max = OrderedKey::new().str("a").u64(u64::MAX).finish();
+        let longer = OrderedKey::new().str("a\0").u64(0).finish();
+        assert!(max < longer);
+        let encoded: Vec<_> = strings
+            .iter()
+            .map(|s| OrderedKey::new().str(s).u64(u64::MAX).finish())
+            .collect();
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let ints = [i64::MIN, -256, -1, 0, 1, 255, 256, i64::MAX];
+        let encoded: Vec<_> = ints
+            .iter()
+            .map(|&i| OrderedKey::new().i64(i).finish())
+            .collect();
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let encoded: Vec<_> = [0, 1, 255, 256, 70_000, u64::MAX]
+            .iter()
+            .map(|&i| OrderedKey::new().u64(i).str("x").finish())
+            .collect();
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn prefix_successor_carries_past_0xff() {
+        assert_eq!(prefix_successor(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_successor(&[1, 0xFF]), Some(vec![2]));
+        assert_eq!(
+            prefix_successor(&[1, 0xFE, 0xFF, 0xFF]),
+            Some(vec![1, 0xFF])
+        );
+        assert_eq!(prefix_successor(&[0xFF, 0xFF]), None);
+        assert_eq!(prefix_successor(&[]), None);
+
+        assert_eq!(
+            prefix_range(&[0xFF]),
+            (Bound::Included(vec![0xFF]), Bound::Unbounded)
+        );
+        assert_eq!(
+            prefix_range(&[]),
+            (Bound::Included(vec![]), Bound::Unbounded)
+        );
+    }
+}
@@ -1,85 +1,650 @@
 use std::{
-    collections::{hash_map::DefaultHasher, BTreeMap},
-    fs::{self, File, OpenOptions},
-    hash::{Hash, Hasher},
-    io::{BufReader, Write},
-    path::Path,
+    any,
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt,
+    hash::Hash,
+    io,
+    ops::{Bound, RangeBounds, RangeInclusive},
+    path::{Path, PathBuf},
+    sync::{mpsc::Receiver, Arc, Mutex, RwLock},
 };
 
-use anyhow::Result;
-use bincode::{Decode, Encode};
-struct Lsm<'a, K, V> {
+use anyhow::{Context, Result};
+pub use batch::{Savepoint, WriteBatch};
+use bincode::{enc::write::SizeWriter, Decode, Encode};
+use bloom::PrefixBloom;
+use cache::ValueCache;
+
+pub use key::{prefix_range, prefix_successor, OrderedKey};
+use manifest::{Edit, Manifest, ManifestState, TableMeta};
+pub use merge::{merge_sorted, Diff};
+use merge::{DiffIter, MergeIter, Source as MergeSource};
+pub use options::{
+    BloomHash, CompactionPriority, CorruptionPolicy, CounterOverflow, FileNames, ManifestSync,
+    MissingSstPolicy, Options, RetryPolicy, WriteOptions, DEFAULT_MAX_KEY_SIZE, DEFAULT_MAX_SIZE,
+    DEFAULT_MAX_VALUE_SIZE, DEFAULT_SST_TARGET_ENTRIES,
+};
+pub use shared::SharedLsm;
+pub use sst::{dump_sst, SstWriter};
+use stats::Counters;
+pub use stats::Stats;
+use storage::Retrying;
+pub use storage::{Disk, Memory, Storage};
+pub use txn::Txn;
+use wal::LogEntry;
+pub use wal::{replay_wal, LogRecord, SkippedRecord, WalReplay};
+use watch::Watchers;
+pub use watch::{Change, WATCH_CAPACITY};
+
+mod batch;
+mod bloom;
+mod cache;
+mod compaction;
+mod format;
+mod key;
+mod manifest;
+mod merge;
+mod options;
+mod shared;
+mod sst;
+mod stats;
+mod storage;
+mod txn;
+mod wal;
+mod watch;
+
+// newest SSTs kept decoded, since reads of recent keys keep landing in them
+const RECENT_SSTS: usize = 2;
+
+pub struct Lsm<K: 'static, V: 'static> {
     // memtable
-    memtable: BTreeMap<K, V>,
+    memtable: BTreeMap<K, Entry<V>>,
 
     // TODO change to size in bytes??
     // max size of memtable before flush
     // use std::mem::size_of_val
     max_size: usize,
+    // L0 SSTs allowed before they're compacted into L1
+    l0_trigger: Option<usize>,
+    // how to choose among due compactions, if more than the L0 one are considered
+    compaction_priority: Option<CompactionPriority>,
+    // flushes after a compaction that only an L0 this far past its trigger compacts
+    compaction_hysteresis: usize,
+    flushes_since_compaction: usize,
+    // what compactions read and write is held to, if anything
+    compaction_limit: Option<compaction::RateLimit>,
+    // largest encoded key and value a write takes
+    max_key_size: usize,
+    max_value_size: usize,
+    // entries per SST when a bulk load or compaction splits its output
+    sst_target_entries: usize,
+    // where every file is read and written
+    storage: Arc<dyn Storage>,
+    // the manifest's directory, which the log and SST names are relative to
+    dir: PathBuf,
     // log
-    wal: File,
-    // manifest handle
-    // manifest: File,
-    manifest_path: &'a Path,
-    // current SSTs
-    tables: Vec<String>,
+    wal_path: PathBuf,
+    // records in the log, counting ones later writes have overwritten
+    wal_records: usize,
+    // size of the log, header included
+    wal_len: u64,
+    // reused for each write's log record
+    encoder: wal::Encoder,
+    // every write after this sequence number is still in the log
+    wal_from: u64,
+    max_wal_bytes: Option<u64>,
+    // fsync the log on every write
+    sync_writes: bool,
+    // batch the manifest edits of a bulk load into one fsync
+    batch_manifest_syncs: bool,
+    // SSTs a manifest batch has dropped, deleted once the batch is durable
+    retired: Vec<String>,
+    // key prefix length new SSTs get a bloom filter over
+    prefix_bloom_len: Option<usize>,
+    prefix_bloom_hash: BloomHash,
+    // key prefixes compactions drop
+    drop_prefixes: Vec<Vec<u8>>,
+    counter_overflow: CounterOverflow,
+    // manifest handle, which also tracks the current SSTs
+    manifest: Manifest,
+    manifest_path: PathBuf,
+    // last sequence number handed out
+    seq: u64,
+    // activity since open or the last `reset_stats`
+    counters: Counters,
+    // up to `RECENT_SSTS` of the newest SSTs, by name
+    recent: Mutex<Vec<CachedSst<K, V>>>,
+    // values point reads found in SSTs, until a write or compaction changes them
+    values: Mutex<ValueCache<K, ValueMeta<V>>>,
+    // keys point reads found absent, until a write or an ingest adds them
+    absent: Mutex<ValueCache<K, ()>>,
+    // SSTs moved aside on open because they couldn't be read
+    quarantined: Vec<SstInfo<K>>,
+    // log records left out on open because they failed their CRC
+    skipped_log_records: Vec<SkippedRecord>,
+    // fail on damaged log records rather than skip them
+    strict: bool,
+    on_write: Option<WriteHook<K, V>>,
+    // subscribers to writes on ranges of keys
+    watchers: Watchers<K, V>,
+    placement: Option<Placement>,
+    // makes the next flush stop short at this point, as if the process died there
+    #[cfg(test)]
+    crash_at: Option<CrashPoint>,
+    // how much of the log an fsync has covered, so a test can drop the rest
+    #[cfg(test)]
+    wal_synced: u64,
 }
 
-#[derive(Encode, Decode, Debug)]
-struct LogEntry<
-    K: Encode + Decode + Hash + Ord + 'static,
-    V: Encode + Decode + Hash + Ord + 'static,
-> {
-    crc: u32,
-    is_tombstone: bool,
-    key: K,
-    value: V,
+/// Steps of a flush a test can stop after.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CrashPoint {
+    SstWritten,
+    ManifestLogged,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+struct Entry<V> {
+    seq: u64,
+    // None is a tombstone
+    value: Option<V>,
+}
+
+/// How an SST's entries are ordered. Lookups binary search the entries, so
+/// every SST has to be strictly ascending (no duplicate keys) under this.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparator {
+    // the key type's `Ord`
+    Ord,
 }
 
 #[derive(Encode, Decode, Debug)]
 struct Sst<K: 'static, V: 'static> {
-    entries: Vec<(K, V)>,
+    comparator: Comparator,
+    // fingerprint of the key/value types, as in the manifest
+    schema: u32,
+    entries: Vec<(K, Entry<V>)>,
+}
+
+/// What `Lsm::try_log_recovery` rebuilds from the log.
+struct LogRecovery<K, V> {
+    memtable: BTreeMap<K, Entry<V>>,
+    // highest sequence number in the log
+    max_seq: u64,
+    // every write after this sequence number is still in the log
+    wal_from: u64,
+    // records that failed their CRC
+    skipped: Vec<SkippedRecord>,
+    // just past the last whole record
+    end: u64,
+}
+
+/// A decoded SST kept in memory, by name.
+type CachedSst<K, V> = (String, Arc<Sst<K, V>>);
+
+/// Picks the directory each new SST is written to.
+type Placement = Box<dyn Fn(&SstPlacement) -> PathBuf + Send + Sync>;
+
+/// What `Lsm::place_ssts` is told about an SST about to be written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SstPlacement {
+    /// 0 for a flush or bulk load, or the level the compaction output goes to
+    pub level: usize,
+    pub entries: usize,
+    /// size of the file it will be
+    pub bytes: u64,
+}
+
+/// Called with the key, its old value and its new value on every write.
+type WriteHook<K, V> = Box<dyn Fn(&K, Option<&V>, Option<&V>) -> Result<()> + Send + Sync>;
+
+/// A value along with where it was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueMeta<V> {
+    pub value: V,
+    /// sequence number of the write that produced this value
+    pub seq: u64,
+    pub source: Source,
+}
+
+/// Where and how big an SST is, as reported by `sst_layout`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SstInfo<K> {
+    pub name: String,
+    /// 0 for flushed SSTs, which can overlap each other, 1 for the output
+    /// of L0 compaction, or whatever `compact_to_level` moved them to
+    pub level: usize,
+    pub min_key: K,
+    pub max_key: K,
+    /// entry count, tombstones included
+    pub entries: usize,
+    pub tombstones: usize,
+    /// size of the file on disk
+    pub bytes: u64,
+}
+
+/// What `Lsm::recover` found in the log.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// records read, counting ones later writes have overwritten
+    pub records: usize,
+    /// records newer than what the handle had, now in the memtable
+    pub applied: usize,
+    pub skipped: Vec<SkippedRecord>,
+    /// bytes at the end that don't frame a whole record, e.g. one still being written
+    pub trailing_bytes: usize,
+}
+
+/// Errors specific to the LSM tree. I/O and encoding errors are passed through as is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The database was written with different key/value types than it's being opened with.
+    SchemaMismatch { expected: u32, found: u32 },
+    /// There's no database at the given manifest path.
+    NotFound(PathBuf),
+    /// An SST's entries aren't strictly ascending by key.
+    UnsortedSst(String),
+    /// The options can't be used with this database.
+    InvalidOptions(String),
+    /// A file isn't one this version can read, or was encoded differently.
+    UnsupportedFormat(PathBuf),
+    /// An SST's contents don't match its checksum.
+    CorruptSst(PathBuf),
+    /// An SST being ingested holds keys in a range the database already has data in.
+    Overlap(PathBuf),
+    /// An SST the manifest lists isn't there.
+    MissingSst(PathBuf),
+    /// SST files named like this database's that the manifest doesn't list.
+    OrphanSsts(Vec<PathBuf>),
+    /// A key is bigger, encoded, than `Options::max_key_size` allows.
+    KeyTooLarge { size: usize, limit: usize },
+    /// A value is bigger, encoded, than `Options::max_value_size` allows.
+    ValueTooLarge { size: usize, limit: usize },
+    /// A read as of this sequence number may need a version that a later
+    /// write in the memtable, or a compaction, has since replaced, or a log
+    /// record that's no longer kept.
+    HistoryUnavailable(u64),
+    /// A write was given a sequence number that isn't after the last one.
+    SequenceOutOfOrder { seq: u64, last: u64 },
+    /// A log record fails its CRC, under `CorruptionPolicy::Strict`, or a
+    /// manifest record does under any policy.
+    CorruptRecord { path: PathBuf, index: usize },
+    /// The disk filled up writing this file. Whatever was being written is
+    /// left out, and the database stays as it was before it.
+    DiskFull(PathBuf),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::SchemaMismatch { expected, found } => write!(
+                f,
+                "schema mismatch: opened with fingerprint {expected:#010x} but database has {found:#010x}"
+            ),
+            Error::NotFound(path) => write!(f, "no database at {}", path.display()),
+            Error::UnsortedSst(table) => write!(f, "entries in {table} are out of order"),
+            Error::InvalidOptions(reason) => write!(f, "invalid options: {reason}"),
+            Error::UnsupportedFormat(path) => {
+                write!(f, "{} isn't in a format this version reads", path.display())
+            }
+            Error::CorruptSst(path) => write!(f, "{} fails its checksum", path.display()),
+            Error::Overlap(path) => write!(
+                f,
+                "keys in {} overlap data already in the database",
+                path.display()
+            ),
+            Error::MissingSst(path) => write!(
+                f,
+                "{} is in the manifest but not on disk",
+                path.display()
+            ),
+            Error::OrphanSsts(paths) => {
+                let paths: Vec<String> = paths
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect();
+                write!(f, "not in the manifest: {}", paths.join(", "))
+            }
+            Error::KeyTooLarge { size, limit } => {
+                write!(f, "key is {size} bytes encoded, over the limit of {limit}")
+            }
+            Error::ValueTooLarge { size, limit } => {
+                write!(f, "value is {size} bytes encoded, over the limit of {limit}")
+            }
+            Error::HistoryUnavailable(seq) => {
+                write!(f, "versions as of sequence {seq} are no longer kept")
+            }
+            Error::SequenceOutOfOrder { seq, last } => {
+                write!(f, "sequence {seq} isn't after the last one, {last}")
+            }
+            Error::CorruptRecord { path, index } => {
+                write!(f, "record {index} of {} fails its CRC", path.display())
+            }
+            Error::DiskFull(path) => write!(f, "disk full writing {}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Where a lookup found its value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Memtable,
+    /// name of the SST file
+    Sst(String),
 }
 
-impl<'a, K, V> Lsm<'a, K, V>
+impl<K, V> Lsm<K, V>
 where
-    K: Encode + Decode + Hash + Ord + 'static,
+    K: Encode + Decode + Hash + Ord + Clone + 'static,
     V: Encode + Decode + Hash + Ord + Clone + 'static,
 {
-    /// Makes a new LSM Handle
+    /// Opens an existing database, failing with `Error::NotFound` if there's
+    /// no manifest at `path`.
+    ///
+    /// Use this over `open_or_create` when the database should already be
+    /// there, so a mistyped path errors instead of quietly making an empty one.
+    ///
+    pub fn open(path: &Path) -> Result<Lsm<K, V>> {
+        Self::open_with(path, Options::default())
+    }
+
+    /// Like `open`, with settings.
+    pub fn open_with(path: &Path, options: Options) -> Result<Lsm<K, V>> {
+        if !path.is_file() {
+            return Err(Error::NotFound(path.to_path_buf()).into());
+        }
+        Self::open_or_create_with(path, options)
+    }
+
+    /// Opens the database at `path`, making a new one if it doesn't exist.
+    ///
+    /// Fails with `Error::SchemaMismatch` if the database at `path` was
+    /// written with different key/value types.
     ///
-    fn new(path: &Path) -> Lsm<K, V> {
+    pub fn open_or_create(path: &Path) -> Result<Lsm<K, V>> {
+        Self::open_or_create_with(path, Options::default())
+    }
+
+    /// Like `open_or_create`, with settings.
+    pub fn open_or_create_with(path: &Path, options: Options) -> Result<Lsm<K, V>> {
+        Self::open_in(Arc::new(Disk::default()), path, options)
+    }
+
+    /// Like `open_or_create_with`, with the files kept in `storage` rather
+    /// than on disk. `path` names the manifest, the same as for the others.
+    ///
+    /// A relative `path` is taken from the working directory as it is now.
+    /// The handle keeps every file's path absolute from then on, so changing
+    /// the working directory later doesn't move the database out from under
+    /// it.
+    ///
+    pub fn open_in(storage: Arc<dyn Storage>, path: &Path, options: Options) -> Result<Lsm<K, V>> {
         // check if manifest exists
         // read manifest, set tables
         // else
         // make manifest
+        let path = &std::path::absolute(path)?;
+        if options.quarantine_corrupt_ssts
+            && options.corruption_policy == Some(CorruptionPolicy::Strict)
+        {
+            return Err(Error::InvalidOptions(
+                "a strict corruption policy can't quarantine SSTs".to_string(),
+            )
+            .into());
+        }
+        let byte_keys = any::TypeId::of::<K>() == any::TypeId::of::<Vec<u8>>();
+        if !byte_keys && !options.compaction_drop_prefixes.is_empty() {
+            return Err(Error::InvalidOptions(
+                "compaction_drop_prefixes needs Vec<u8> keys".to_string(),
+            )
+            .into());
+        }
+        if options.compaction_bytes_per_sec == Some(0) {
+            return Err(
+                Error::InvalidOptions("compaction_bytes_per_sec can't be 0".to_string()).into(),
+            );
+        }
+        if !byte_keys && options.prefix_bloom_len.is_some() {
+            return Err(
+                Error::InvalidOptions("prefix_bloom_len needs Vec<u8> keys".to_string()).into(),
+            );
+        }
 
-        let manifest_content = if path.is_file() {
-            let content = fs::read(path).unwrap(); // TODO add error checking
-            bincode::decode_from_slice::<Vec<String>, _>(&content, bincode::config::standard())
-                .unwrap()
-                .0
+        let storage: Arc<dyn Storage> = match options.io_retry.attempts {
+            0 | 1 => storage,
+            _ => Arc::new(Retrying::new(storage, options.io_retry)),
+        };
+
+        let schema = Self::schema_fingerprint();
+        let mut manifest = if storage.exists(path) {
+            let manifest = Manifest::open(storage.clone(), path)?;
+            // check before touching the log, which would decode as garbage
+            if manifest.state().schema != schema {
+                return Err(Error::SchemaMismatch {
+                    expected: schema,
+                    found: manifest.state().schema,
+                }
+                .into());
+            }
+            if options
+                .files
+                .as_ref()
+                .is_some_and(|files| *files != manifest.state().files)
+            {
+                return Err(Error::InvalidOptions(
+                    "file names differ from the ones the database was created with".to_string(),
+                )
+                .into());
+            }
+            if options
+                .max_size
+                .is_some_and(|max_size| max_size != manifest.state().max_size)
+            {
+                return Err(Error::InvalidOptions(format!(
+                    "the database was created with a max_size of {}",
+                    manifest.state().max_size
+                ))
+                .into());
+            }
+            manifest
         } else {
-            vec![]
+            let files = options.files.clone().unwrap_or_default();
+            let manifest_name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+            files
+                .validate(manifest_name, &Self::stem(path))
+                .map_err(Error::InvalidOptions)?;
+
+            // record the schema right away, so even a never-flushed database is checked on reopen
+            Manifest::create(
+                storage.clone(),
+                path,
+                ManifestState {
+                    schema,
+                    max_size: options.max_size.unwrap_or(DEFAULT_MAX_SIZE),
+                    files,
+                    ..Default::default()
+                },
+            )?
         };
+        manifest.sync_edits(options.manifest_sync == ManifestSync::Always);
+        let max_size = manifest.state().max_size;
 
         // make/recover log
-        let memtable = Self::try_log_recovery(Path::new(".log")).unwrap_or(BTreeMap::new());
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let wal_path = dir.join(manifest.state().files.wal());
+        let strict = options.corruption_policy == Some(CorruptionPolicy::Strict);
+        let LogRecovery {
+            memtable,
+            max_seq: log_seq,
+            wal_from,
+            skipped,
+            end: log_end,
+        } = Self::try_log_recovery(&*storage, &wal_path, manifest.state().seq)?;
+        Self::check_skipped(strict, &wal_path, &skipped)?;
+        let seq = log_seq.max(manifest.state().seq);
+        if storage.exists(&wal_path) && storage.len(&wal_path)? > log_end {
+            // cut off a torn write, or every record appended after it would be unreadable
+            storage
+                .truncate(&wal_path, log_end)
+                .map_err(|e| storage::write_error(&wal_path, e))?;
+        }
+        if log_end == 0 {
+            storage.append(&wal_path, &format::header(format::WAL))?;
+        }
+        #[cfg(test)]
+        let wal_synced = storage.len(&wal_path)?;
 
-        Lsm {
+        let mut lsm = Lsm {
+            wal_records: memtable.len(),
+            wal_len: storage.len(&wal_path)?,
+            encoder: wal::Encoder::new(options.encode_buffer_hint.unwrap_or(0)),
+            wal_from,
+            max_wal_bytes: options.max_wal_bytes,
             memtable,
-            max_size: 2, // TODO make this a parameter
-            // wal: File::open(Path::new(".log")).unwrap(),
-            wal: OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(".log")
-                .unwrap(),
-            // manifest: manifest_file,
-            manifest_path: path,
-            tables: manifest_content,
+            max_size,
+            l0_trigger: options.l0_compaction_trigger,
+            compaction_priority: options.compaction_priority,
+            compaction_hysteresis: options.compaction_hysteresis.unwrap_or(0),
+            // as if the last compaction was long enough ago
+            flushes_since_compaction: options.compaction_hysteresis.unwrap_or(0),
+            compaction_limit: options
+                .compaction_bytes_per_sec
+                .map(compaction::RateLimit::new),
+            max_key_size: options.max_key_size.unwrap_or(DEFAULT_MAX_KEY_SIZE),
+            max_value_size: options.max_value_size.unwrap_or(DEFAULT_MAX_VALUE_SIZE),
+            sst_target_entries: options
+                .sst_target_entries
+                .unwrap_or(DEFAULT_SST_TARGET_ENTRIES)
+                .max(1),
+            storage,
+            dir,
+            wal_path,
+            sync_writes: options.sync_writes,
+            batch_manifest_syncs: options.batch_manifest_syncs,
+            retired: vec![],
+            prefix_bloom_len: options.prefix_bloom_len,
+            prefix_bloom_hash: options.prefix_bloom_hash,
+            drop_prefixes: options.compaction_drop_prefixes.clone(),
+            counter_overflow: options.counter_overflow,
+            manifest,
+            manifest_path: path.to_path_buf(),
+            seq,
+            counters: Counters::default(),
+            recent: Mutex::new(vec![]),
+            values: Mutex::new(ValueCache::new(options.value_cache_bytes.unwrap_or(0))),
+            // each key counts as 1
+            absent: Mutex::new(ValueCache::new(options.negative_cache_keys.unwrap_or(0))),
+            quarantined: vec![],
+            skipped_log_records: skipped,
+            strict,
+            on_write: None,
+            watchers: Watchers::default(),
+            placement: None,
+            #[cfg(test)]
+            crash_at: None,
+            #[cfg(test)]
+            wal_synced,
+        };
+        match options.missing_ssts {
+            MissingSstPolicy::OnRead => {}
+            MissingSstPolicy::Fail => {
+                if let Some(table) = lsm.tables().iter().find(|table| !lsm.sst_exists(table)) {
+                    return Err(Error::MissingSst(lsm.sst_path(&table.name)).into());
+                }
+            }
+            MissingSstPolicy::Drop => lsm.drop_missing_ssts()?,
+        }
+        if options.quarantine_corrupt_ssts
+            || options.corruption_policy == Some(CorruptionPolicy::Lenient)
+        {
+            lsm.quarantine()?;
+        }
+
+        Ok(lsm)
+    }
+
+    fn sst_exists(&self, table: &TableMeta) -> bool {
+        self.storage.exists(&self.sst_path(&table.name))
+    }
+
+    /// Drops the SSTs that aren't on disk from the manifest.
+    fn drop_missing_ssts(&mut self) -> Result<()> {
+        let mut edits = vec![];
+        for (table, info) in self.tables().to_vec().into_iter().zip(self.sst_layout()?) {
+            if !self.sst_exists(&table) {
+                edits.push(Edit::RemoveSst(table.name));
+                self.quarantined.push(info);
+            }
+        }
+        if !edits.is_empty() {
+            self.manifest.log(edits)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads every SST, moving the ones that fail to `{name}.quarantined` and
+    /// dropping them from the manifest.
+    fn quarantine(&mut self) -> Result<()> {
+        let mut edits = vec![];
+        for (table, info) in self.tables().to_vec().into_iter().zip(self.sst_layout()?) {
+            let readable = self.read_sst(&table.name).is_ok_and(|sst| {
+                sst.comparator == Comparator::Ord && Self::is_sorted(&sst.entries)
+            });
+            if readable {
+                continue;
+            }
+            let path = self.sst_path(&table.name);
+            if self.storage.exists(&path) {
+                let moved = self.sst_path(&format!("{}.quarantined", table.name));
+                self.storage.rename(&path, &moved)?;
+            }
+            edits.push(Edit::RemoveSst(table.name));
+            self.quarantined.push(info);
+        }
+        if !edits.is_empty() {
+            self.manifest.log(edits)?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// The SSTs that were moved aside when this handle was opened with
+    /// `Options::quarantine_corrupt_ssts`, or dropped for being missing under
+    /// `MissingSstPolicy::Drop`, as the manifest described them.
+    ///
+    /// Keys between each one's `min_key` and `max_key` may be missing, or read
+    /// as an older version an SST further down still holds.
+    ///
+    pub fn quarantined(&self) -> &[SstInfo<K>] {
+        &self.quarantined
+    }
+
+    ///
+    /// The log records that failed their CRC when this handle was opened,
+    /// and were left out of the memtable. The writes they held are lost.
+    ///
+    pub fn skipped_log_records(&self) -> &[SkippedRecord] {
+        &self.skipped_log_records
+    }
+
+    /// Fails on the first of `skipped`, from the log at `path`, if `strict`.
+    fn check_skipped(strict: bool, path: &Path, skipped: &[SkippedRecord]) -> Result<()> {
+        match skipped.first() {
+            Some(record) if strict => Err(Error::CorruptRecord {
+                path: path.to_path_buf(),
+                index: record.index,
+            }
+            .into()),
+            _ => Ok(()),
         }
     }
 
@@ -87,182 +652,6338 @@ where
     /// Puts a key-value pair into the LSM tree.
     ///
     ///
-    fn put(&mut self, key: K, value: V) -> Result<usize> {
-        if self.memtable.len() >= self.max_size {
-            // dump memtable to sst
+    pub fn put(&mut self, key: K, value: V) -> Result<usize> {
+        self.write_entry(key, Some(value), self.sync_writes)
+    }
+
+    ///
+    /// Puts a key-value pair and returns the value it replaced, if the key
+    /// had one, like `BTreeMap::insert`. The old value is looked up through
+    /// the SSTs as `get` would, and `&mut self` is held from then through
+    /// the write, so no other write can land in between.
+    ///
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        let old = self.get(&key)?;
+        self.put(key, value)?;
+        Ok(old)
+    }
+    ///
+    /// Puts a key-value pair, with `opts` deciding whether this one write is
+    /// fsynced regardless of the database-wide setting.
+    ///
+    /// An fsynced write makes every write before it durable too, since they
+    /// share the log.
+    ///
+    pub fn put_opts(&mut self, key: K, value: V, opts: WriteOptions) -> Result<()> {
+        self.write_entry(key, Some(value), opts.sync)?;
+        Ok(())
+    }
 
-            let dump: Vec<(K, V)> = std::mem::take(&mut self.memtable).into_iter().collect();
-            let payload = bincode::encode_to_vec(dump, bincode::config::standard())?;
+    ///
+    /// Deletes a key from the LSM tree by writing a tombstone for it.
+    ///
+    pub fn delete(&mut self, key: K) -> Result<usize> {
+        self.write_entry(key, None, self.sync_writes)
+    }
 
-            let name = format!(
-                "sst{:03}{}",
-                self.tables.len(),
-                self.manifest_path.file_stem().unwrap().to_str().unwrap()
-            );
-            let mut table = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(Path::new(&name))?;
-            table.write_all(&payload)?;
-            table.flush()?;
-            self.tables.push(name);
-            self.write_manifest()?;
+    ///
+    /// Puts a key-value pair as sequence number `seq` rather than the next
+    /// one, so a follower applying a leader's log in order ends up with the
+    /// same sequence numbers. `seq` has to be after `last_seq`, or this fails
+    /// with `Error::SequenceOutOfOrder`; it doesn't have to be right after.
+    ///
+    pub fn put_with_seq(&mut self, key: K, value: V, seq: u64) -> Result<usize> {
+        self.write_entry_at(key, Some(value), self.sync_writes, seq)
+    }
 
-            self.wal = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(".log")?;
-        }
+    ///
+    /// Deletes a key as sequence number `seq`, as `put_with_seq` puts one.
+    ///
+    pub fn delete_with_seq(&mut self, key: K, seq: u64) -> Result<usize> {
+        self.write_entry_at(key, None, self.sync_writes, seq)
+    }
 
-        // bincode::encode_into_writer( Self::new_wal_entry(false, key, value), self.wal, bincode::config::standard());
-        let entry = Self::new_wal_entry(false, key, value);
-        let payload = bincode::encode_to_vec(&entry, bincode::config::standard())?;
+    fn write_entry(&mut self, key: K, value: Option<V>, sync: bool) -> Result<usize> {
+        self.write_entry_at(key, value, sync, self.seq + 1)
+    }
 
-        let bytes_written = self.wal.write(&payload)?;
-        self.wal.flush()?;
+    fn write_entry_at(&mut self, key: K, value: Option<V>, sync: bool, seq: u64) -> Result<usize> {
+        if seq <= self.seq {
+            return Err(Error::SequenceOutOfOrder {
+                seq,
+                last: self.seq,
+            }
+            .into());
+        }
+        self.check_size(&key, value.as_ref())?;
+        if let Some(hook) = &self.on_write {
+            hook(&key, self.get(&key)?.as_ref(), value.as_ref())?;
+        }
+        if self.should_flush() {
+            self.flush()?;
+        } else if self.wal_records >= wal::COALESCE_MIN_RECORDS
+            && self.wal_records >= 2 * self.memtable.len()
+        {
+            // mostly overwrites of the same keys
+            self.coalesce_wal()?;
+        }
 
-        self.memtable.insert(entry.key, entry.value);
+        // bincode::encode_into_writer( Self::new_wal_entry(false, key, value), self.wal, format::config());
+        let entry = LogEntry::new(seq, key, value);
+        // out of `self` while it's borrowed, so appending can use the rest
+        let mut encoder = std::mem::take(&mut self.encoder);
+        let written = encoder
+            .encode(&entry)
+            .and_then(|payload| self.append_log(payload, false).map(|()| payload.len()));
+        self.encoder = encoder;
+        let len = written?;
+        // only once it's in the log, so a failed append doesn't use up the number
+        self.seq = seq;
+        if sync {
+            self.flush_wal()?;
+        }
+        self.apply(entry);
 
-        Ok(bytes_written)
+        Ok(len)
     }
 
     ///
-    /// Gets a value addressed by key from the LSM Tree.
+    /// Applies every operation in `batch`, in order, as one write to the log.
     ///
-    /// Returns None if not present.
+    /// After a crash either all of them are recovered or none are. Like `put`,
+    /// it's only fsynced if `Options::sync_writes` is set.
+    ///
+    pub fn commit(&mut self, batch: WriteBatch<K, V>) -> Result<()> {
+        self.commit_ops(batch, false)
+    }
+
     ///
-    fn get(&self, key: &K) -> Option<V> {
-        if let Some(value) = self.memtable.get(key) {
-            return Some(value.clone());
+    /// Starts a transaction: writes buffered until its `commit`, which logs
+    /// them between a begin and a commit marker and applies them together.
+    /// Replaying the log leaves out a transaction whose commit marker never
+    /// made it, or any of whose records fail their CRC.
+    ///
+    pub fn begin(&mut self) -> Txn<'_, K, V> {
+        Txn::new(self)
+    }
+
+    /// Writes `batch` to the log as one record, or between transaction
+    /// markers if `txn`, and applies it.
+    pub(crate) fn commit_ops(&mut self, batch: WriteBatch<K, V>, txn: bool) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        for (key, value) in &batch.ops {
+            self.check_size(key, value.as_ref())?;
+        }
+        if let Some(hook) = &self.on_write {
+            // earlier operations in the batch are what later ones overwrite
+            let mut pending: BTreeMap<&K, Option<&V>> = BTreeMap::new();
+            for (key, value) in &batch.ops {
+                let old = match pending.get(key) {
+                    Some(old) => old.cloned(),
+                    None => self.get(key)?,
+                };
+                hook(key, old.as_ref(), value.as_ref())?;
+                pending.insert(key, value.as_ref());
+            }
+        }
+        if self.should_flush() {
+            self.flush()?;
         }
 
-        // search through all tables
-        for table in self.tables.iter().rev() {
-            let mut reader = BufReader::new(File::open(Path::new(table)).unwrap()); // TODO error checking
+        let entries: Vec<LogEntry<K, V>> = batch
+            .ops
+            .into_iter()
+            .zip(self.seq + 1..)
+            .map(|((key, value), seq)| LogEntry::new(seq, key, value))
+            .collect();
+        let payload = if txn {
+            wal::encode_txn(&entries)?
+        } else {
+            wal::encode_batch(&entries)?
+        };
+        self.append_log(&payload, false)?;
+        self.seq += entries.len() as u64;
+        if self.sync_writes {
+            self.flush_wal()?;
+        }
+        for entry in entries {
+            self.apply(entry);
+        }
 
-            let sst = bincode::decode_from_reader::<Sst<K, V>, &mut BufReader<File>, _>(
-                &mut reader,
-                bincode::config::standard(),
-            )
-            .unwrap();
+        Ok(())
+    }
 
-            let search = sst.entries.binary_search_by_key(&key, |(k, _)| k);
-            if let Ok(index) = search {
-                return Some(sst.entries.get(index).unwrap().1.clone());
-            }
+    ///
+    /// Registers `hook` to be called on every `put`, `put_opts`, `delete` and
+    /// `put_if_unchanged`, and on each operation of a `commit`, replacing any
+    /// hook set before. It gets the key, the value it had (`None` if absent)
+    /// and the value being written (`None` for a delete).
+    ///
+    /// It runs synchronously before the write reaches the log, so an error
+    /// from it aborts the write and is returned. For a batch, the hook sees
+    /// every operation first and an error aborts all of them, though calls
+    /// already made for that batch aren't undone. What a bulk load writes
+    /// straight to SSTs, ingested SSTs and `retain` don't go through it.
+    ///
+    pub fn on_write(
+        &mut self,
+        hook: impl Fn(&K, Option<&V>, Option<&V>) -> Result<()> + Send + Sync + 'static,
+    ) {
+        self.on_write = Some(Box::new(hook));
+    }
+
+    ///
+    /// Registers `placement` to pick the directory each SST a flush, bulk
+    /// load or compaction writes goes to, replacing any set before. A relative
+    /// directory is taken from the manifest's, and an empty one is the
+    /// manifest's itself. The directory has to exist.
+    ///
+    /// The manifest records where each SST went, so they're found again
+    /// after reopening without `placement`. SSTs already written stay where
+    /// they are until a compaction rewrites them. Ingested SSTs always go in
+    /// the manifest's directory.
+    ///
+    pub fn place_ssts(
+        &mut self,
+        placement: impl Fn(&SstPlacement) -> PathBuf + Send + Sync + 'static,
+    ) {
+        self.placement = Some(Box::new(placement));
+    }
+
+    ///
+    /// Subscribes to the writes on keys in `range`. Every `put`, `delete` and
+    /// operation of a `commit` on such a key is sent as a `Change::Write` once
+    /// it's in the log, from within the write.
+    ///
+    /// Changes arrive in the order the writes were made, a batch's together
+    /// and in batch order. Up to `WATCH_CAPACITY` can wait in the receiver;
+    /// past that, writes don't wait for it, and the ones it misses are
+    /// replaced by a single `Change::Lagged` with how many there were, in the
+    /// place they'd have been. Dropping the receiver unsubscribes. What a
+    /// bulk load writes straight to SSTs, ingested SSTs, `retain` and
+    /// `recover` aren't reported.
+    ///
+    pub fn watch(
+        &self,
+        range: impl RangeBounds<K> + Send + Sync + 'static,
+    ) -> Receiver<Change<K, V>> {
+        self.watchers.add(Box::new(move |key| range.contains(key)))
+    }
+
+    fn append_log(&mut self, payload: &[u8], sync: bool) -> Result<()> {
+        if let Err(e) = self.storage.append(&self.wal_path, payload) {
+            // later records would land behind a torn one, where replay never reaches
+            let _ = self.storage.truncate(&self.wal_path, self.wal_len);
+            return Err(storage::write_error(&self.wal_path, e));
+        }
+        self.wal_len += payload.len() as u64;
+        Counters::add(&self.counters.wal_bytes, payload.len() as u64);
+        if sync {
+            self.flush_wal()?;
         }
+        Ok(())
+    }
 
-        None
+    ///
+    /// Fsyncs the log, making every write so far durable, and does nothing
+    /// else; the memtable stays where it is. For handles that don't sync each
+    /// write, to pick when they pay for it.
+    ///
+    pub fn flush_wal(&mut self) -> Result<()> {
+        self.storage.sync(&self.wal_path)?;
+        #[cfg(test)]
+        {
+            self.wal_synced = self.storage.len(&self.wal_path)?;
+        }
+        Ok(())
     }
 
-    fn write_manifest(&mut self) -> Result<()> {
-        let mut manifest = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(self.manifest_path)
-            .unwrap();
-        manifest.write_all(&bincode::encode_to_vec(
-            &self.tables,
-            bincode::config::standard(),
-        )?)?;
-        manifest.flush()?;
+    /// Puts a logged entry in the memtable, telling whoever watches its key.
+    fn apply(&mut self, entry: LogEntry<K, V>) {
+        self.watchers.notify(&entry.key, entry.value.as_ref());
+        self.forget_value(&entry.key);
+        if entry.value.is_some() {
+            Counters::add(&self.counters.puts, 1);
+        } else {
+            Counters::add(&self.counters.deletes, 1);
+        }
+        self.memtable.insert(
+            entry.key,
+            Entry {
+                seq: entry.seq,
+                value: entry.value,
+            },
+        );
+        self.wal_records += 1;
+    }
+
+    /// Rewrites the log as one record per memtable key, dropping the ones later
+    /// writes overwrote. Written to the side and renamed over, so a crash leaves
+    /// either the old log or the new one.
+    fn coalesce_wal(&mut self) -> Result<()> {
+        let mut payload = format::header(format::WAL).to_vec();
+        for (key, entry) in &self.memtable {
+            payload.extend(LogEntry::new(entry.seq, key.clone(), entry.value.clone()).encode()?);
+        }
+
+        let tmp = self.wal_path.with_extension("tmp");
+        if let Err(e) = self.storage.write(&tmp, &payload) {
+            let _ = self.storage.remove(&tmp);
+            return Err(storage::write_error(&tmp, e));
+        }
+        self.storage.rename(&tmp, &self.wal_path)?;
+
+        self.wal_records = self.memtable.len();
+        self.wal_len = payload.len() as u64;
+        // only the newest version of each key is left
+        self.wal_from = self.seq;
+        #[cfg(test)]
+        {
+            self.wal_synced = payload.len() as u64;
+        }
 
         Ok(())
     }
 
-    fn try_log_recovery(log_path: &Path) -> Result<BTreeMap<K, V>, ()> {
-        if log_path.is_file() {
-            let mut memtable: BTreeMap<K, V> = BTreeMap::new();
+    ///
+    /// Dumps the memtable to a new SST and starts a fresh log.
+    ///
+    /// Does nothing if the memtable is empty.
+    ///
+    /// The SST is synced before the manifest points at it, and the log is only
+    /// cleared once the manifest edit is durable, so every write stays
+    /// recoverable from one or the other throughout. The memtable is kept until
+    /// then too, so a failed flush loses nothing in memory either.
+    ///
+    pub fn flush(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
 
-            let mut input_reader = BufReader::new(File::open(log_path).unwrap());
-            while let Ok(entry) = bincode::decode_from_reader::<
-                LogEntry<K, V>,
-                &mut BufReader<File>,
-                _,
-            >(&mut input_reader, bincode::config::standard())
-            {
-                if entry.crc == Self::compute_crc(&entry) {
-                    if entry.is_tombstone {
-                        memtable.remove(&entry.key);
-                    } else {
-                        memtable.insert(entry.key, entry.value);
-                    }
-                }
-            }
+        let dump: Vec<(K, Entry<V>)> = self
+            .memtable
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect();
+        let table = self.write_sst(dump)?;
+        #[cfg(test)]
+        self.crash_point(CrashPoint::SstWritten)?;
+        self.manifest
+            .log(vec![Edit::AddSst(table), Edit::SetSequence(self.seq)])?;
+        #[cfg(test)]
+        self.crash_point(CrashPoint::ManifestLogged)?;
+        self.memtable.clear();
+        self.reset_wal()?;
+        Counters::add(&self.counters.flushes, 1);
+        self.flushes_since_compaction += 1;
 
-            return Ok(memtable);
+        match self.compaction_priority {
+            Some(priority) => self.compact_by(priority),
+            None => self.compact_l0(),
         }
-        Err(())
     }
 
-    fn new_wal_entry(is_tombstone: bool, key: K, value: V) -> LogEntry<K, V> {
-        let mut entry = LogEntry {
-            crc: 0,
-            is_tombstone,
-            key,
-            value,
+    /// Once L0 has more SSTs than the trigger allows, merges them and L1 into a
+    /// single L1 SST. L1 holds everything older than L0, so nothing is left for
+    /// tombstones to shadow and they're dropped.
+    fn compact_l0(&mut self) -> Result<()> {
+        let Some(trigger) = self.l0_trigger else {
+            return Ok(());
         };
-        entry.crc = Self::compute_crc(&entry);
-        entry
+        let trigger = trigger + self.l0_margin();
+        let l0 = self
+            .tables()
+            .iter()
+            .filter(|table| table.level == 0)
+            .count();
+        if l0 <= trigger {
+            return Ok(());
+        }
+
+        self.merge_run(0..=self.tables().len() - 1, 1)
     }
 
-    fn compute_hash<H: Hash>(elem: &H) -> u64 {
-        let mut state = DefaultHasher::new();
-        elem.hash(&mut state);
-        state.finish()
+    /// Runs whichever due compaction ranks highest under `priority`, if any is.
+    fn compact_by(&mut self, priority: CompactionPriority) -> Result<()> {
+        let candidates = self.due_compactions();
+        match compaction::pick(self.tables(), candidates, priority) {
+            Some(candidate) => self.merge_run(candidate.run, candidate.level),
+            None => Ok(()),
+        }
     }
 
-    fn compute_crc(entry: &LogEntry<K, V>) -> u32 {
-        let mut hasher = crc32fast::Hasher::new();
-        hasher.update(if entry.is_tombstone { &[1] } else { &[0] });
-        hasher.update(&Self::compute_hash(&entry.key).to_le_bytes());
-        hasher.update(&Self::compute_hash(&entry.value).to_le_bytes());
-        hasher.finalize()
+    /// The compactions due now, with the L0 trigger raised by the margin.
+    fn due_compactions(&self) -> Vec<compaction::Candidate> {
+        let margin = self.l0_margin();
+        let trigger = self.l0_trigger.map(|trigger| trigger + margin);
+        let mut candidates = compaction::candidates(self.tables(), trigger);
+        if margin > 0 {
+            candidates.retain(|candidate| candidate.reason == compaction::Reason::L0Overflow);
+        }
+        candidates
     }
-}
 
-// TODO write some actual tests for this
+    /// How many SSTs past its trigger L0 has to be before it's compacted:
+    /// `Options::compaction_hysteresis` for that many flushes after the last
+    /// compaction, when nothing else is considered either, and none after.
+    fn l0_margin(&self) -> usize {
+        if self.flushes_since_compaction < self.compaction_hysteresis {
+            self.compaction_hysteresis
+        } else {
+            0
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Merges the adjacent SSTs in `run` into target-sized SSTs at `level`, in
+    /// their place. Tombstones are dropped when nothing older is left underneath.
+    ///
+    /// Keys under `Options::compaction_drop_prefixes` go too. Where older SSTs
+    /// are left underneath they become tombstones instead, so the versions
+    /// down there stay shadowed until a compaction reaches them.
+    ///
+    fn merge_run(&mut self, run: RangeInclusive<usize>, level: usize) -> Result<()> {
+        Counters::add(&self.counters.compactions_started, 1);
+        let drop_tombstones = *run.start() == 0;
+        let inputs = self.tables()[run].to_vec();
+        self.throttle(Self::bytes(&inputs));
+        let merged: Vec<(K, Entry<V>)> = self
+            .merge_tables(&inputs)?
+            .map(|(key, mut entry)| {
+                if self.dropped_by_prefix(&key) {
+                    entry.value = None;
+                }
+                (key, entry)
+            })
+            .filter(|(_, entry)| !drop_tombstones || entry.value.is_some())
+            .collect();
 
-    #[test]
-    fn it_works() -> anyhow::Result<()> {
-        let mut lsm: Lsm<String, u32> = Lsm::new(Path::new("test.lsm"));
+        let removed: Vec<String> = inputs.iter().map(|table| table.name.clone()).collect();
+        let written_after = inputs
+            .iter()
+            .map(|table| table.written_after)
+            .min()
+            .unwrap_or(0);
+        let mut tables = self.write_ssts(merged, level)?;
+        for table in &mut tables {
+            table.written_after = written_after;
+        }
+        self.manifest
+            .log(vec![Edit::ReplaceSstsWith(removed, tables.clone())])?;
 
-        lsm.put("p".to_string(), 4)?;
-        lsm.put("j".to_string(), 7)?;
+        let (read, written) = (Self::bytes(&inputs), Self::bytes(&tables));
+        self.remove_tables(inputs)?;
+        self.count_compaction(read, written);
+        self.flushes_since_compaction = 0;
 
-        // println!("pranoy = {:?}", lsm.get(&"pranoy".to_string()));
-        assert_eq!(lsm.get(&"p".to_string()), Some(4));
+        Ok(())
+    }
 
-        lsm.put("b".to_string(), 10)?;
+    fn bytes(tables: &[TableMeta]) -> u64 {
+        tables.iter().map(|table| table.bytes).sum()
+    }
 
-        // println!("pranoy = {}", lsm.get(&"pranoy".to_string()).unwrap());
-        assert_eq!(lsm.get(&"p".to_string()), Some(4));
+    /// Waits until compactions can move `bytes` more, if they're limited.
+    fn throttle(&mut self, bytes: u64) {
+        if let Some(limit) = &mut self.compaction_limit {
+            limit.take(bytes);
+        }
+    }
 
-        lsm.put("a".to_string(), 2)?;
-        // println!("june = {}", lsm.get(&"june".to_string()).unwrap());
-        assert_eq!(lsm.get(&"j".to_string()), Some(7));
+    fn count_compaction(&self, read: u64, written: u64) {
+        Counters::add(&self.counters.compactions, 1);
+        Counters::add(&self.counters.compaction_bytes_read, read);
+        Counters::add(&self.counters.compaction_bytes_written, written);
+    }
 
-        lsm.put("t".to_string(), 3847)?;
-        // println!("-----");
-        // println!("pranoy = {}", lsm.get(&"pranoy".to_string()).unwrap());
-        // println!("june = {}", lsm.get(&"june".to_string()).unwrap());
-        // println!("bentry = {}", lsm.get(&"bentry".to_string()).unwrap());
-        // println!("andy = {}", lsm.get(&"andy".to_string()).unwrap());
-        // println!("tony = {}", lsm.get(&"tony".to_string()).unwrap());
-        assert_eq!(lsm.get(&"p".to_string()), Some(4));
-        assert_eq!(lsm.get(&"j".to_string()), Some(7));
-        assert_eq!(lsm.get(&"b".to_string()), Some(10));
-        assert_eq!(lsm.get(&"a".to_string()), Some(2));
-        assert_eq!(lsm.get(&"t".to_string()), Some(3847));
+    /// Drops the handle as a power cut would, losing the part of the log that
+    /// was never fsynced.
+    #[cfg(test)]
+    fn crash(self) -> Result<()> {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&self.wal_path)?
+            .set_len(self.wal_synced)?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn crash_point(&self, point: CrashPoint) -> Result<()> {
+        if self.crash_at == Some(point) {
+            anyhow::bail!("crashed after {point:?}");
+        }
+        Ok(())
+    }
+
+    ///
+    /// Opens or creates the database at `path` and loads `entries` into it.
+    ///
+    /// As long as the keys come strictly ascending, they're written straight
+    /// out as SSTs without going through the log or the memtable. From the
+    /// first key that's out of order on, the rest are put one by one instead.
+    ///
+    pub fn bulk_load(path: &Path, entries: impl IntoIterator<Item = (K, V)>) -> Result<Lsm<K, V>> {
+        let mut lsm = Self::open_or_create(path)?;
+        lsm.load(entries)?;
+        Ok(lsm)
+    }
 
+    fn load(&mut self, entries: impl IntoIterator<Item = (K, V)>) -> Result<()> {
+        // lookups check the memtable first, so it can't hold anything older than the SSTs
+        self.flush()?;
 
-        assert_eq!(4, 4);
+        let mut entries = entries.into_iter();
+        // the log is left alone until after the batch, so nothing relies on it being durable
+        let unsorted = self.batched(|lsm| lsm.load_sorted(&mut entries))?;
+
+        for (key, value) in unsorted.into_iter().chain(entries) {
+            self.put(key, value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes out SSTs from `entries` for as long as the keys ascend, and
+    /// returns the first one that doesn't.
+    fn load_sorted(
+        &mut self,
+        entries: &mut impl Iterator<Item = (K, V)>,
+    ) -> Result<Option<(K, V)>> {
+        let mut chunk: Vec<(K, Entry<V>)> = vec![];
+        let mut previous: Option<K> = None;
+        let mut unsorted = None;
+        for (key, value) in entries.by_ref() {
+            if previous.as_ref().is_some_and(|previous| *previous >= key) {
+                unsorted = Some((key, value));
+                break;
+            }
+            previous = Some(key.clone());
+            self.check_size(&key, Some(&value))?;
+
+            self.seq += 1;
+            chunk.push((
+                key,
+                Entry {
+                    seq: self.seq,
+                    value: Some(value),
+                },
+            ));
+            if chunk.len() == self.sst_target_entries {
+                self.add_sst(std::mem::take(&mut chunk))?;
+            }
+        }
+        if !chunk.is_empty() {
+            self.add_sst(chunk)?;
+        }
+        self.compact_l0()?;
+
+        Ok(unsorted)
+    }
+
+    /// Runs `f` with its manifest edits batched into one fsync, if the
+    /// database is set up for that. Whatever `f` logged is committed even if it
+    /// fails, as it would have been without the batch.
+    fn batched<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if !self.batch_manifest_syncs || self.manifest.batching() {
+            return f(self);
+        }
+
+        self.manifest.begin_batch()?;
+        let result = f(self);
+        self.manifest.commit_batch()?;
+        for name in std::mem::take(&mut self.retired) {
+            self.storage.remove(&self.sst_path(&name))?;
+        }
+        result
+    }
+
+    /// Deletes SSTs the manifest no longer lists, or holds on to them while a
+    /// batch that dropped them might still be lost.
+    fn remove_tables(&mut self, tables: Vec<TableMeta>) -> Result<()> {
+        // what's cached names SSTs that are gone, and may be a key that was dropped
+        self.values
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        for table in tables {
+            if self.manifest.batching() {
+                self.retired.push(table.name);
+            } else {
+                self.storage.remove(&self.sst_path(&table.name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes sorted entries out as the newest SST and records it.
+    fn add_sst(&mut self, entries: Vec<(K, Entry<V>)>) -> Result<()> {
+        for (key, _) in &entries {
+            self.forget_value(key);
+        }
+        let table = self.write_sst(entries)?;
+        self.manifest
+            .log(vec![Edit::AddSst(table), Edit::SetSequence(self.seq)])
+    }
+
+    ///
+    /// Takes in an SST prepared with `SstWriter`, without rewriting it.
+    ///
+    /// The file's checksum, types and key order are checked first. Its entries
+    /// carry no sequence numbers to order them against existing data, so its
+    /// key range mustn't overlap anything already in the database, or this
+    /// fails with `Error::Overlap`. It joins L1 as the oldest SST.
+    ///
+    /// The file is moved into place, or copied if that can't be done.
+    ///
+    pub fn ingest_sst(&mut self, path: &Path) -> Result<()> {
+        let sst = self.read_sst_file(path)?;
+        if sst.schema != Self::schema_fingerprint() {
+            return Err(Error::SchemaMismatch {
+                expected: Self::schema_fingerprint(),
+                found: sst.schema,
+            }
+            .into());
+        }
+        if sst.comparator != Comparator::Ord || !Self::is_sorted(&sst.entries) {
+            return Err(Error::UnsortedSst(path.display().to_string()).into());
+        }
+        let (Some((min_key, _)), Some((max_key, _))) = (sst.entries.first(), sst.entries.last())
+        else {
+            return Ok(());
+        };
+
+        let range = (Bound::Included(min_key), Bound::Included(max_key));
+        let in_memtable = self.memtable.range(range).next().is_some();
+        let in_ssts = self
+            .sst_layout()?
+            .iter()
+            .any(|info| Self::overlaps(&range, &info.min_key, &info.max_key));
+        if in_memtable || in_ssts {
+            return Err(Error::Overlap(path.to_path_buf()).into());
+        }
+
+        let config = format::config();
+        let table = TableMeta {
+            name: self.manifest.state().files.sst(
+                self.manifest.state().next_table,
+                &Self::stem(&self.manifest_path),
+            ),
+            level: 1,
+            min_key: bincode::encode_to_vec(min_key, config)?,
+            max_key: bincode::encode_to_vec(max_key, config)?,
+            entries: sst.entries.len(),
+            tombstones: sst
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.value.is_none())
+                .count(),
+            bytes: self.storage.len(path)?,
+            min_seq: sst
+                .entries
+                .iter()
+                .map(|(_, entry)| entry.seq)
+                .min()
+                .unwrap_or(0),
+            max_seq: sst
+                .entries
+                .iter()
+                .map(|(_, entry)| entry.seq)
+                .max()
+                .unwrap_or(0),
+            // its entries all come before any write
+            written_after: 0,
+            prefix_bloom: None,
+        };
+        // a rename can't cross filesystems
+        let target = self.sst_path(&table.name);
+        if self.storage.rename(path, &target).is_err() {
+            self.storage.write(&target, &self.storage.read(path)?)?;
+        }
+        self.manifest.log(vec![Edit::IngestSst(table)])?;
+        // any of its keys may have been read as absent
+        self.absent
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+
+        Ok(())
+    }
+
+    ///
+    /// Removes every key for which `f` returns false.
+    ///
+    /// Done as one merge pass over the memtable and all SSTs, writing the kept
+    /// entries to a single SST that replaces the rest. Nothing older is left
+    /// for tombstones to shadow, so they're dropped too. This holds `&mut self`
+    /// throughout, so no write can land in the middle of it.
+    ///
+    pub fn retain(&mut self, f: impl Fn(&K, &V) -> bool) -> Result<()> {
+        Counters::add(&self.counters.compactions_started, 1);
+        self.throttle(Self::bytes(self.tables()));
+        let kept: Vec<(K, Entry<V>)> = self
+            .merge()?
+            .filter(|(key, entry)| entry.value.as_ref().is_some_and(|value| f(key, value)))
+            .collect();
+
+        let old_tables = self.manifest.state().tables.clone();
+        let mut edits: Vec<Edit> = old_tables
+            .iter()
+            .map(|table| Edit::RemoveSst(table.name.clone()))
+            .collect();
+        let written_after = old_tables
+            .iter()
+            .map(|table| table.written_after)
+            .min()
+            .unwrap_or(self.manifest.state().seq);
+        let mut written = 0;
+        for mut table in self.write_ssts(kept, 0)? {
+            table.written_after = written_after;
+            written += table.bytes;
+            edits.push(Edit::AddSst(table));
+        }
+        edits.push(Edit::SetSequence(self.seq));
+
+        self.manifest.log(edits)?;
+        self.memtable.clear();
+        self.reset_wal()?;
+
+        let read = Self::bytes(&old_tables);
+        self.remove_tables(old_tables)?;
+        self.count_compaction(read, written);
+
+        Ok(())
+    }
+
+    ///
+    /// Merges the SSTs holding keys in `range` into one, dropping the versions
+    /// they shadow, and leaves every other SST alone.
+    ///
+    /// Lookups go through SSTs newest first, so the merged SST takes the place
+    /// of the ones it replaces. Any SST sitting between two overlapping ones is
+    /// merged too, or its keys could end up shadowed by older versions.
+    /// Tombstones are only dropped when nothing older is left underneath.
+    ///
+    pub fn compact_range(&mut self, range: impl RangeBounds<K>) -> Result<()> {
+        let layout = self.sst_layout()?;
+        let overlapping: Vec<usize> = layout
+            .iter()
+            .enumerate()
+            .filter(|(_, info)| Self::overlaps(&range, &info.min_key, &info.max_key))
+            .map(|(i, _)| i)
+            .collect();
+        let (Some(&first), Some(&last)) = (overlapping.first(), overlapping.last()) else {
+            return Ok(());
+        };
+        // a single SST has no duplicates to merge away
+        if first == last {
+            return Ok(());
+        }
+
+        // it takes the place of the oldest input, so it belongs to that level
+        let level = self.tables()[first].level;
+        self.merge_run(first..=last, level)
+    }
+
+    ///
+    /// Flushes the memtable, then merges every SST above `level` down into
+    /// it, along with what's already there, leaving `level` as SSTs with
+    /// disjoint bounds and nothing above it. For consolidating before a
+    /// read-heavy stretch or a backup.
+    ///
+    /// The merge covers everything from the oldest SST at or above `level`
+    /// to the newest, so an SST from a deeper level that's newer than that,
+    /// as an ingested one can be, is merged in and moved up too. Level 0 is
+    /// never merged into, so for it this only flushes.
+    ///
+    pub fn compact_to_level(&mut self, level: usize) -> Result<()> {
+        self.flush()?;
+
+        let Some(first) = self.tables().iter().position(|table| table.level <= level) else {
+            return Ok(());
+        };
+        // already there
+        if self.tables()[first..]
+            .iter()
+            .all(|table| table.level >= level)
+        {
+            return Ok(());
+        }
+        self.merge_run(first..=self.tables().len() - 1, level)
+    }
+
+    fn overlaps(range: &impl RangeBounds<K>, min_key: &K, max_key: &K) -> bool {
+        let starts_after = match range.start_bound() {
+            Bound::Included(start) => start > max_key,
+            Bound::Excluded(start) => start >= max_key,
+            Bound::Unbounded => false,
+        };
+        let ends_before = match range.end_bound() {
+            Bound::Included(end) => end < min_key,
+            Bound::Excluded(end) => end <= min_key,
+            Bound::Unbounded => false,
+        };
+        !starts_after && !ends_before
+    }
+
+    /// Writes sorted entries out as a new SST file, returning what the
+    /// manifest needs to know about it. It still has to be added there.
+    fn write_sst(&mut self, entries: Vec<(K, Entry<V>)>) -> Result<TableMeta> {
+        self.write_sst_numbered(entries, self.manifest.state().next_table, 0)
+    }
+
+    /// Writes sorted entries out as SSTs at `level` of about
+    /// `sst_target_entries` each, so their bounds are disjoint and in key
+    /// order. Nothing is written for no entries. They all still have to be
+    /// added to the manifest, in one edit.
+    fn write_ssts(&mut self, entries: Vec<(K, Entry<V>)>, level: usize) -> Result<Vec<TableMeta>> {
+        // evened out, so the last one isn't left with a handful
+        let count = entries.len().div_ceil(self.sst_target_entries);
+        let per_sst = entries.len().div_ceil(count.max(1));
+        let next_table = self.manifest.state().next_table;
+
+        let mut tables: Vec<TableMeta> = vec![];
+        let mut entries = entries.into_iter().peekable();
+        while entries.peek().is_some() {
+            let chunk = entries.by_ref().take(per_sst).collect();
+            match self.write_sst_numbered(chunk, next_table + tables.len(), level) {
+                Ok(table) => {
+                    self.throttle(table.bytes);
+                    tables.push(table);
+                }
+                Err(e) => {
+                    // nothing points at them yet
+                    for table in tables {
+                        let _ = self.storage.remove(&self.sst_path(&table.name));
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(tables)
+    }
+
+    fn write_sst_numbered(
+        &mut self,
+        entries: Vec<(K, Entry<V>)>,
+        number: usize,
+        level: usize,
+    ) -> Result<TableMeta> {
+        debug_assert!(
+            Self::is_sorted(&entries),
+            "SST entries must be strictly ascending by key"
+        );
+        let config = format::config();
+        let mut meta = TableMeta {
+            name: self
+                .manifest
+                .state()
+                .files
+                .sst(number, &Self::stem(&self.manifest_path)),
+            level,
+            min_key: bincode::encode_to_vec(&entries[0].0, config)?,
+            max_key: bincode::encode_to_vec(&entries[entries.len() - 1].0, config)?,
+            entries: entries.len(),
+            tombstones: entries
+                .iter()
+                .filter(|(_, entry)| entry.value.is_none())
+                .count(),
+            bytes: 0,
+            min_seq: entries
+                .iter()
+                .map(|(_, entry)| entry.seq)
+                .min()
+                .unwrap_or(0),
+            max_seq: entries
+                .iter()
+                .map(|(_, entry)| entry.seq)
+                .max()
+                .unwrap_or(0),
+            // since the last flush; a merge puts in its inputs' instead
+            written_after: self.manifest.state().seq,
+            prefix_bloom: self.prefix_bloom(&entries),
+        };
+
+        let sst = Sst {
+            comparator: Comparator::Ord,
+            schema: Self::schema_fingerprint(),
+            entries,
+        };
+        let payload = sst::file_bytes(&bincode::encode_to_vec(sst, config)?);
+        meta.bytes = payload.len() as u64;
+
+        if let Some(place) = &self.placement {
+            let dir = place(&SstPlacement {
+                level,
+                entries: meta.entries,
+                bytes: meta.bytes,
+            });
+            // the manifest holds the name, so it needs to hold the directory too
+            if !dir.as_os_str().is_empty() {
+                let path = dir.join(&meta.name);
+                let Some(name) = path.to_str() else {
+                    let reason = format!("SST directory {} isn't UTF-8", dir.display());
+                    return Err(Error::InvalidOptions(reason).into());
+                };
+                meta.name = name.to_string();
+            }
+        }
+        let path = self.sst_path(&meta.name);
+        if let Err(e) = self.storage.write(&path, &payload) {
+            let _ = self.storage.remove(&path);
+            return Err(storage::write_error(&path, e));
+        }
+
+        Ok(meta)
+    }
+
+    /// A filter over the key prefixes in `entries`, if the handle was opened
+    /// with a prefix length, which it only can be with byte string keys.
+    fn prefix_bloom(&self, entries: &[(K, Entry<V>)]) -> Option<PrefixBloom> {
+        let len = self.prefix_bloom_len?;
+        let mut prefixes: Vec<&[u8]> = vec![];
+        for (key, _) in entries {
+            let key: &Vec<u8> = (key as &dyn any::Any).downcast_ref()?;
+            // sorted, so keys sharing a prefix are next to each other
+            if let Some(prefix) = key.get(..len) {
+                if prefixes.last() != Some(&prefix) {
+                    prefixes.push(prefix);
+                }
+            }
+        }
+        Some(PrefixBloom::new(
+            len,
+            self.prefix_bloom_hash,
+            prefixes.into_iter(),
+        ))
+    }
+
+    /// Whether `key` is a byte string under one of the prefixes compactions
+    /// drop.
+    fn dropped_by_prefix(&self, key: &K) -> bool {
+        let Some(key) = (key as &dyn any::Any).downcast_ref::<Vec<u8>>() else {
+            return false;
+        };
+        self.drop_prefixes
+            .iter()
+            .any(|prefix| key.starts_with(prefix))
+    }
+
+    fn reset_wal(&mut self) -> Result<()> {
+        self.storage
+            .write(&self.wal_path, &format::header(format::WAL))
+            .map_err(|e| storage::write_error(&self.wal_path, e))?;
+        self.wal_records = 0;
+        self.wal_len = format::HEADER_LEN as u64;
+        self.wal_from = self.seq;
+        #[cfg(test)]
+        {
+            self.wal_synced = format::HEADER_LEN as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Newest entry for every key across just `tables`, in key order,
+    /// tombstones included.
+    fn merge_tables(&self, tables: &[TableMeta]) -> Result<MergeIter<'_, K, V>> {
+        let mut sources: Vec<MergeSource<'_, K, V>> = vec![];
+        for table in tables {
+            sources.push(Box::new(self.open_sst(&table.name)?.entries.into_iter()));
+        }
+        Ok(MergeIter::new(sources))
+    }
+
+    /// Newest entry for every key across the memtable and all SSTs, in key
+    /// order, tombstones included.
+    fn merge(&self) -> Result<MergeIter<'_, K, V>> {
+        let mut sources: Vec<MergeSource<'_, K, V>> = vec![Box::new(
+            self.memtable
+                .iter()
+                .map(|(key, entry)| (key.clone(), entry.clone())),
+        )];
+        for table in self.tables().iter().rev() {
+            let entries = self.open_sst(&table.name)?.entries;
+            sources.push(Box::new(merge::Shared::new(entries)));
+        }
+        Ok(MergeIter::new(sources))
+    }
+
+    ///
+    /// Gets a value addressed by key from the LSM Tree.
+    ///
+    /// Returns None if not present.
+    ///
+    /// An SST that's missing or can't be decoded fails the whole lookup
+    /// rather than being skipped, since the key might have lived there and
+    /// skipping it could hand back an older value.
+    ///
+    /// The value is cloned out, so for large ones a `V` that's cheap to clone
+    /// pays off: `Arc<[u8]>` encodes the same as `Vec<u8>` (though the schema
+    /// check still tells them apart), and a get from the memtable or a recent
+    /// or cached SST just bumps its count. Each SST read from disk decodes a
+    /// fresh copy either way.
+    ///
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        Ok(self.get_with_metadata(key)?.map(|meta| meta.value))
+    }
+
+    ///
+    /// Like `get`, but a value found in the memtable is borrowed rather than
+    /// cloned, for as long as the borrow of the handle lasts. Values from SSTs
+    /// are decoded into owned ones either way.
+    ///
+    pub fn get_cow(&self, key: &K) -> Result<Option<Cow<'_, V>>> {
+        if let Some(entry) = self.memtable.get(key) {
+            Counters::add(&self.counters.gets, 1);
+            return Ok(entry.value.as_ref().map(Cow::Borrowed));
+        }
+        Ok(self.get(key)?.map(Cow::Owned))
+    }
+
+    ///
+    /// Looks up each of `keys`, all against the same state of the database:
+    /// every one is read with `get_as_of` at the `last_seq` taken on the way
+    /// in, so no value is from a write after another key's.
+    ///
+    /// Writes need `&mut self`, so none can land while this holds `&self`
+    /// anyway. Shared behind a lock, that means holding one read guard for
+    /// the whole call, where separate `get`s under separate guards could see
+    /// a write land between them.
+    ///
+    pub fn get_consistent(&self, keys: &[K]) -> Result<Vec<Option<V>>> {
+        let seq = self.last_seq();
+        keys.iter().map(|key| self.get_as_of(key, seq)).collect()
+    }
+
+    ///
+    /// Like `get`, but falls back to `default` if the key isn't present.
+    ///
+    pub fn get_or(&self, key: &K, default: V) -> Result<V> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    ///
+    /// Like `get`, but falls back to calling `f` if the key isn't present.
+    /// `f` isn't called otherwise.
+    ///
+    pub fn get_or_else(&self, key: &K, f: impl FnOnce() -> V) -> Result<V> {
+        Ok(self.get(key)?.unwrap_or_else(f))
+    }
+
+    ///
+    /// Gets a value along with the sequence number of the write that set it,
+    /// to hand back to `put_if_unchanged` later.
+    ///
+    pub fn get_for_update(&self, key: &K) -> Result<Option<(V, u64)>> {
+        Ok(self
+            .get_with_metadata(key)?
+            .map(|meta| (meta.value, meta.seq)))
+    }
+
+    ///
+    /// Puts `value` only if `key` is still at the version `expected_seq` that
+    /// `get_for_update` reported, returning whether it was written. A key that's
+    /// absent or deleted is at version 0.
+    ///
+    /// This holds `&mut self`, so nothing can write between the check and the put.
+    ///
+    pub fn put_if_unchanged(&mut self, key: K, value: V, expected_seq: u64) -> Result<bool> {
+        let current = self.get_with_metadata(&key)?.map_or(0, |meta| meta.seq);
+        if current != expected_seq {
+            return Ok(false);
+        }
+        self.put(key, value)?;
+        Ok(true)
+    }
+
+    ///
+    /// The memtable's entry count, tombstones included, and roughly how many
+    /// bytes its keys and values take encoded. Goes over every entry to
+    /// measure them, so it's worth polling sparingly on a big memtable.
+    ///
+    pub fn memtable_size(&self) -> (usize, usize) {
+        let bytes = self
+            .memtable
+            .iter()
+            // these were all encoded once already, for the log
+            .map(|(key, entry)| {
+                Self::encoded_len(key).unwrap_or(0) + Self::encoded_len(&entry.value).unwrap_or(0)
+            })
+            .sum();
+        (self.memtable.len(), bytes)
+    }
+
+    ///
+    /// Whether the memtable has reached `Options::max_size`, or the log
+    /// `Options::max_wal_bytes`, so the next write flushes first. A scheduler
+    /// can call `flush` itself once this says so, to take the flush off the
+    /// write path.
+    ///
+    pub fn should_flush(&self) -> bool {
+        // with nothing in the memtable a flush wouldn't start the log over
+        let wal_full = self
+            .max_wal_bytes
+            .is_some_and(|max| self.wal_len >= max && !self.memtable.is_empty());
+        self.memtable.len() >= self.max_size || wal_full
+    }
+
+    /// Returns the sequence number of the most recent write.
+    pub fn last_seq(&self) -> u64 {
+        self.seq
+    }
+
+    ///
+    /// The size of `key`'s current value as it's encoded on disk, or `None` if
+    /// the key is absent or deleted.
+    ///
+    /// SSTs don't record entry sizes, so the value is still looked up, but
+    /// it's only measured, never copied into a buffer or handed back.
+    ///
+    pub fn value_size(&self, key: &K) -> Result<Option<usize>> {
+        let Some(meta) = self.get_with_metadata(key)? else {
+            return Ok(None);
+        };
+        Ok(Some(Self::encoded_len(&meta.value)?))
+    }
+
+    /// How many bytes `item` encodes to, without encoding it anywhere.
+    fn encoded_len(item: &impl Encode) -> Result<usize> {
+        let mut size = SizeWriter::default();
+        bincode::encode_into_writer(item, &mut size, format::config())?;
+        Ok(size.bytes_written)
+    }
+
+    /// Fails if `key` or `value` is over the handle's size limit.
+    fn check_size(&self, key: &K, value: Option<&V>) -> Result<()> {
+        let size = Self::encoded_len(key)?;
+        if size > self.max_key_size {
+            let limit = self.max_key_size;
+            return Err(Error::KeyTooLarge { size, limit }.into());
+        }
+        if let Some(value) = value {
+            let size = Self::encoded_len(value)?;
+            if size > self.max_value_size {
+                let limit = self.max_value_size;
+                return Err(Error::ValueTooLarge { size, limit }.into());
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Like `get`, but also reports the sequence number of the value and
+    /// whether it came from the memtable or an SST (and which one).
+    ///
+    pub fn get_with_metadata(&self, key: &K) -> Result<Option<ValueMeta<V>>> {
+        Counters::add(&self.counters.gets, 1);
+        if let Some(entry) = self.memtable.get(key) {
+            return Ok(entry.value.clone().map(|value| ValueMeta {
+                value,
+                seq: entry.seq,
+                source: Source::Memtable,
+            }));
+        }
+
+        if let Some(meta) = self.value_cache().get(key) {
+            return Ok(Some(meta.clone()));
+        }
+        if self.absent_cache().get(key).is_some() {
+            return Ok(None);
+        }
+
+        // search through the tables newest first, skipping any whose bounds rule the key out
+        let config = format::config();
+        for (age, table) in self.tables().iter().rev().enumerate() {
+            let min_key: K = bincode::decode_from_slice(&table.min_key, config)?.0;
+            let max_key: K = bincode::decode_from_slice(&table.max_key, config)?.0;
+            if key < &min_key || key > &max_key {
+                continue;
+            }
+            let sst = self.lookup_sst(age, &table.name)?;
+
+            let search = sst.entries.binary_search_by_key(&key, |(k, _)| k);
+            if let Ok(index) = search {
+                let entry = &sst.entries[index].1;
+                let Some(value) = entry.value.clone() else {
+                    self.remember_absent(key);
+                    return Ok(None);
+                };
+                let meta = ValueMeta {
+                    value,
+                    seq: entry.seq,
+                    source: Source::Sst(table.name.clone()),
+                };
+                let mut values = self.value_cache();
+                if values.enabled() {
+                    values.insert(key.clone(), meta.clone(), Self::encoded_len(&meta.value)?);
+                }
+                return Ok(Some(meta));
+            }
+        }
+
+        self.remember_absent(key);
+        Ok(None)
+    }
+
+    fn remember_absent(&self, key: &K) {
+        let mut absent = self.absent_cache();
+        if absent.enabled() {
+            absent.insert(key.clone(), (), 1);
+        }
+    }
+
+    ///
+    /// The value `key` had once the write numbered `seq` was made, ignoring
+    /// every write after it. `None` if it was absent or deleted then.
+    ///
+    /// Only one version of a key is kept in the memtable and in each SST, so
+    /// the one asked for is gone once an overwrite before the next flush, or
+    /// a compaction, replaces it. This fails with `Error::HistoryUnavailable`
+    /// when that may have happened, rather than answering with an older
+    /// version. Reading as of `last_seq` always works.
+    ///
+    pub fn get_as_of(&self, key: &K, seq: u64) -> Result<Option<V>> {
+        let key = (Bound::Included(key.clone()), Bound::Included(key.clone()));
+        Ok(self.scan_as_of(key, seq)?.next().map(|(_, value)| value))
+    }
+
+    ///
+    /// Like `scan`, with every key as `get_as_of` would read it, in key order.
+    ///
+    /// Reads the whole range before returning, and fails the same way as
+    /// `get_as_of` if any key in it can't be read as of `seq`.
+    ///
+    pub fn scan_as_of(
+        &self,
+        range: impl RangeBounds<K>,
+        seq: u64,
+    ) -> Result<impl Iterator<Item = (K, V)>> {
+        let config = format::config();
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut found: BTreeMap<K, Option<V>> = BTreeMap::new();
+
+        // every write since the last flush, one version of each key
+        let flushed = self.manifest.state().seq;
+        for (key, entry) in self
+            .memtable
+            .iter()
+            .filter(|(key, _)| bounds.contains(*key))
+        {
+            if entry.seq <= seq {
+                found.insert(key.clone(), entry.value.clone());
+            } else if seq > flushed {
+                return Err(Error::HistoryUnavailable(seq).into());
+            }
+        }
+
+        // A version an SST replaced was written after what that SST records
+        // as `written_after`. Where a key is only in an SST in a version newer
+        // than `seq`, the one asked for may be among those, unless that's
+        // already past `seq`. Neighbouring SSTs say nothing about it, since
+        // split compaction output and ingested SSTs sit side by side by key.
+        let mut newer: BTreeMap<K, u64> = BTreeMap::new();
+        for table in self.tables().iter().rev() {
+            // every version here is too new, and none of them could hide the
+            // one asked for
+            if table.min_seq > seq && table.written_after >= seq {
+                continue;
+            }
+            let min_key: K = bincode::decode_from_slice(&table.min_key, config)?.0;
+            let max_key: K = bincode::decode_from_slice(&table.max_key, config)?.0;
+            if !Self::overlaps(&bounds, &min_key, &max_key) {
+                continue;
+            }
+            let entries = self.open_sst(&table.name)?.entries;
+            for (key, entry) in entries {
+                if !bounds.contains(&key) || found.contains_key(&key) {
+                    continue;
+                }
+                if entry.seq > seq {
+                    newer.insert(key, table.written_after);
+                    continue;
+                }
+                if newer.remove(&key).is_some_and(|after| after < seq) {
+                    return Err(Error::HistoryUnavailable(seq).into());
+                }
+                found.insert(key, entry.value);
+            }
+        }
+        // nothing older to have been written before them
+        if newer.values().any(|&after| after < seq) {
+            return Err(Error::HistoryUnavailable(seq).into());
+        }
+
+        Ok(found
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, value?))))
+    }
+
+    ///
+    /// Yields every key written after `seq` in key order, with its newest
+    /// value or None if it was deleted.
+    ///
+    /// Only the newest version of a key is kept, so a key overwritten several
+    /// times shows up once. Changes come from the memtable and whichever SSTs
+    /// still carry those sequences; once SSTs are merged together, history from
+    /// before that point is gone.
+    ///
+    pub fn changes_since(&self, seq: u64) -> Result<impl Iterator<Item = (K, Option<V>)>> {
+        let mut changes: BTreeMap<K, Option<V>> = BTreeMap::new();
+
+        for (key, entry) in self.memtable.iter().filter(|(_, entry)| entry.seq > seq) {
+            changes.insert(key.clone(), entry.value.clone());
+        }
+
+        // newest first, so the first version seen for a key wins. An older SST
+        // can still hold newer writes than a later one, as split compaction
+        // output is ordered by key, so each is weighed by its own range.
+        for table in self.tables().iter().rev() {
+            if table.max_seq <= seq {
+                continue;
+            }
+            let sst = self.open_sst(&table.name)?;
+            for (key, entry) in sst.entries {
+                if entry.seq > seq {
+                    changes.entry(key).or_insert(entry.value);
+                }
+            }
+        }
+
+        Ok(changes.into_iter())
+    }
+
+    ///
+    /// Iterates over every live key-value pair in key order; `Iter` says
+    /// exactly what that guarantees.
+    ///
+    pub fn iter(&self) -> Result<Iter<'_, K, V>> {
+        Ok(Iter {
+            merge: self.merge()?,
+        })
+    }
+
+    ///
+    /// The keys whose live values differ between this database and `other`,
+    /// in key order, as changes from this one to `other`. Empty when the two
+    /// hold the same pairs, however differently they're laid out in SSTs.
+    ///
+    /// Walks the two `iter`s side by side, so neither is gathered up in
+    /// memory. For checking a replica or a restored backup.
+    ///
+    pub fn diff<'a>(
+        &'a self,
+        other: &'a Lsm<K, V>,
+    ) -> Result<impl Iterator<Item = Diff<K, V>> + 'a> {
+        Ok(DiffIter::new(self.iter()?, other.iter()?))
+    }
+
+    ///
+    /// Iterates over the live key-value pairs with keys in `range`, in key
+    /// order, with the guarantees `Iter` gives.
+    ///
+    pub fn scan(&self, range: impl RangeBounds<K>) -> Result<Iter<'_, K, V>> {
+        self.scan_tables(range, |_| true)
+    }
+
+    ///
+    /// Like `scan`, with each key and value as the bytes they're stored as,
+    /// for passing along to something that decodes them itself, with the
+    /// same `bincode` configuration and types.
+    ///
+    /// SST entries aren't delimited on disk without their types, so the SSTs
+    /// are still decoded to merge them and each pair is encoded again on the
+    /// way out. What this saves is the caller handling `K` and `V`, not the
+    /// decoding. A pair that fails to encode comes out as its error, and the
+    /// pairs after it still follow.
+    ///
+    pub fn scan_raw(
+        &self,
+        range: impl RangeBounds<K>,
+    ) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_> {
+        let config = format::config();
+        Ok(self.scan(range)?.map(move |(key, value)| {
+            Ok((
+                bincode::encode_to_vec(key, config)?,
+                bincode::encode_to_vec(value, config)?,
+            ))
+        }))
+    }
+
+    /// Like `scan`, reading only the SSTs `read` picks.
+    fn scan_tables(
+        &self,
+        range: impl RangeBounds<K>,
+        read: impl Fn(&TableMeta) -> bool,
+    ) -> Result<Iter<'_, K, V>> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+
+        let mut sources = vec![merge::bounded(
+            self.memtable
+                .iter()
+                .map(|(key, entry)| (key.clone(), entry.clone())),
+            bounds.clone(),
+        )];
+        for table in self.tables().iter().rev().filter(|table| read(table)) {
+            let entries = self.open_sst(&table.name)?.entries;
+            sources.push(merge::bounded(merge::Shared::new(entries), bounds.clone()));
+        }
+
+        Ok(Iter {
+            merge: MergeIter::new(sources),
+        })
+    }
+
+    ///
+    /// Re-reads the log and brings the memtable up to date with it, without
+    /// reopening. For picking up records another process appended, as a
+    /// follower tailing a leader's log would.
+    ///
+    /// Every record is weighed by sequence number, so nothing the handle
+    /// already has is replaced by an older version, and records at or below
+    /// what's been flushed to SSTs are ignored. Whoever else writes the log
+    /// has to number its records above this handle's, and can't append while
+    /// this handle does. A record cut off at the end is left for a later
+    /// `recover` to pick up once the rest of it is there. Write hooks aren't
+    /// called for what's recovered.
+    ///
+    pub fn recover(&mut self) -> Result<RecoveryReport> {
+        let log = self.storage.read(&self.wal_path)?;
+        let records = wal::read_records::<K, V>(&log, &self.wal_path)?;
+        Self::check_skipped(self.strict, &self.wal_path, &records.skipped)?;
+        let mut report = RecoveryReport {
+            records: records.entries.len(),
+            skipped: records.skipped,
+            trailing_bytes: records.trailing_bytes,
+            ..Default::default()
+        };
+
+        let flushed = self.manifest.state().seq;
+        for entry in records.entries {
+            self.seq = self.seq.max(entry.seq);
+            let known = self
+                .memtable
+                .get(&entry.key)
+                .is_some_and(|current| current.seq >= entry.seq);
+            if entry.seq <= flushed || known {
+                continue;
+            }
+            self.forget_value(&entry.key);
+            self.memtable.insert(
+                entry.key,
+                Entry {
+                    seq: entry.seq,
+                    value: entry.value,
+                },
+            );
+            report.applied += 1;
+        }
+        self.wal_records = report.records;
+        self.wal_len = log.len() as u64;
+
+        Ok(report)
+    }
+
+    ///
+    /// The writes after sequence number `from_seq`, in sequence order, read
+    /// back out of the log: what a replica that has applied everything up to
+    /// `from_seq` needs next, say through `put_with_seq`. Writes made after
+    /// this returns aren't in it, so a replica keeps up by asking again from
+    /// the last one it applied, perhaps whenever `watch` says there's more.
+    ///
+    /// A flush starts the log over, and coalescing it keeps only each key's
+    /// newest version. If a write after `from_seq` may have gone that way,
+    /// this fails with `Error::HistoryUnavailable` rather than leave a gap,
+    /// and the replica has to catch up some other way, for instance from a
+    /// copy of the SSTs. After a reopen that's known not to have happened
+    /// only when the log's sequence numbers follow on from the SSTs' without
+    /// a gap.
+    ///
+    pub fn wal_stream(&self, from_seq: u64) -> Result<impl Iterator<Item = LogRecord<K, V>>> {
+        if from_seq < self.wal_from {
+            return Err(Error::HistoryUnavailable(from_seq).into());
+        }
+        let records =
+            wal::read_records::<K, V>(&self.storage.read(&self.wal_path)?, &self.wal_path)?;
+        // a gap in the stream either way
+        Self::check_skipped(true, &self.wal_path, &records.skipped)?;
+
+        let mut stream: Vec<LogRecord<K, V>> = records
+            .entries
+            .into_iter()
+            .filter(|entry| entry.seq > from_seq)
+            .map(|entry| LogRecord {
+                seq: entry.seq,
+                key: entry.key,
+                value: entry.value,
+            })
+            .collect();
+        stream.sort_by_key(|record| record.seq);
+        Ok(stream.into_iter())
+    }
+
+    ///
+    /// Iterates over the keys in `range` whose newest version is a tombstone,
+    /// in key order: the deletes compaction hasn't dropped yet. For working
+    /// out why a key is missing, or why space isn't being reclaimed.
+    ///
+    pub fn scan_tombstones(
+        &self,
+        range: impl RangeBounds<K>,
+    ) -> Result<impl Iterator<Item = K> + '_> {
+        let merge = self.scan(range)?.merge;
+        Ok(merge.filter_map(|(key, entry)| entry.value.is_none().then_some(key)))
+    }
+
+    ///
+    /// Counts the live keys in `range`, the ones `scan(range)` would yield,
+    /// without cloning a single value. SSTs whose bounds miss the range aren't
+    /// read at all.
+    ///
+    pub fn count_range(&self, range: impl RangeBounds<K>) -> Result<usize> {
+        let config = format::config();
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+
+        // only whether each version is a tombstone matters
+        let mut sources: Vec<MergeSource<'_, K, ()>> = vec![merge::bounded(
+            self.memtable.iter().map(|(key, entry)| {
+                let entry = Entry {
+                    seq: entry.seq,
+                    value: entry.value.as_ref().map(|_| ()),
+                };
+                (key.clone(), entry)
+            }),
+            bounds.clone(),
+        )];
+        for table in self.tables().iter().rev() {
+            let min_key: K = bincode::decode_from_slice(&table.min_key, config)?.0;
+            let max_key: K = bincode::decode_from_slice(&table.max_key, config)?.0;
+            if !Self::overlaps(&bounds, &min_key, &max_key) {
+                continue;
+            }
+            let entries = self.open_sst(&table.name)?.entries;
+            sources.push(merge::bounded(
+                entries.into_iter().map(|(key, entry)| {
+                    let entry = Entry {
+                        seq: entry.seq,
+                        value: entry.value.map(|_| ()),
+                    };
+                    (key, entry)
+                }),
+                bounds.clone(),
+            ));
+        }
+
+        Ok(MergeIter::new(sources)
+            .filter(|(_, entry)| entry.value.is_some())
+            .count())
+    }
+
+    ///
+    /// Like `scan`, yielding only the pairs `pred` accepts.
+    ///
+    /// `pred` is checked against each version of a key before the sources
+    /// are merged, so a memtable value it rejects is never cloned; a rejected
+    /// version stands in as a tombstone, keeping older versions of its key
+    /// hidden. SSTs are decoded whole either way, so a predicate that only
+    /// looks at keys doesn't save decoding their values. SSTs whose bounds
+    /// miss the range aren't read at all.
+    ///
+    pub fn scan_filter<'a>(
+        &'a self,
+        range: impl RangeBounds<K>,
+        pred: impl Fn(&K, &V) -> bool + 'a,
+    ) -> Result<Iter<'a, K, V>> {
+        let config = format::config();
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let pred = std::rc::Rc::new(pred);
+
+        let memtable = {
+            let (bounds, pred) = (bounds.clone(), pred.clone());
+            self.memtable
+                .iter()
+                .filter(move |(key, _)| bounds.contains(*key))
+                .map(move |(key, entry)| {
+                    let value = entry.value.as_ref().filter(|value| pred(key, value));
+                    let entry = Entry {
+                        seq: entry.seq,
+                        value: value.cloned(),
+                    };
+                    (key.clone(), entry)
+                })
+        };
+        let mut sources: Vec<MergeSource<'a, K, V>> = vec![Box::new(memtable)];
+        for table in self.tables().iter().rev() {
+            let min_key: K = bincode::decode_from_slice(&table.min_key, config)?.0;
+            let max_key: K = bincode::decode_from_slice(&table.max_key, config)?.0;
+            if !Self::overlaps(&bounds, &min_key, &max_key) {
+                continue;
+            }
+            let entries = self.open_sst(&table.name)?.entries;
+            let pred = pred.clone();
+            sources.push(Box::new(
+                merge::bounded(merge::Shared::new(entries), bounds.clone()).map(
+                    move |(key, entry)| {
+                        let entry = Entry {
+                            seq: entry.seq,
+                            value: entry.value.filter(|value| pred(&key, value)),
+                        };
+                        (key, entry)
+                    },
+                ),
+            ));
+        }
+
+        Ok(Iter {
+            merge: MergeIter::new(sources),
+        })
+    }
+
+    ///
+    /// Iterates over the live key-value pairs from the first key at or after
+    /// `start` to the end, in key order. The same as `scan(start..)`.
+    ///
+    pub fn iter_from(&self, start: &K) -> Result<Iter<'_, K, V>> {
+        self.scan((Bound::Included(start.clone()), Bound::Unbounded))
+    }
+
+    ///
+    /// Like `scan`, passing each live pair through `f` as it's reached. `f`
+    /// only runs as the iterator is advanced, and never for deleted keys or
+    /// ones outside `range`.
+    ///
+    pub fn scan_map<'a, T>(
+        &'a self,
+        range: impl RangeBounds<K>,
+        mut f: impl FnMut(K, V) -> T + 'a,
+    ) -> Result<impl DoubleEndedIterator<Item = T> + 'a> {
+        Ok(self.scan(range)?.map(move |(key, value)| f(key, value)))
+    }
+    ///
+    /// Iterates over every live value, in the order of their keys.
+    ///
+    pub fn values(&self) -> Result<impl Iterator<Item = V> + '_> {
+        Ok(self.iter()?.map(|(_, value)| value))
+    }
+
+    /// An SST for a point lookup, `age` tables back from the newest. The newest
+    /// few are served from memory after the first read.
+    fn lookup_sst(&self, age: usize, table: &str) -> Result<Arc<Sst<K, V>>> {
+        if age >= RECENT_SSTS {
+            return Ok(Arc::new(self.open_sst(table)?));
+        }
+
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, sst)) = recent.iter().find(|(name, _)| name == table) {
+            return Ok(sst.clone());
+        }
+        let sst = Arc::new(self.open_sst(table)?);
+        // whatever's no longer among the newest makes way
+        let newest: Vec<&str> = self
+            .tables()
+            .iter()
+            .rev()
+            .take(RECENT_SSTS)
+            .map(|table| table.name.as_str())
+            .collect();
+        recent.retain(|(name, _)| newest.contains(&name.as_str()));
+        recent.push((table.to_string(), sst.clone()));
+        Ok(sst)
+    }
+
+    fn value_cache(&self) -> std::sync::MutexGuard<'_, ValueCache<K, ValueMeta<V>>> {
+        self.values.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn absent_cache(&self) -> std::sync::MutexGuard<'_, ValueCache<K, ()>> {
+        self.absent.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Drops `key` from the value cache and the absent keys, for when a newer
+    /// version is written.
+    fn forget_value(&mut self, key: &K) {
+        self.values
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(key);
+        self.absent
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(key);
+    }
+
+    /// Reads an SST from disk, counting it in the stats.
+    fn open_sst(&self, table: &str) -> Result<Sst<K, V>> {
+        Counters::add(&self.counters.sst_reads, 1);
+        self.read_sst(table)
+    }
+
+    fn read_sst(&self, table: &str) -> Result<Sst<K, V>> {
+        let path = self.sst_path(table);
+        match self.storage.read(&path) {
+            Ok(bytes) => Self::decode_sst(&bytes, &path),
+            // the io error stays underneath, for callers that look for that instead
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(anyhow::Error::new(e).context(Error::MissingSst(path)))
+            }
+            Err(e) => Err(e).with_context(|| format!("opening SST {}", path.display())),
+        }
+    }
+
+    /// Where the SST named `table` in the manifest lives.
+    fn sst_path(&self, table: &str) -> PathBuf {
+        self.dir.join(table)
+    }
+
+    fn read_sst_file(&self, path: &Path) -> Result<Sst<K, V>> {
+        let bytes = self
+            .storage
+            .read(path)
+            .with_context(|| format!("opening SST {}", path.display()))?;
+        Self::decode_sst(&bytes, path)
+    }
+
+    fn decode_sst(bytes: &[u8], path: &Path) -> Result<Sst<K, V>> {
+        let body = sst::body(bytes, path)?;
+
+        Ok(bincode::decode_from_slice(body, format::config())
+            .with_context(|| format!("decoding SST {}", path.display()))?
+            .0)
+    }
+
+    ///
+    /// Checks that every SST is in the order lookups rely on.
+    ///
+    /// Fails with `Error::UnsortedSst` naming the first table that isn't.
+    ///
+    pub fn verify(&self) -> Result<()> {
+        for table in self.tables() {
+            let sst = self.open_sst(&table.name)?;
+            if sst.comparator != Comparator::Ord || !Self::is_sorted(&sst.entries) {
+                return Err(Error::UnsortedSst(table.name.clone()).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Checks that the manifest and the SST files agree: every SST it lists is
+    /// there and reads back intact, and there are no others named like this
+    /// database's beside it, as a crash partway through a flush or compaction
+    /// can leave behind.
+    ///
+    /// Fails with `Error::MissingSst` for the first listed SST that's gone,
+    /// the error reading the first one that's damaged, or `Error::OrphanSsts`
+    /// naming every SST the manifest doesn't list. Nothing is changed.
+    ///
+    pub fn check_consistency(&self) -> Result<()> {
+        for table in self.tables() {
+            let path = self.sst_path(&table.name);
+            if !self.storage.exists(&path) {
+                return Err(Error::MissingSst(path).into());
+            }
+            self.read_sst(&table.name)?;
+        }
+
+        let stem = Self::stem(&self.manifest_path);
+        let files = &self.manifest.state().files;
+        let mut orphans: Vec<PathBuf> = self
+            .storage
+            .list(&self.dir)?
+            .into_iter()
+            .filter(|name| files.is_sst(name, &stem))
+            .filter(|name| !self.tables().iter().any(|table| &table.name == name))
+            .map(|name| self.sst_path(&name))
+            .collect();
+        if !orphans.is_empty() {
+            orphans.sort();
+            return Err(Error::OrphanSsts(orphans).into());
+        }
+
+        Ok(())
+    }
+
+    fn is_sorted(entries: &[(K, Entry<V>)]) -> bool {
+        entries.windows(2).all(|pair| pair[0].0 < pair[1].0)
+    }
+
+    ///
+    /// Describes every live SST, oldest first, from what the manifest records
+    /// about them. No SST files are read.
+    ///
+    pub fn sst_layout(&self) -> Result<Vec<SstInfo<K>>> {
+        let config = format::config();
+        self.tables()
+            .iter()
+            .map(|table| {
+                Ok(SstInfo {
+                    name: table.name.clone(),
+                    level: table.level,
+                    min_key: bincode::decode_from_slice(&table.min_key, config)?.0,
+                    max_key: bincode::decode_from_slice(&table.max_key, config)?.0,
+                    entries: table.entries,
+                    tombstones: table.tombstones,
+                    bytes: table.bytes,
+                })
+            })
+            .collect()
+    }
+
+    ///
+    /// Estimates the bytes a full compaction of the SSTs would leave: their
+    /// size without tombstones and the versions newer writes shadow. Only the
+    /// manifest is read, so this is cheap to call. Comparing it against the
+    /// SSTs' total `bytes` gives the space amplification.
+    ///
+    /// SSTs with overlapping key ranges are assumed to hold mostly the same
+    /// keys, so a group of them counts as many keys as its largest SST, with
+    /// the group's share of tombstones taken out.
+    ///
+    pub fn estimate_live_data_size(&self) -> Result<usize> {
+        let mut layout = self.sst_layout()?;
+        layout.sort_by(|a, b| a.min_key.cmp(&b.min_key));
+
+        let mut live = 0.0;
+        let mut group: Vec<&SstInfo<K>> = vec![];
+        for info in &layout {
+            // sorted by start, so a table either overlaps the group or starts a new one
+            let overlaps = group.iter().any(|other| info.min_key <= other.max_key);
+            if !overlaps {
+                live += Self::estimate_group(&group);
+                group.clear();
+            }
+            group.push(info);
+        }
+        live += Self::estimate_group(&group);
+
+        Ok(live as usize)
+    }
+
+    fn estimate_group(group: &[&SstInfo<K>]) -> f64 {
+        let entries: usize = group.iter().map(|info| info.entries).sum();
+        if entries == 0 {
+            return 0.0;
+        }
+        let tombstones: usize = group.iter().map(|info| info.tombstones).sum();
+        let bytes: u64 = group.iter().map(|info| info.bytes).sum();
+        let keys = group.iter().map(|info| info.entries).max().unwrap_or(0);
+
+        let live_share = 1.0 - tombstones as f64 / entries as f64;
+        keys as f64 * live_share * (bytes as f64 / entries as f64)
+    }
+
+    ///
+    /// Returns once no flush or compaction is left pending.
+    ///
+    /// Both run inline, on the thread whose write or call sets them off, so
+    /// by the time that call returns they're done, and this returns at once.
+    /// It's for code that checks `stats` or `sst_layout` after writing, so it
+    /// doesn't depend on that.
+    ///
+    pub fn wait_for_idle(&self) {}
+
+    ///
+    /// Counts what this handle has done since the last `reset_stats`, or since
+    /// it was opened if there hasn't been one.
+    ///
+    pub fn stats(&self) -> Stats {
+        self.counters.window()
+    }
+
+    ///
+    /// Counts what this handle has done since it was opened. Unaffected by
+    /// `reset_stats`.
+    ///
+    pub fn total_stats(&self) -> Stats {
+        self.counters.totals()
+    }
+
+    ///
+    /// Starts a new window for `stats`, returning the one that just ended.
+    /// Calling this at a fixed interval gives per-interval rates.
+    ///
+    pub fn reset_stats(&self) -> Stats {
+        self.counters.reset()
+    }
+
+    // SSTs are named after the manifest, so databases sharing a directory don't clash
+    fn stem(manifest: &Path) -> String {
+        // lossy, but the same every time, so a name that isn't UTF-8 still maps to one set of SSTs
+        manifest.file_stem().unwrap().to_string_lossy().into_owned()
+    }
+
+    fn tables(&self) -> &[TableMeta] {
+        &self.manifest.state().tables
+    }
+
+    /// Rebuilds the memtable from the log, along with the highest sequence
+    /// number seen in it. No log means nothing to recover.
+    ///
+    /// Records at or below `flushed` are already in SSTs: a crash between a
+    /// flush's manifest edit and clearing the log leaves them behind, and
+    /// replaying them would bring back what a later SST, such as the output
+    /// of `retain`, has dropped.
+    ///
+    /// The memtable the log rebuilds over SSTs holding everything up to
+    /// `flushed`, the highest sequence number in it, and the sequence number
+    /// after which every write is still in it.
+    ///
+    /// The log only holds every write if their numbers follow on from
+    /// `flushed` without a gap. Otherwise it may have been coalesced, so only
+    /// what's after its newest record is known to be complete.
+    ///
+    fn try_log_recovery(
+        storage: &dyn Storage,
+        log_path: &Path,
+        flushed: u64,
+    ) -> Result<LogRecovery<K, V>> {
+        let mut memtable: BTreeMap<K, Entry<V>> = BTreeMap::new();
+        let mut max_seq = 0;
+        if !storage.exists(log_path) {
+            return Ok(LogRecovery {
+                memtable,
+                max_seq,
+                wal_from: flushed,
+                skipped: vec![],
+                end: 0,
+            });
+        }
+
+        let records = wal::read_records::<K, V>(&storage.read(log_path)?, log_path)?;
+        let mut unflushed: Vec<u64> = vec![];
+        for entry in records.entries {
+            max_seq = max_seq.max(entry.seq);
+            if entry.seq <= flushed {
+                continue;
+            }
+            unflushed.push(entry.seq);
+            // by sequence rather than position, since a coalesced log isn't in write order
+            if memtable
+                .get(&entry.key)
+                .is_some_and(|newer| newer.seq > entry.seq)
+            {
+                continue;
+            }
+            // tombstones stay in the memtable so they shadow older SSTs
+            memtable.insert(
+                entry.key,
+                Entry {
+                    seq: entry.seq,
+                    value: entry.value,
+                },
+            );
+        }
+
+        unflushed.sort_unstable();
+        let gapless = unflushed
+            .iter()
+            .zip(flushed + 1..)
+            .all(|(&seq, n)| seq == n);
+        let wal_from = if gapless { flushed } else { max_seq };
+        Ok(LogRecovery {
+            memtable,
+            max_seq,
+            wal_from,
+            skipped: records.skipped,
+            end: records.end as u64,
+        })
+    }
+
+    /// Fingerprints the key/value types by name. crc rather than `DefaultHasher`
+    /// since this is persisted and has to stay stable across builds.
+    fn schema_fingerprint() -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(any::type_name::<K>().as_bytes());
+        hasher.update(&[0]);
+        hasher.update(any::type_name::<V>().as_bytes());
+        hasher.finalize()
+    }
+}
+
+impl<K, V> Lsm<K, V>
+where
+    K: Encode + Decode + Hash + Ord + Clone + Send + Sync + 'static,
+    V: Encode + Decode + Hash + Ord + Clone + Send + Sync + 'static,
+{
+    ///
+    /// Like `open_or_create_with`, but handing out one handle per database
+    /// in the process: opening a path that's already open, under any
+    /// spelling of it, returns the handle that's there, and `options` are
+    /// ignored. The database is closed once every clone of the handle is
+    /// dropped.
+    ///
+    /// Fails with `Error::SchemaMismatch` if the path is open with other
+    /// key/value types. Handles opened some other way aren't known here.
+    ///
+    pub fn open_shared(path: &Path, options: Options) -> Result<SharedLsm<K, V>> {
+        shared::get_or_open(path, Self::schema_fingerprint(), || {
+            Ok(RwLock::new(Self::open_or_create_with(path, options)?))
+        })
+    }
+}
+
+impl<V> Lsm<Vec<u8>, V>
+where
+    V: Encode + Decode + Hash + Ord + Clone + 'static,
+{
+    ///
+    /// Iterates over the live key-value pairs whose keys start with `prefix`,
+    /// in key order. An empty prefix scans everything.
+    ///
+    /// SSTs whose prefix bloom filter rules the prefix out aren't read. That
+    /// only works for prefixes at least `Options::prefix_bloom_len` long.
+    ///
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Iter<'_, Vec<u8>, V>> {
+        self.scan_tables(prefix_range(prefix), |table| {
+            table
+                .prefix_bloom
+                .as_ref()
+                .is_none_or(|bloom| bloom.may_contain(prefix))
+        })
+    }
+
+    ///
+    /// Deletes every key starting with `prefix`, as one batch of tombstones.
+    /// Reads stop seeing them right away, and compaction into the bottom
+    /// level drops them along with what they shadow. An empty prefix deletes
+    /// everything.
+    ///
+    /// Each key is looked up and gets its own tombstone, so this costs as much
+    /// as scanning the prefix.
+    ///
+    pub fn delete_prefix(&mut self, prefix: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, _) in self.scan_prefix(prefix)? {
+            batch.delete(key);
+        }
+        self.commit(batch)
+    }
+
+    ///
+    /// Subscribes to the writes on keys starting with `prefix`, as `watch`
+    /// does for a range.
+    ///
+    pub fn watch_prefix(&self, prefix: &[u8]) -> Receiver<Change<Vec<u8>, V>> {
+        self.watch(prefix_range(prefix))
+    }
+}
+
+impl<K> Lsm<K, i64>
+where
+    K: Encode + Decode + Hash + Ord + Clone + 'static,
+{
+    ///
+    /// Adds `delta` to the counter at `key` and returns the new count. A key
+    /// that's absent or deleted counts as 0.
+    ///
+    /// A sum past the range of `i64` saturates or wraps, as set by
+    /// `Options::counter_overflow`.
+    ///
+    pub fn increment(&mut self, key: K, delta: i64) -> Result<i64> {
+        let current = self.get(&key)?.unwrap_or(0);
+        let count = match self.counter_overflow {
+            CounterOverflow::Saturate => current.saturating_add(delta),
+            CounterOverflow::Wrap => current.wrapping_add(delta),
+        };
+        self.put(key, count)?;
+        Ok(count)
+    }
+}
+
+/// Iterator over the live key-value pairs of an `Lsm`, in key order.
+///
+/// Keys come strictly ascending by their `Ord`, from either end, each once
+/// with its newest value, and deleted keys are left out. That holds however
+/// the data is spread over the memtable and SSTs, and whether or not
+/// compaction has run, so it's the same as iterating a `BTreeMap` of what
+/// was last written to each key.
+///
+/// A clone carries on from the same position independently of the original.
+/// The two share what they've read from SSTs rather than copying it, and
+/// clone each pair they yield only while the other is still around.
+///
+pub struct Iter<'a, K, V> {
+    merge: MergeIter<'a, K, V>,
+}
+
+impl<'a, K: Clone + 'a, V: Clone + 'a> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Iter<'a, K, V> {
+        Iter {
+            merge: self.merge.clone(),
+        }
+    }
+}
+
+impl<K: Ord, V> Iterator for Iter<'_, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        // skip over deleted keys
+        loop {
+            let (key, entry) = self.merge.next()?;
+            if let Some(value) = entry.value {
+                return Some((key, value));
+            }
+        }
+    }
+}
+
+/// Walks from the last key back, and can be mixed with `next` on the same
+/// iterator; the two ends meet without yielding any key twice.
+impl<K: Ord, V> DoubleEndedIterator for Iter<'_, K, V> {
+    fn next_back(&mut self) -> Option<(K, V)> {
+        loop {
+            let (key, entry) = self.merge.next_back()?;
+            if let Some(value) = entry.value {
+                return Some((key, value));
+            }
+        }
+    }
+}
+
+///
+/// The pairs of a consumed handle, from `Lsm::into_iter`, in the order `Iter`
+/// gives them.
+///
+/// The SSTs are all read up front. If one can't be, its error is the only
+/// item, in place of any pairs.
+///
+pub struct IntoIter<K, V> {
+    pairs: Option<Iter<'static, K, V>>,
+    error: Option<anyhow::Error>,
+}
+
+impl<K: Ord, V> Iterator for IntoIter<K, V> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Result<(K, V)>> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        self.pairs.as_mut()?.next().map(Ok)
+    }
+}
+
+/// Consumes the handle, moving entries out rather than cloning them.
+impl<K, V> IntoIterator for Lsm<K, V>
+where
+    K: Encode + Decode + Hash + Ord + Clone + 'static,
+    V: Encode + Decode + Hash + Ord + Clone + 'static,
+{
+    type Item = Result<(K, V)>;
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(self) -> IntoIter<K, V> {
+        let mut sources: Vec<MergeSource<'static, K, V>> = vec![];
+        for table in self.tables().iter().rev() {
+            match self.read_sst(&table.name) {
+                Ok(sst) => sources.push(Box::new(merge::Shared::new(sst.entries))),
+                Err(e) => {
+                    return IntoIter {
+                        pairs: None,
+                        error: Some(e),
+                    }
+                }
+            }
+        }
+        // collected, since a map's own iterator can't be cloned
+        let memtable: Vec<(K, Entry<V>)> = self.memtable.into_iter().collect();
+        sources.insert(0, Box::new(merge::Shared::new(memtable)));
+
+        IntoIter {
+            pairs: Some(Iter {
+                merge: MergeIter::new(sources),
+            }),
+            error: None,
+        }
+    }
+}
+
+/// Puts every pair in turn.
+///
+/// # Panics
+///
+/// If a write fails, as `Extend` has no way to return the error. The pairs
+/// before it stay written. To handle the error instead, `put` each pair, or
+/// `commit` them as a `WriteBatch`; to fill a new database, `Lsm::bulk_load`.
+impl<K, V> Extend<(K, V)> for Lsm<K, V>
+where
+    K: Encode + Decode + Hash + Ord + Clone + 'static,
+    V: Encode + Decode + Hash + Ord + Clone + 'static,
+{
+    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, entries: T) {
+        for (key, value) in entries {
+            self.put(key, value).expect("failed to write");
+        }
+    }
+}
+
+/// A summary for logs: where the database is, how full the memtable is,
+/// its SSTs by level, and whether the next write or flush has work to do.
+/// Never shows keys or values.
+impl<K, V> fmt::Debug for Lsm<K, V>
+where
+    K: Encode + Decode + Hash + Ord + Clone + 'static,
+    V: Encode + Decode + Hash + Ord + Clone + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut levels: BTreeMap<usize, usize> = BTreeMap::new();
+        for table in self.tables() {
+            *levels.entry(table.level).or_default() += 1;
+        }
+        let due = self.due_compactions();
+        // without a priority only the L0 trigger is acted on
+        let compaction_due = due.iter().any(|candidate| {
+            self.compaction_priority.is_some() || candidate.reason == compaction::Reason::L0Overflow
+        });
+
+        f.debug_struct("Lsm")
+            .field("manifest", &self.manifest_path)
+            .field("last_seq", &self.seq)
+            .field(
+                "memtable",
+                &format_args!("{}/{}", self.memtable.len(), self.max_size),
+            )
+            .field("wal_records", &self.wal_records)
+            .field("ssts_by_level", &levels)
+            .field(
+                "sst_bytes",
+                &self.tables().iter().map(|table| table.bytes).sum::<u64>(),
+            )
+            .field("flush_due", &self.should_flush())
+            .field("compaction_due", &compaction_due)
+            .finish()
+    }
+}
+
+// TODO write some actual tests for this
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::{self, OpenOptions},
+        sync::{Mutex, MutexGuard},
+    };
+
+    // every handle shares `.log` in the working directory, so tests can't
+    // overlap; one test moves the working directory, so in-memory tests that
+    // open a path twice take it too
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    thread_local! {
+        // heap allocations made on this thread so far
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    /// The system allocator, counting allocations per thread so a test can
+    /// see what a stretch of its own code allocates.
+    struct CountingAlloc;
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            unsafe { std::alloc::System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            unsafe { std::alloc::System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static COUNTING: CountingAlloc = CountingAlloc;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|count| count.get())
+    }
+
+    /// Holds the test lock and cleans up a database's files on both ends of a test.
+    struct TestDb {
+        name: &'static str,
+        _guard: MutexGuard<'static, ()>,
+    }
+
+    impl TestDb {
+        fn new(name: &'static str) -> TestDb {
+            let db = TestDb {
+                name,
+                _guard: LOCK.lock().unwrap_or_else(|e| e.into_inner()),
+            };
+            db.cleanup();
+            db
+        }
+
+        fn manifest(&self) -> &'static Path {
+            Path::new(self.name)
+        }
+
+        fn cleanup(&self) {
+            remove_db_files(self.name);
+        }
+    }
+
+    fn remove_db_files(manifest: &str) {
+        let stem = Path::new(manifest).file_stem().unwrap().to_str().unwrap();
+        let _ = fs::remove_file(".log");
+        let _ = fs::remove_file(manifest);
+        // quarantined SSTs keep their name, with a suffix
+        let quarantined = format!("{stem}.quarantined");
+        for file in fs::read_dir(".").unwrap().flatten() {
+            let file_name = file.file_name().to_string_lossy().into_owned();
+            if file_name.starts_with("sst")
+                && (file_name.ends_with(stem) || file_name.ends_with(&quarantined))
+            {
+                let _ = fs::remove_file(file.path());
+            }
+        }
+    }
+
+    impl Drop for TestDb {
+        fn drop(&mut self) {
+            self.cleanup();
+        }
+    }
+
+    #[test]
+    fn it_works() -> anyhow::Result<()> {
+        let db = TestDb::new("test.lsm");
+        let mut lsm: Lsm<String, u32> = Lsm::open_or_create(db.manifest())?;
+
+        lsm.put("p".to_string(), 4)?;
+        lsm.put("j".to_string(), 7)?;
+
+        // println!("pranoy = {:?}", lsm.get(&"pranoy".to_string())?);
+        assert_eq!(lsm.get(&"p".to_string())?, Some(4));
+
+        lsm.put("b".to_string(), 10)?;
+
+        // println!("pranoy = {}", lsm.get(&"pranoy".to_string())?.unwrap());
+        assert_eq!(lsm.get(&"p".to_string())?, Some(4));
+
+        lsm.put("a".to_string(), 2)?;
+        // println!("june = {}", lsm.get(&"june".to_string())?.unwrap());
+        assert_eq!(lsm.get(&"j".to_string())?, Some(7));
+
+        lsm.put("t".to_string(), 3847)?;
+        // println!("-----");
+        // println!("pranoy = {}", lsm.get(&"pranoy".to_string())?.unwrap());
+        // println!("june = {}", lsm.get(&"june".to_string())?.unwrap());
+        // println!("bentry = {}", lsm.get(&"bentry".to_string())?.unwrap());
+        // println!("andy = {}", lsm.get(&"andy".to_string())?.unwrap());
+        // println!("tony = {}", lsm.get(&"tony".to_string())?.unwrap());
+        assert_eq!(lsm.get(&"p".to_string())?, Some(4));
+        assert_eq!(lsm.get(&"j".to_string())?, Some(7));
+        assert_eq!(lsm.get(&"b".to_string())?, Some(10));
+        assert_eq!(lsm.get(&"a".to_string())?, Some(2));
+        assert_eq!(lsm.get(&"t".to_string())?, Some(3847));
+
+        assert_eq!(4, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_with_metadata_reports_source() -> anyhow::Result<()> {
+        let db = TestDb::new("meta.lsm");
+        let mut lsm: Lsm<String, u32> = Lsm::open_or_create(db.manifest())?;
+
+        lsm.put("a".to_string(), 1)?;
+        lsm.flush()?;
+        lsm.put("b".to_string(), 2)?;
+
+        let meta = lsm.get_with_metadata(&"a".to_string())?.unwrap();
+        assert_eq!(meta.value, 1);
+        assert_eq!(meta.seq, 1);
+        assert_eq!(meta.source, Source::Sst("sst000meta".to_string()));
+
+        let meta = lsm.get_with_metadata(&"b".to_string())?.unwrap();
+        assert_eq!(meta.value, 2);
+        assert_eq!(meta.seq, 2);
+        assert_eq!(meta.source, Source::Memtable);
+
+        assert_eq!(lsm.get_with_metadata(&"c".to_string())?, None);
+
+        // sequence numbers keep counting up across a reopen
+        drop(lsm);
+        let mut lsm: Lsm<String, u32> = Lsm::open_or_create(db.manifest())?;
+        lsm.put("c".to_string(), 3)?;
+        assert_eq!(lsm.get_with_metadata(&"c".to_string())?.unwrap().seq, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn changes_since_reports_later_writes() -> anyhow::Result<()> {
+        let db = TestDb::new("changes.lsm");
+        let mut lsm: Lsm<String, u32> = Lsm::open_or_create(db.manifest())?;
+
+        lsm.put("a".to_string(), 1)?;
+        lsm.put("b".to_string(), 2)?;
+        lsm.flush()?;
+        let mark = lsm.last_seq();
+
+        lsm.put("c".to_string(), 3)?;
+        lsm.delete("a".to_string())?;
+        lsm.flush()?;
+        lsm.put("b".to_string(), 20)?;
+
+        let changes: Vec<_> = lsm.changes_since(mark)?.collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("a".to_string(), None),
+                ("b".to_string(), Some(20)),
+                ("c".to_string(), Some(3)),
+            ]
+        );
+        assert_eq!(lsm.get(&"a".to_string())?, None);
+        assert_eq!(lsm.changes_since(lsm.last_seq())?.count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_with_wrong_types_is_schema_mismatch() -> anyhow::Result<()> {
+        let db = TestDb::new("schema.lsm");
+        let mut lsm: Lsm<String, u32> = Lsm::open_or_create(db.manifest())?;
+        lsm.put("a".to_string(), 1)?;
+        drop(lsm);
+
+        let err = Lsm::<String, u64>::open(db.manifest()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::SchemaMismatch { .. })
+        ));
+
+        // the right types still open fine and see the data
+        let lsm: Lsm<String, u32> = Lsm::open_or_create(db.manifest())?;
+        assert_eq!(lsm.get(&"a".to_string())?, Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn retain_drops_rejected_keys() -> anyhow::Result<()> {
+        let db = TestDb::new("retain.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+
+        for i in 0..10 {
+            lsm.put(i, i)?;
+        }
+        // newer versions have to win over the ones in older SSTs
+        lsm.put(3, 30)?;
+        lsm.put(4, 41)?;
+        lsm.delete(6)?;
+
+        lsm.retain(|_, value| value % 2 == 0)?;
+        assert_eq!(lsm.tables().len(), 1);
+
+        let expected = [(0, 0), (2, 2), (3, 30), (8, 8)];
+        for i in 0..10 {
+            let want = expected.iter().find(|(k, _)| *k == i).map(|(_, v)| *v);
+            assert_eq!(lsm.get(&i)?, want);
+        }
+
+        // new flushes don't clobber the retained SST and it survives a reopen
+        lsm.put(10, 10)?;
+        lsm.flush()?;
+        drop(lsm);
+        let lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+        assert_eq!(lsm.get(&3)?, Some(30));
+        assert_eq!(lsm.get(&10)?, Some(10));
+        assert_eq!(lsm.get(&5)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn strict_open_requires_existing_database() -> anyhow::Result<()> {
+        let db = TestDb::new("strict.lsm");
+
+        let err = Lsm::<String, u32>::open(db.manifest()).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::NotFound(db.manifest().to_path_buf()))
+        );
+        assert!(!db.manifest().exists());
+
+        let mut lsm: Lsm<String, u32> = Lsm::open_or_create(db.manifest())?;
+        assert!(db.manifest().is_file());
+        lsm.put("a".to_string(), 1)?;
+        drop(lsm);
+
+        let lsm: Lsm<String, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.get(&"a".to_string())?, Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_detects_unsorted_sst() -> anyhow::Result<()> {
+        let db = TestDb::new("unsorted.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+
+        for i in 0..4 {
+            lsm.put(i, i)?;
+        }
+        lsm.flush()?;
+        lsm.verify()?;
+
+        // write_sst would refuse this, so put the file together by hand
+        let entry = |seq| Entry {
+            seq,
+            value: Some(0),
+        };
+        let sst = Sst {
+            comparator: Comparator::Ord,
+            schema: Lsm::<u32, u32>::schema_fingerprint(),
+            entries: vec![(5, entry(10)), (4, entry(11))],
+        };
+        let payload = bincode::encode_to_vec(sst, format::config())?;
+        fs::write("sst999unsorted", sst::file_bytes(&payload))?;
+        lsm.manifest.log(vec![Edit::AddSst(TableMeta {
+            name: "sst999unsorted".to_string(),
+            ..Default::default()
+        })])?;
+
+        let err = lsm.verify().err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::UnsortedSst("sst999unsorted".to_string()))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn iterators_match_reference_map() -> anyhow::Result<()> {
+        let db = TestDb::new("iter.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+        let mut reference = BTreeMap::new();
+
+        // overwrites and deletes spread over several SSTs and the memtable
+        for i in 0..40u32 {
+            let key = (i * 7) % 13;
+            if i % 5 == 0 {
+                lsm.delete(key)?;
+                reference.remove(&key);
+            } else {
+                lsm.put(key, i)?;
+                reference.insert(key, i);
+            }
+        }
+
+        assert_eq!(
+            lsm.values()?.collect::<Vec<_>>(),
+            reference.values().copied().collect::<Vec<_>>()
+        );
+
+        let entries: Vec<_> = lsm.iter()?.collect();
+        assert_eq!(entries, reference.into_iter().collect::<Vec<_>>());
+        assert_eq!(lsm.into_iter().collect::<Result<Vec<_>>>()?, entries);
+
+        Ok(())
+    }
+
+    #[test]
+    fn into_iter_yields_the_error_reading_an_sst() -> anyhow::Result<()> {
+        let db = TestDb::new("into_iter_error.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+        lsm.put(1, 1)?;
+        lsm.flush()?;
+        lsm.put(2, 2)?;
+        let name = lsm.sst_layout()?[0].name.clone();
+        let mut bytes = fs::read(&name)?;
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        fs::write(&name, bytes)?;
+
+        let items: Vec<Result<(u32, u32)>> = lsm.into_iter().collect();
+        assert_eq!(items.len(), 1);
+        assert!(matches!(
+            items[0].as_ref().unwrap_err().downcast_ref::<Error>(),
+            Some(Error::CorruptSst(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_errors_on_missing_sst() -> anyhow::Result<()> {
+        let db = TestDb::new("missing.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+
+        lsm.put(1, 1)?;
+        lsm.flush()?;
+        lsm.put(2, 2)?;
+        fs::remove_file(&lsm.tables()[0].name)?;
+
+        // the memtable is still fine, the SST lookup fails cleanly
+        assert_eq!(lsm.get(&2)?, Some(2));
+        let err = lsm.get(&1).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+            Some(std::io::ErrorKind::NotFound)
+        );
+        assert!(lsm.iter().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sst_layout_tracks_bounds_and_counts() -> anyhow::Result<()> {
+        let db = TestDb::new("layout.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+
+        for i in 0..6 {
+            lsm.put(i, i)?;
+        }
+        lsm.delete(2)?;
+        lsm.flush()?;
+
+        let layout = lsm.sst_layout()?;
+        let summary: Vec<_> = layout
+            .iter()
+            .map(|sst| (sst.min_key, sst.max_key, sst.entries, sst.tombstones))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![(0, 1, 2, 0), (2, 3, 2, 0), (4, 5, 2, 0), (2, 2, 1, 1)]
+        );
+        for sst in &layout {
+            assert_eq!(sst.level, 0);
+            assert_eq!(sst.bytes, fs::metadata(&sst.name)?.len());
+        }
+
+        // merging everything leaves one SST with the live keys
+        lsm.retain(|_, _| true)?;
+        let layout = lsm.sst_layout()?;
+        assert_eq!(layout.len(), 1);
+        assert_eq!((layout[0].min_key, layout[0].max_key), (0, 5));
+        assert_eq!((layout[0].entries, layout[0].tombstones), (5, 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_composite_keys_in_logical_order() -> anyhow::Result<()> {
+        let db = TestDb::new("composite.lsm");
+        let mut lsm: Lsm<Vec<u8>, u64> = Lsm::open_or_create(db.manifest())?;
+
+        let key = |tenant: &str, ts: u64| OrderedKey::new().str(tenant).u64(ts).finish();
+        // timestamps straddle byte boundaries, tenants are prefixes of each other
+        let mut logical = vec![];
+        for tenant in ["b", "ab", "a"] {
+            for ts in [70_000, 5, 256, 1] {
+                lsm.put(key(tenant, ts), ts)?;
+                logical.push((tenant, ts));
+            }
+        }
+        logical.sort();
+
+        let values: Vec<_> = lsm.values()?.collect();
+        assert_eq!(
+            values,
+            logical.iter().map(|(_, ts)| *ts).collect::<Vec<_>>()
+        );
+
+        // everything for tenant "a" from ts 5 on, without spilling into "ab"
+        let scanned: Vec<_> = lsm
+            .scan(key("a", 5)..=key("a", u64::MAX))?
+            .map(|(_, ts)| ts)
+            .collect();
+        assert_eq!(scanned, vec![5, 256, 70_000]);
+
+        let scanned: Vec<_> = lsm.scan(key("ab", 0)..key("ab", 256))?.collect();
+        assert_eq!(scanned, vec![(key("ab", 1), 1), (key("ab", 5), 5)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_or_else_only_calls_fallback_when_missing() -> anyhow::Result<()> {
+        let db = TestDb::new("default.lsm");
+        let mut lsm: Lsm<String, u32> = Lsm::open_or_create(db.manifest())?;
+        lsm.put("a".to_string(), 1)?;
+
+        let mut calls = 0;
+        let value = lsm.get_or_else(&"a".to_string(), || {
+            calls += 1;
+            0
+        })?;
+        assert_eq!((value, calls), (1, 0));
+
+        let value = lsm.get_or_else(&"b".to_string(), || {
+            calls += 1;
+            7
+        })?;
+        assert_eq!((value, calls), (7, 1));
+
+        assert_eq!(lsm.get_or(&"a".to_string(), 9)?, 1);
+        assert_eq!(lsm.get_or(&"b".to_string(), 9)?, 9);
+
+        Ok(())
+    }
+
+    #[test]
+    fn flush_threshold_is_restored_from_manifest() -> anyhow::Result<()> {
+        let db = TestDb::new("config.lsm");
+        let options = Options {
+            max_size: Some(3),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+
+        for i in 0..3 {
+            lsm.put(i, i)?;
+        }
+        assert!(lsm.tables().is_empty());
+        drop(lsm);
+
+        // not given on reopen, so the stored threshold holds
+        let mut lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.max_size, 3);
+        lsm.put(3, 3)?;
+        assert_eq!(lsm.tables().len(), 1);
+        drop(lsm);
+
+        // the same value again is fine, a different one is rejected
+        let max_size = |max_size| Options {
+            max_size: Some(max_size),
+            ..Default::default()
+        };
+        drop(Lsm::<u32, u32>::open_with(db.manifest(), max_size(3))?);
+        let err = Lsm::<u32, u32>::open_with(db.manifest(), max_size(5))
+            .map(|_| ())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidOptions(_))
+        ));
+        let lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.max_size, 3);
+        drop(lsm);
+
+        // the stored schema still rejects the wrong types
+        assert!(Lsm::<u32, String>::open(db.manifest()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_prefix_stops_at_successor() -> anyhow::Result<()> {
+        let db = TestDb::new("prefix.lsm");
+        let mut lsm: Lsm<Vec<u8>, u32> = Lsm::open_or_create(db.manifest())?;
+
+        let keys: [&[u8]; 8] = [
+            b"a",
+            b"ab",
+            b"abc",
+            b"ac",
+            &[b'a', 0xFF],
+            &[b'a', 0xFF, 0xFF],
+            b"b",
+            &[0xFF, 1],
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            lsm.put(key.to_vec(), i as u32)?;
+        }
+
+        let scanned = |prefix: &[u8]| -> anyhow::Result<Vec<u32>> {
+            Ok(lsm.scan_prefix(prefix)?.map(|(_, i)| i).collect())
+        };
+        assert_eq!(scanned(b"ab")?, vec![1, 2]);
+        assert_eq!(scanned(b"a")?, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(scanned(&[b'a', 0xFF])?, vec![4, 5]);
+        assert_eq!(scanned(&[0xFF])?, vec![7]);
+        assert_eq!(scanned(&[])?, (0..8).collect::<Vec<_>>());
+        assert_eq!(scanned(b"z")?, vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "wal-compression")]
+    fn compressed_log_is_smaller_and_recovers() -> anyhow::Result<()> {
+        let db = TestDb::new("compressed.lsm");
+        let options = Options {
+            max_size: Some(100),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, String> = Lsm::open_or_create_with(db.manifest(), options)?;
+
+        let value = |i: u32| format!("{i}").repeat(500);
+        let mut raw = 0;
+        for i in 0..10 {
+            raw += value(i).len();
+            lsm.put(i, value(i))?;
+        }
+        drop(lsm);
+        assert!(fs::metadata(".log")?.len() < raw as u64 / 4);
+
+        let lsm: Lsm<u32, String> = Lsm::open(db.manifest())?;
+        assert!(lsm.tables().is_empty());
+        for i in 0..10 {
+            assert_eq!(lsm.get(&i)?, Some(value(i)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_survive_a_crash_mid_flush() -> anyhow::Result<()> {
+        let db = TestDb::new("crash.lsm");
+
+        for (point, tables) in [(CrashPoint::SstWritten, 0), (CrashPoint::ManifestLogged, 1)] {
+            db.cleanup();
+            let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+            lsm.put(1, 1)?;
+            lsm.put(2, 2)?;
+
+            // the memtable is full, so this write flushes first and dies partway
+            lsm.crash_at = Some(point);
+            assert!(lsm.put(3, 3).is_err());
+            // nothing acknowledged is lost from the live handle either
+            assert_eq!(lsm.get(&1)?, Some(1));
+            drop(lsm);
+
+            let lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+            assert_eq!(lsm.tables().len(), tables, "{point:?}");
+            assert_eq!(lsm.get(&1)?, Some(1), "{point:?}");
+            assert_eq!(lsm.get(&2)?, Some(2), "{point:?}");
+            assert_eq!(lsm.get(&3)?, None, "{point:?}");
+            assert_eq!(lsm.last_seq(), 2, "{point:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact_range_only_rewrites_overlapping_tables() -> anyhow::Result<()> {
+        let db = TestDb::new("range.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+
+        for key in 0..10 {
+            lsm.put(key, key)?;
+        }
+        // many versions of a hot range, in SSTs of their own
+        for version in 0..4 {
+            lsm.put(100, version)?;
+            lsm.put(101, version)?;
+        }
+        lsm.delete(101)?;
+        lsm.put(102, 0)?;
+        for key in 200..210 {
+            lsm.put(key, key)?;
+        }
+        lsm.flush()?;
+
+        let before: Vec<String> = lsm.tables().iter().map(|t| t.name.clone()).collect();
+        let hot: Vec<usize> = lsm
+            .sst_layout()?
+            .iter()
+            .enumerate()
+            .filter(|(_, info)| info.min_key <= 101 && info.max_key >= 100)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(hot, (5..10).collect::<Vec<_>>());
+
+        lsm.compact_range(100..=101)?;
+
+        let after: Vec<String> = lsm.tables().iter().map(|t| t.name.clone()).collect();
+        assert_eq!(after.len(), before.len() - 4);
+        assert_eq!(after[..5], before[..5]);
+        assert_eq!(after[6..], before[10..]);
+        assert!(!before.contains(&after[5]));
+        for name in &before[5..10] {
+            assert!(!Path::new(name).exists());
+        }
+
+        // older tables are left underneath, so the tombstone has to stay
+        let merged = &lsm.sst_layout()?[5];
+        assert_eq!((merged.min_key, merged.max_key), (100, 102));
+        assert_eq!((merged.entries, merged.tombstones), (3, 1));
+
+        assert_eq!(lsm.get(&100)?, Some(3));
+        assert_eq!(lsm.get(&101)?, None);
+        assert_eq!(lsm.get(&102)?, Some(0));
+        assert_eq!(lsm.iter()?.count(), 22);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_sorted_honors_precedence() -> anyhow::Result<()> {
+        let db = TestDb::new("ours.lsm");
+        // the lock is already held, so the second database is cleaned up by hand
+        let theirs_path = Path::new("theirs.lsm");
+        remove_db_files("theirs.lsm");
+
+        let mut ours: Lsm<u32, String> = Lsm::open_or_create(db.manifest())?;
+        for key in [1, 3, 5, 7] {
+            ours.put(key, format!("ours{key}"))?;
+        }
+        ours.delete(5)?;
+        // both share a log, so it has to be empty before the other one writes
+        ours.flush()?;
+
+        let mut theirs: Lsm<u32, String> = Lsm::open_or_create(theirs_path)?;
+        for key in [2, 3, 5, 6, 7] {
+            theirs.put(key, format!("theirs{key}"))?;
+        }
+        theirs.flush()?;
+
+        // ours wins on conflicts, except that key 7 keeps both
+        let mut conflicts = vec![];
+        let merged: Vec<(u32, String)> = merge_sorted(ours.iter()?, theirs.iter()?, |key, a, b| {
+            conflicts.push(*key);
+            if *key == 7 {
+                format!("{a}+{b}")
+            } else {
+                a
+            }
+        })
+        .collect();
+
+        assert_eq!(
+            merged,
+            vec![
+                (1, "ours1".to_string()),
+                (2, "theirs2".to_string()),
+                (3, "ours3".to_string()),
+                (5, "theirs5".to_string()),
+                (6, "theirs6".to_string()),
+                (7, "ours7+theirs7".to_string()),
+            ]
+        );
+        assert_eq!(conflicts, vec![3, 7]);
+
+        drop(theirs);
+        remove_db_files("theirs.lsm");
+        Ok(())
+    }
+
+    #[test]
+    fn handle_can_move_to_another_thread() -> anyhow::Result<()> {
+        fn assert_thread_safe<T: Send + Sync + 'static>() {}
+        assert_thread_safe::<Lsm<String, u32>>();
+
+        let db = TestDb::new("thread.lsm");
+        let mut lsm: Lsm<String, u32> = Lsm::open_or_create(db.manifest())?;
+        lsm.put("a".to_string(), 1)?;
+
+        let lsm = std::thread::spawn(move || -> anyhow::Result<Lsm<String, u32>> {
+            for i in 0..5 {
+                lsm.put(format!("k{i}"), i)?;
+            }
+            assert_eq!(lsm.get(&"a".to_string())?, Some(1));
+            Ok(lsm)
+        })
+        .join()
+        .unwrap()?;
+
+        assert_eq!(lsm.get(&"k4".to_string())?, Some(4));
+        assert_eq!(lsm.iter()?.count(), 6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn handle_outlives_the_path_it_was_opened_with() -> anyhow::Result<()> {
+        let db = TestDb::new("owned.lsm");
+        let path = PathBuf::from("owned.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(&path)?;
+        drop(path);
+
+        // enough to flush, which names SSTs after the manifest
+        for i in 0..5 {
+            lsm.put(i, i)?;
+        }
+        assert_eq!(lsm.tables()[0].name, "sst000owned");
+        assert_eq!(lsm.get(&0)?, Some(0));
+        drop(lsm);
+
+        let lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.iter()?.count(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn l0_is_compacted_once_over_the_trigger() -> anyhow::Result<()> {
+        let db = TestDb::new("l0.lsm");
+        let options = Options {
+            l0_compaction_trigger: Some(3),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+
+        let mut reference = BTreeMap::new();
+        for i in 0..60 {
+            let key = i * 7 % 13;
+            if i % 5 == 4 {
+                lsm.delete(key)?;
+                reference.remove(&key);
+            } else {
+                lsm.put(key, i)?;
+                reference.insert(key, i);
+            }
+
+            let layout = lsm.sst_layout()?;
+            let l0 = layout.iter().filter(|sst| sst.level == 0).count();
+            assert!(l0 <= 3, "{l0} L0 SSTs after write {i}");
+            assert!(layout.iter().filter(|sst| sst.level == 1).count() <= 1);
+        }
+        assert!(lsm.sst_layout()?.iter().any(|sst| sst.level == 1));
+
+        for key in 0..13 {
+            assert_eq!(lsm.get(&key)?, reference.get(&key).copied(), "key {key}");
+        }
+        assert_eq!(lsm.iter()?.collect::<BTreeMap<_, _>>(), reference);
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_synced_writes_survive_a_crash() -> anyhow::Result<()> {
+        let db = TestDb::new("sync.lsm");
+        let options = || Options {
+            max_size: Some(100),
+            ..Default::default()
+        };
+        let lazy = WriteOptions { sync: false };
+        let synced = WriteOptions { sync: true };
+
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options())?;
+        lsm.put(1, 1)?;
+        lsm.put_opts(2, 2, lazy)?;
+        lsm.crash()?;
+
+        let mut lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options())?;
+        assert_eq!((lsm.get(&1)?, lsm.get(&2)?), (None, None));
+
+        // a synced write carries everything before it along
+        lsm.put(1, 1)?;
+        lsm.put_opts(2, 2, synced)?;
+        lsm.put_opts(3, 3, lazy)?;
+        lsm.crash()?;
+
+        let lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options())?;
+        assert_eq!(lsm.get(&1)?, Some(1));
+        assert_eq!(lsm.get(&2)?, Some(2));
+        assert_eq!(lsm.get(&3)?, None);
+        drop(lsm);
+
+        // the database-wide setting syncs plain puts
+        let options = Options {
+            sync_writes: true,
+            ..options()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options)?;
+        lsm.put(4, 4)?;
+        lsm.crash()?;
+        let lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.get(&4)?, Some(4));
+
+        Ok(())
+    }
+
+    #[test]
+    fn flush_wal_makes_writes_durable_without_an_sst() -> anyhow::Result<()> {
+        let db = TestDb::new("flush-wal.lsm");
+        let options = || Options {
+            max_size: Some(100),
+            ..Default::default()
+        };
+
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options())?;
+        for i in 0..10 {
+            lsm.put(i, i * 2)?;
+        }
+        lsm.delete(3)?;
+        lsm.flush_wal()?;
+        lsm.put(10, 20)?;
+        assert!(lsm.tables().is_empty());
+        lsm.crash()?;
+
+        let lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options())?;
+        assert!(lsm.tables().is_empty());
+        assert_eq!(lsm.get(&9)?, Some(18));
+        assert_eq!(lsm.get(&3)?, None);
+        assert_eq!(lsm.get(&10)?, None);
+        assert_eq!(lsm.iter()?.count(), 9);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reset_stats_starts_a_new_window() -> anyhow::Result<()> {
+        let db = TestDb::new("stats.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+
+        for i in 0..5 {
+            lsm.put(i, i)?;
+        }
+        lsm.delete(0)?;
+        lsm.get(&1)?;
+        let stats = lsm.stats();
+        assert_eq!((stats.puts, stats.deletes, stats.gets), (5, 1, 1));
+        assert_eq!(stats.flushes, 2);
+        assert!(stats.wal_bytes > 0);
+
+        let ended = lsm.reset_stats();
+        assert_eq!(ended.puts, 5);
+        assert_eq!(lsm.stats().puts, 0);
+
+        // the memtable is full, so this flushes
+        lsm.put(9, 9)?;
+        lsm.get(&9)?;
+        lsm.get(&10)?;
+        lsm.retain(|_, _| true)?;
+        let stats = lsm.stats();
+        assert_eq!((stats.puts, stats.deletes, stats.gets), (1, 0, 2));
+        assert_eq!((stats.flushes, stats.compactions), (1, 1));
+
+        // the totals carry on through the reset
+        let totals = lsm.total_stats();
+        assert_eq!((totals.puts, totals.deletes, totals.gets), (6, 1, 3));
+        assert_eq!((totals.flushes, totals.compactions), (3, 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn overwrites_of_a_hot_key_are_coalesced() -> anyhow::Result<()> {
+        let db = TestDb::new("hot.lsm");
+        let options = || Options {
+            max_size: Some(10),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u64> = Lsm::open_or_create_with(db.manifest(), options())?;
+
+        let mut record = 0;
+        for i in 0..1000 {
+            record = lsm.put(7, i)? as u64;
+        }
+        lsm.put(8, 0)?;
+        // the log holds a fraction of the writes, not one record each
+        let log = fs::metadata(".log")?.len();
+        assert!(
+            log < record * 2 * wal::COALESCE_MIN_RECORDS as u64,
+            "{log} bytes"
+        );
+        drop(lsm);
+
+        let mut lsm: Lsm<u32, u64> = Lsm::open_with(db.manifest(), options())?;
+        assert_eq!(lsm.get(&7)?, Some(999));
+        assert_eq!(lsm.last_seq(), 1001);
+
+        lsm.flush()?;
+        let sst = lsm.read_sst(&lsm.tables()[0].name)?;
+        assert_eq!(sst.entries.len(), 2);
+        assert_eq!((sst.entries[0].0, sst.entries[0].1.value), (7, Some(999)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn recent_reads_only_touch_the_newest_sst() -> anyhow::Result<()> {
+        let db = TestDb::new("recent.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+
+        // appends, so every SST holds its own slice of the key space
+        for i in 0..40 {
+            lsm.put(i, i)?;
+        }
+        lsm.flush()?;
+        assert_eq!(lsm.tables().len(), 20);
+
+        lsm.reset_stats();
+        assert_eq!(lsm.get(&38)?, Some(38));
+        assert_eq!(lsm.stats().sst_reads, 1);
+        // and it's kept in memory after that
+        assert_eq!(lsm.get(&39)?, Some(39));
+        assert_eq!(lsm.get(&38)?, Some(38));
+        assert_eq!(lsm.stats().sst_reads, 1);
+
+        // bounds skip straight to the one SST that can hold an old key, or none
+        lsm.reset_stats();
+        assert_eq!(lsm.get(&0)?, Some(0));
+        assert_eq!(lsm.stats().sst_reads, 1);
+        assert_eq!(lsm.get(&1000)?, None);
+        assert_eq!(lsm.stats().sst_reads, 1);
+
+        // a flush makes a new newest SST, which is read rather than served stale
+        lsm.put(38, 0)?;
+        lsm.flush()?;
+        assert_eq!(lsm.get(&38)?, Some(0));
+        assert_eq!(lsm.get(&39)?, Some(39));
+
+        Ok(())
+    }
+
+    #[test]
+    fn databases_with_their_own_file_names_share_a_directory() -> anyhow::Result<()> {
+        let db = TestDb::new("alpha.lsm");
+        remove_db_files("beta.lsm");
+        let names = |prefix: &str| FileNames {
+            prefix: prefix.to_string(),
+            wal_extension: "wal".to_string(),
+            sst_extension: "sst".to_string(),
+        };
+        let options = |prefix: &str| Options {
+            files: Some(names(prefix)),
+            ..Default::default()
+        };
+
+        // both open at once, each with a log of its own
+        let mut alpha: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options("alpha"))?;
+        let mut beta: Lsm<u32, u32> =
+            Lsm::open_or_create_with(Path::new("beta.lsm"), options("beta"))?;
+        for i in 0..5 {
+            alpha.put(i, i)?;
+            beta.put(i, i * 10)?;
+        }
+        assert_eq!(alpha.tables()[0].name, "alphasst000alpha.sst");
+        assert!(Path::new("alpha.wal").is_file() && Path::new("beta.wal").is_file());
+        assert!(!Path::new(".log").exists());
+        drop((alpha, beta));
+
+        // the names are stored, so a plain open finds the right files
+        let alpha: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        let beta: Lsm<u32, u32> = Lsm::open(Path::new("beta.lsm"))?;
+        assert_eq!(
+            alpha.iter()?.collect::<Vec<_>>(),
+            (0..5).map(|i| (i, i)).collect::<Vec<_>>()
+        );
+        assert_eq!(beta.get(&4)?, Some(40));
+        drop((alpha, beta));
+
+        // they can't be changed afterwards, or be mistaken for the manifest
+        let err = Lsm::<u32, u32>::open_with(db.manifest(), options("gamma"))
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidOptions(_))
+        ));
+        let clash = Options {
+            files: Some(FileNames {
+                prefix: "clash".to_string(),
+                wal_extension: "lsm".to_string(),
+                sst_extension: String::new(),
+            }),
+            ..Default::default()
+        };
+        let err = Lsm::<u32, u32>::open_or_create_with(Path::new("clash.lsm"), clash)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidOptions(_))
+        ));
+        assert!(!Path::new("clash.lsm").exists());
+
+        for name in ["alpha", "beta"] {
+            for file in fs::read_dir(".")?.flatten() {
+                let file_name = file.file_name().to_string_lossy().into_owned();
+                if names(name).is_sst(&file_name, name) || file_name == names(name).wal() {
+                    fs::remove_file(file.path())?;
+                }
+            }
+        }
+        remove_db_files("beta.lsm");
+        Ok(())
+    }
+
+    #[test]
+    fn stale_version_fails_put_if_unchanged() -> anyhow::Result<()> {
+        let db = TestDb::new("optimistic.lsm");
+        let mut lsm: Lsm<String, u32> = Lsm::open_or_create(db.manifest())?;
+        let key = || "balance".to_string();
+        lsm.put(key(), 100)?;
+        lsm.flush()?;
+
+        // both read the same version
+        let (first, first_seq) = lsm.get_for_update(&key())?.unwrap();
+        let (second, second_seq) = lsm.get_for_update(&key())?.unwrap();
+        assert_eq!((first, second), (100, 100));
+        assert_eq!(first_seq, second_seq);
+
+        assert!(lsm.put_if_unchanged(key(), first + 10, first_seq)?);
+        assert!(!lsm.put_if_unchanged(key(), second - 10, second_seq)?);
+        assert_eq!(lsm.get(&key())?, Some(110));
+
+        // retrying from a fresh read goes through
+        let (value, seq) = lsm.get_for_update(&key())?.unwrap();
+        assert!(lsm.put_if_unchanged(key(), value - 10, seq)?);
+        assert_eq!(lsm.get(&key())?, Some(100));
+
+        // an absent key is at version 0
+        assert_eq!(lsm.get_for_update(&"new".to_string())?, None);
+        assert!(!lsm.put_if_unchanged("new".to_string(), 1, 1)?);
+        assert!(lsm.put_if_unchanged("new".to_string(), 1, 0)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn files_carry_their_format_in_a_header() -> anyhow::Result<()> {
+        let db = TestDb::new("header.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+        for i in 0..3 {
+            lsm.put(i, i)?;
+        }
+        let sst = lsm.tables()[0].name.clone();
+        drop(lsm);
+
+        let files = [
+            (format::SST, sst.as_str()),
+            (format::WAL, ".log"),
+            (format::MANIFEST, "header.lsm"),
+        ];
+        for (kind, file) in files {
+            assert_eq!(
+                fs::read(file)?[..format::HEADER_LEN],
+                format::header(kind),
+                "{file}"
+            );
+        }
+        let lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.iter()?.count(), 3);
+        drop(lsm);
+
+        // as if written with some other configuration
+        let rewrite_config_id = |file: &str, id: u8| -> anyhow::Result<()> {
+            let mut content = fs::read(file)?;
+            content[4] = id;
+            Ok(fs::write(file, content)?)
+        };
+        let unsupported = |err: anyhow::Error, file: &str| {
+            assert_eq!(
+                err.downcast_ref::<Error>(),
+                Some(&Error::UnsupportedFormat(
+                    std::path::absolute(file).unwrap()
+                ))
+            );
+        };
+
+        rewrite_config_id(&sst, format::CONFIG_ID + 1)?;
+        let lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        unsupported(lsm.get(&0).err().unwrap(), &sst);
+        drop(lsm);
+        rewrite_config_id(&sst, format::CONFIG_ID)?;
+
+        for file in [".log", "header.lsm"] {
+            rewrite_config_id(file, format::CONFIG_ID + 1)?;
+            unsupported(Lsm::<u32, u32>::open(db.manifest()).err().unwrap(), file);
+            rewrite_config_id(file, format::CONFIG_ID)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn extend_puts_every_pair() -> anyhow::Result<()> {
+        let db = TestDb::new("extend.lsm");
+        let mut lsm: Lsm<String, u32> = Lsm::open_or_create(db.manifest())?;
+
+        lsm.extend(vec![
+            ("b".to_string(), 2),
+            ("a".to_string(), 1),
+            ("b".to_string(), 3),
+        ]);
+        assert_eq!(
+            lsm.iter()?.collect::<Vec<_>>(),
+            vec![("a".to_string(), 1), ("b".to_string(), 3)]
+        );
+        assert_eq!(lsm.last_seq(), 3);
+
+        // a failed write has nowhere to go but a panic
+        let sim = Arc::new(Memory::default());
+        let mut lsm: Lsm<String, u32> = Lsm::open_in(
+            sim.clone(),
+            Path::new("extend-full.lsm"),
+            Options::default(),
+        )?;
+        sim.set_full(true);
+        let extended = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            lsm.extend(vec![("c".to_string(), 4)]);
+        }));
+        assert!(extended.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_load_writes_sorted_input_straight_to_ssts() -> anyhow::Result<()> {
+        let db = TestDb::new("bulk.lsm");
+        let n = 2 * DEFAULT_SST_TARGET_ENTRIES as u32 + 10;
+        let lsm: Lsm<u32, u32> = Lsm::bulk_load(db.manifest(), (0..n).map(|i| (i, i * 2)))?;
+
+        let layout = lsm.sst_layout()?;
+        let bounds: Vec<_> = layout
+            .iter()
+            .map(|sst| (sst.min_key, sst.max_key, sst.entries))
+            .collect();
+        let chunk = DEFAULT_SST_TARGET_ENTRIES as u32;
+        assert_eq!(
+            bounds,
+            vec![
+                (0, chunk - 1, chunk as usize),
+                (chunk, 2 * chunk - 1, chunk as usize),
+                (2 * chunk, n - 1, 10),
+            ]
+        );
+        // nothing went through the log
+        assert_eq!(lsm.stats().wal_bytes, 0);
+        assert_eq!(lsm.get(&1234)?, Some(2468));
+        assert_eq!(lsm.last_seq(), n as u64);
+        drop(lsm);
+
+        // keys out of order are put as usual, on top of what's already loaded
+        let mut lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        lsm.put(n + 100, 0)?;
+        lsm.load([(n, 0), (n + 1, 1), (5, 5), (n + 1, 2)])?;
+        assert_eq!(lsm.tables().len(), 5);
+        assert_eq!(lsm.get(&5)?, Some(5));
+        assert_eq!(lsm.get(&(n + 1))?, Some(2));
+        assert_eq!(lsm.get(&(n + 100))?, Some(0));
+        assert_eq!(lsm.iter()?.count(), n as usize + 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn ingest_sst_from_writer() -> anyhow::Result<()> {
+        let db = TestDb::new("ingest.lsm");
+        let mut lsm: Lsm<u32, String> = Lsm::open_or_create(db.manifest())?;
+        lsm.put(1, "one".to_string())?;
+        lsm.put(1000, "thousand".to_string())?;
+        lsm.flush()?;
+        lsm.put(2000, "two thousand".to_string())?;
+
+        let external = Path::new("external.ingest");
+        // written with the wrong key type
+        let mut writer = SstWriter::<i32, String>::new(external);
+        writer.add(5000, String::new())?;
+        writer.finish()?;
+        let err = lsm.ingest_sst(external).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::SchemaMismatch { .. })
+        ));
+
+        let mut writer = SstWriter::<u32, String>::new(external);
+        for key in 10..20 {
+            writer.add(key, format!("ingested{key}"))?;
+        }
+        assert!(writer.add(15, String::new()).is_err());
+        writer.finish()?;
+
+        // 10..20 sits inside the flushed SST's bounds
+        let err = lsm.ingest_sst(external).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::Overlap(external.to_path_buf()))
+        );
+
+        let mut writer = SstWriter::<u32, String>::new(external);
+        for key in 3000..3100 {
+            writer.add(key, format!("ingested{key}"))?;
+        }
+        writer.finish()?;
+        // a damaged file is turned away
+        let mut damaged = fs::read(external)?;
+        damaged[20] ^= 1;
+        fs::write("damaged.ingest", damaged)?;
+        let err = lsm.ingest_sst(Path::new("damaged.ingest")).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::CorruptSst(_))
+        ));
+        fs::remove_file("damaged.ingest")?;
+
+        lsm.ingest_sst(external)?;
+        assert!(!external.exists());
+        assert_eq!(lsm.get(&3050)?, Some("ingested3050".to_string()));
+        assert_eq!(lsm.get(&1)?, Some("one".to_string()));
+        assert_eq!(lsm.sst_layout()?[0].level, 1);
+        drop(lsm);
+
+        let lsm: Lsm<u32, String> = Lsm::open(db.manifest())?;
+        let keys: Vec<u32> = lsm.iter()?.map(|(key, _)| key).collect();
+        let expected: Vec<u32> = [1, 1000, 2000].into_iter().chain(3000..3100).collect();
+        assert_eq!(keys, expected);
+        lsm.verify()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_from_both_ends() -> anyhow::Result<()> {
+        let db = TestDb::new("both-ends.lsm");
+        let options = Options {
+            max_size: Some(16),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+
+        // keys spread over several SSTs and the memtable, with overwrites and deletes
+        for key in 0..60 {
+            lsm.put(key, key)?;
+        }
+        for key in (0..60).step_by(3) {
+            lsm.put(key, key + 100)?;
+        }
+        for key in (0..60).step_by(7) {
+            lsm.delete(key)?;
+        }
+        let mut expected = BTreeMap::new();
+        for key in 5..50 {
+            if key % 7 != 0 {
+                expected.insert(key, if key % 3 == 0 { key + 100 } else { key });
+            }
+        }
+
+        let mut scan = lsm.scan(5..50)?;
+        let (mut front, mut back) = (vec![], vec![]);
+        while let Some(pair) = scan.next() {
+            front.push(pair);
+            match scan.next_back() {
+                Some(pair) => back.push(pair),
+                None => break,
+            }
+        }
+        assert_eq!(scan.next(), None);
+        assert_eq!(scan.next_back(), None);
+        back.reverse();
+        front.extend(back);
+        assert_eq!(front, expected.into_iter().collect::<Vec<_>>());
+
+        assert_eq!(
+            lsm.iter()?
+                .rev()
+                .map(|(key, _)| key)
+                .take(3)
+                .collect::<Vec<_>>(),
+            vec![59, 58, 57]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_load_batches_manifest_syncs() -> anyhow::Result<()> {
+        let db = TestDb::new("batched.lsm");
+        let options = || Options {
+            l0_compaction_trigger: Some(4),
+            batch_manifest_syncs: true,
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options())?;
+        let syncs = lsm.manifest.syncs();
+
+        // ten SSTs written, then compacted into ten more in L1
+        let count = 10 * DEFAULT_SST_TARGET_ENTRIES as u32;
+        lsm.load((0..count).map(|i| (i, i)))?;
+        assert_eq!(lsm.manifest.syncs() - syncs, 1);
+        assert_eq!(lsm.tables().len(), 10);
+        assert!(lsm.tables().iter().all(|table| table.level == 1));
+        assert_eq!(lsm.get(&(count - 1))?, Some(count - 1));
+
+        // the inputs were deleted once the batch was durable
+        let stem = Lsm::<u32, u32>::stem(db.manifest());
+        let files = lsm.manifest.state().files.clone();
+        let ssts = fs::read_dir(".")?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| files.is_sst(name, &stem))
+            .count();
+        assert_eq!(ssts, 10);
+        drop(lsm);
+
+        let lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options())?;
+        assert_eq!(lsm.iter()?.count(), count as usize);
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_sst_is_quarantined() -> anyhow::Result<()> {
+        let db = TestDb::new("quarantine.lsm");
+        let options = Options {
+            max_size: Some(10),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options.clone())?;
+        for key in 0..30 {
+            lsm.put(key, key)?;
+        }
+        let layout = lsm.sst_layout()?;
+        let damaged = layout[1].clone();
+        assert_eq!((damaged.min_key, damaged.max_key), (10, 19));
+        drop(lsm);
+
+        let mut bytes = fs::read(&damaged.name)?;
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        fs::write(&damaged.name, bytes)?;
+
+        // without the option, the damage only shows when the SST is read
+        let lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options.clone())?;
+        assert!(lsm.quarantined().is_empty());
+        assert!(lsm.get(&15).is_err());
+        drop(lsm);
+
+        let options = Options {
+            quarantine_corrupt_ssts: true,
+            ..options
+        };
+        let lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options)?;
+        assert_eq!(lsm.quarantined(), std::slice::from_ref(&damaged));
+        assert_eq!(lsm.get(&15)?, None);
+        assert_eq!(lsm.get(&5)?, Some(5));
+        assert_eq!(lsm.get(&25)?, Some(25));
+        assert_eq!(lsm.iter()?.count(), 20);
+        drop(lsm);
+
+        // it stays out of the database after reopening normally
+        let lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert!(lsm.quarantined().is_empty());
+        assert_eq!(lsm.sst_layout()?.len(), layout.len() - 1);
+        let moved = format!("{}.quarantined", damaged.name);
+        assert!(Path::new(&moved).exists());
+        fs::remove_file(moved)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn iter_from_starts_inside_an_sst() -> anyhow::Result<()> {
+        let db = TestDb::new("iter-from.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+        for key in (0..20).step_by(2) {
+            lsm.put(key, key)?;
+        }
+        lsm.flush()?;
+        lsm.put(15, 15)?;
+        lsm.put(30, 30)?;
+
+        // 12 is only in the SST
+        assert!(!lsm.memtable.contains_key(&12));
+        assert_eq!(
+            lsm.iter_from(&12)?.map(|(key, _)| key).collect::<Vec<_>>(),
+            vec![12, 14, 15, 16, 18, 30]
+        );
+        // a start between keys begins at the next one
+        assert_eq!(lsm.iter_from(&13)?.next(), Some((14, 14)));
+        assert_eq!(lsm.iter_from(&31)?.next(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch_rolls_back_to_savepoint() -> anyhow::Result<()> {
+        let db = TestDb::new("write-batch.lsm");
+        let mut lsm: Lsm<u32, String> = Lsm::open_or_create(db.manifest())?;
+        lsm.put(1, "one".to_string())?;
+        lsm.put(2, "two".to_string())?;
+
+        let mut batch = WriteBatch::new();
+        batch.put(3, "three".to_string());
+        batch.delete(1);
+        let savepoint = batch.savepoint();
+        batch.put(4, "four".to_string());
+        batch.delete(2);
+        batch.put(3, "undone".to_string());
+        batch.rollback_to(savepoint);
+        batch.put(5, "five".to_string());
+        assert_eq!(batch.len(), 3);
+        // nothing's written before the commit
+        assert_eq!(lsm.get(&3)?, None);
+
+        lsm.commit(batch)?;
+        let expected = vec![
+            (2, "two".to_string()),
+            (3, "three".to_string()),
+            (5, "five".to_string()),
+        ];
+        assert_eq!(lsm.iter()?.collect::<Vec<_>>(), expected);
+        drop(lsm);
+        let lsm: Lsm<u32, String> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.iter()?.collect::<Vec<_>>(), expected);
+
+        // a batch cut short by a torn write is lost whole
+        let mut lsm = lsm;
+        let mut batch = WriteBatch::new();
+        batch.put(6, "six".to_string());
+        batch.put(7, "seven".to_string());
+        lsm.commit(batch)?;
+        drop(lsm);
+        let log = OpenOptions::new().write(true).open(".log")?;
+        log.set_len(log.metadata()?.len() - 1)?;
+        let lsm: Lsm<u32, String> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.iter()?.collect::<Vec<_>>(), expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_hook_keeps_reverse_index() -> anyhow::Result<()> {
+        let db = TestDb::new("write-hook.lsm");
+        let mut lsm: Lsm<u32, String> = Lsm::open_or_create(db.manifest())?;
+        let index: Arc<Mutex<BTreeMap<String, u32>>> = Arc::default();
+
+        let reverse = index.clone();
+        lsm.on_write(move |key, old, new| {
+            if new.is_some_and(|new| new.is_empty()) {
+                anyhow::bail!("empty values can't be indexed");
+            }
+            let mut reverse = reverse.lock().unwrap();
+            if let Some(old) = old {
+                reverse.remove(old);
+            }
+            if let Some(new) = new {
+                reverse.insert(new.clone(), *key);
+            }
+            Ok(())
+        });
+
+        for key in 0..10 {
+            lsm.put(key, format!("v{key}"))?;
+        }
+        lsm.put(3, "three".to_string())?;
+        lsm.delete(4)?;
+        let mut batch = WriteBatch::new();
+        batch.put(5, "five".to_string());
+        batch.put(5, "FIVE".to_string());
+        batch.delete(6);
+        lsm.commit(batch)?;
+
+        // a failing hook stops the write
+        assert!(lsm.put(7, String::new()).is_err());
+        assert_eq!(lsm.get(&7)?, Some("v7".to_string()));
+
+        let expected: BTreeMap<String, u32> =
+            lsm.iter()?.map(|(key, value)| (value, key)).collect();
+        assert_eq!(*index.lock().unwrap(), expected);
+        assert_eq!(expected.len(), 8);
+
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_bloom_skips_ssts() -> anyhow::Result<()> {
+        let db = TestDb::new("prefix-bloom.lsm");
+        let options = || Options {
+            max_size: Some(100),
+            prefix_bloom_len: Some(4),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<Vec<u8>, u32> = Lsm::open_or_create_with(db.manifest(), options())?;
+
+        // each SST's keys span the others' prefixes, so their bounds don't help
+        let tenants = ["aaaa", "bbbb", "cccc", "dddd"];
+        for (sst, tenant) in tenants.iter().enumerate() {
+            for i in 0..10u32 {
+                lsm.put(format!("{tenant}{i}").into_bytes(), i)?;
+            }
+            lsm.put(tenants[0].as_bytes().to_vec(), 0)?;
+            lsm.put(format!("eeee{sst}").into_bytes(), 0)?;
+            lsm.flush()?;
+        }
+        drop(lsm);
+
+        let lsm: Lsm<Vec<u8>, u32> = Lsm::open_with(db.manifest(), options())?;
+        assert_eq!(lsm.sst_layout()?.len(), tenants.len());
+        let scan = |prefix: &[u8]| -> anyhow::Result<(usize, u64)> {
+            lsm.reset_stats();
+            let count = lsm.scan_prefix(prefix)?.count();
+            Ok((count, lsm.stats().sst_reads))
+        };
+        assert_eq!(scan(b"cccc")?, (10, 1));
+        assert_eq!(scan(b"cccc5")?, (1, 1));
+        assert_eq!(scan(b"zzzz")?, (0, 0));
+        // too short for the filter, so every SST is read
+        assert_eq!(scan(b"cc")?, (10, 4));
+        drop(lsm);
+
+        // other keys have no byte prefixes to filter on
+        let err = Lsm::<String, u32>::open_with(db.manifest(), options())
+            .map(|_| ())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidOptions(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_bloom_uses_the_configured_hash() -> anyhow::Result<()> {
+        let db = TestDb::new("prefix-bloom-hash.lsm");
+        let options = |hash| Options {
+            max_size: Some(100),
+            prefix_bloom_len: Some(4),
+            prefix_bloom_hash: hash,
+            ..Default::default()
+        };
+        let key = |tenant: &str, i: u32| format!("{tenant}{i}").into_bytes();
+
+        let mut lsm: Lsm<Vec<u8>, u32> =
+            Lsm::open_or_create_with(db.manifest(), options(BloomHash::Fnv1a))?;
+        lsm.put(key("aaaa", 0), 0)?;
+        lsm.flush()?;
+        drop(lsm);
+        // SSTs already written keep the hash they were built with
+        let mut lsm: Lsm<Vec<u8>, u32> = Lsm::open_with(db.manifest(), options(BloomHash::Crc32))?;
+        lsm.put(key("bbbb", 0), 0)?;
+        lsm.flush()?;
+
+        let hashes: Vec<Option<BloomHash>> = lsm
+            .tables()
+            .iter()
+            .map(|table| table.prefix_bloom.as_ref().map(|bloom| bloom.hash))
+            .collect();
+        assert_eq!(hashes, vec![Some(BloomHash::Fnv1a), Some(BloomHash::Crc32)]);
+        for tenant in ["aaaa", "bbbb"] {
+            lsm.reset_stats();
+            assert_eq!(lsm.scan_prefix(tenant.as_bytes())?.count(), 1);
+            assert_eq!(lsm.stats().sst_reads, 1);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn recovery_goes_by_sequence() -> anyhow::Result<()> {
+        let db = TestDb::new("recovery-order.lsm");
+        drop(Lsm::<u32, u32>::open_or_create(db.manifest())?);
+
+        // records out of sequence order, as if the log had been pieced together
+        let records: [LogEntry<u32, u32>; 7] = [
+            LogEntry::new(4, 1, Some(40)),
+            LogEntry::new(2, 1, Some(20)),
+            LogEntry::new(5, 2, None),
+            LogEntry::new(3, 2, Some(30)),
+            LogEntry::new(1, 3, None),
+            LogEntry::new(6, 3, Some(60)),
+            LogEntry::new(7, 4, Some(70)),
+        ];
+        let mut log = format::header(format::WAL).to_vec();
+        for record in &records {
+            log.extend(record.encode()?);
+        }
+        fs::write(".log", log)?;
+
+        let lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(
+            lsm.iter()?.collect::<Vec<_>>(),
+            vec![(1, 40), (3, 60), (4, 70)]
+        );
+        assert_eq!(lsm.get_for_update(&1)?, Some((40, 4)));
+        assert_eq!(lsm.last_seq(), 7);
+
+        Ok(())
+    }
+
+    #[test]
+    fn live_size_estimate_leaves_out_dead_versions() -> anyhow::Result<()> {
+        let db = TestDb::new("live-size.lsm");
+        let options = Options {
+            max_size: Some(25),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, String> = Lsm::open_or_create_with(db.manifest(), options)?;
+
+        // every key rewritten ten times, then half of them deleted
+        for round in 0..10 {
+            for key in 0..100 {
+                lsm.put(key, format!("value {key} in round {round}"))?;
+            }
+        }
+        for key in 50..100 {
+            lsm.delete(key)?;
+        }
+        lsm.flush()?;
+
+        let on_disk: u64 = lsm.sst_layout()?.iter().map(|info| info.bytes).sum();
+        let estimate = lsm.estimate_live_data_size()?;
+        assert!(estimate > 0);
+        assert!((estimate as u64) * 10 < on_disk, "{estimate} of {on_disk}");
+
+        // close to what compacting everything really leaves
+        lsm.retain(|_, _| true)?;
+        let compacted = lsm.sst_layout()?[0].bytes as f64;
+        let ratio = estimate as f64 / compacted;
+        assert!(
+            (0.5..2.0).contains(&ratio),
+            "{estimate} against {compacted}"
+        );
+        assert_eq!(lsm.estimate_live_data_size()?, compacted as usize);
+
+        Ok(())
+    }
+
+    #[test]
+    fn acknowledged_writes_survive_injected_crashes() -> anyhow::Result<()> {
+        // the working directory only stays put while the lock is held
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = Path::new("sim.lsm");
+        let options = || Options {
+            max_size: Some(6),
+            l0_compaction_trigger: Some(2),
+            sync_writes: true,
+            ..Default::default()
+        };
+        // enough to flush, compact and coalesce the log along the way
+        let ops: Vec<(u32, Option<u32>)> = (0..80)
+            .map(|i| {
+                let key = i * 7 % 5 + i / 40 * 5;
+                (key, if i % 9 == 8 { None } else { Some(i) })
+            })
+            .collect();
+        let run = |sim: Arc<Memory>, acked: &mut BTreeMap<u32, Option<u32>>| {
+            let Ok(mut lsm) = Lsm::<u32, u32>::open_in(sim, path, options()) else {
+                return None;
+            };
+            for &(key, value) in &ops {
+                let written = match value {
+                    Some(value) => lsm.put(key, value),
+                    None => lsm.delete(key),
+                };
+                if written.is_err() {
+                    return Some((key, value));
+                }
+                acked.insert(key, value);
+            }
+            None
+        };
+
+        let clean = Arc::new(Memory::default());
+        run(clean.clone(), &mut BTreeMap::new());
+        let total = clean.ops();
+
+        // stop the workload dead at every step it takes
+        for fail_after in 0..total {
+            let sim = Arc::new(Memory::failing_after(fail_after));
+            let mut acked = BTreeMap::new();
+            let in_flight = run(sim.clone(), &mut acked);
+
+            // a power cut, or just the process dying with a write half done
+            for after in [sim.crash(), sim.restart()] {
+                let after = Arc::new(after);
+                let mut lsm = Lsm::<u32, u32>::open_in(after.clone(), path, options())?;
+                let recovered: BTreeMap<u32, u32> = lsm.iter()?.collect();
+                for key in 0..10 {
+                    let found = recovered.get(&key).copied();
+                    // the write that failed may or may not have made it
+                    let expected = acked.get(&key).copied().flatten();
+                    assert!(
+                        found == expected || in_flight == Some((key, found)),
+                        "failing after {fail_after} ops: key {key} is {found:?}, not {expected:?}"
+                    );
+                }
+
+                // and what's written once it's recovered is kept too
+                lsm.put(100, fail_after as u32)?;
+                drop(lsm);
+                let lsm = Lsm::<u32, u32>::open_in(after, path, options())?;
+                assert_eq!(
+                    lsm.get(&100)?,
+                    Some(fail_after as u32),
+                    "failing after {fail_after} ops"
+                );
+                let reopened: BTreeMap<u32, u32> =
+                    lsm.iter()?.filter(|&(key, _)| key != 100).collect();
+                assert_eq!(reopened, recovered, "failing after {fail_after} ops");
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn crash_keeps_only_names_whose_directory_was_synced() -> anyhow::Result<()> {
+        let dir = Path::new("/sim");
+        let file = |name: &str| dir.join(name);
+
+        let sim = Memory::default();
+        sim.append(&file("synced"), b"a")?;
+        sim.sync(&file("synced"))?;
+        sim.write(&file("written"), b"b")?;
+        // nothing syncs the directory after this one is made
+        sim.append(&file("appended"), b"c")?;
+        let crashed = sim.crash();
+        let mut names = crashed.list(dir)?;
+        names.sort();
+        assert_eq!(names, vec!["synced", "written"]);
+
+        // the rename goes through, but the crash comes before its directory is synced
+        // each write of a new file is two steps, and so is the rename
+        let sim = Memory::failing_after(5);
+        sim.write(&file("snapshot.tmp"), b"new")?;
+        sim.write(&file("snapshot"), b"old")?;
+        assert!(sim
+            .rename(&file("snapshot.tmp"), &file("snapshot"))
+            .is_err());
+        assert_eq!(sim.read(&file("snapshot"))?, b"new");
+        let crashed = sim.crash();
+        assert_eq!(crashed.read(&file("snapshot"))?, b"old");
+        assert!(crashed.exists(&file("snapshot.tmp")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn counters_add_up_across_flushes() -> anyhow::Result<()> {
+        let db = TestDb::new("counters.lsm");
+        let options = Options {
+            max_size: Some(4),
+            l0_compaction_trigger: Some(2),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<String, i64> = Lsm::open_or_create_with(db.manifest(), options.clone())?;
+
+        let counters = ["a", "b", "c", "d", "e"].map(String::from);
+        for round in 0..20 {
+            for (i, counter) in counters.iter().enumerate() {
+                lsm.increment(counter.clone(), i as i64 - round % 3)?;
+            }
+        }
+        assert!(lsm.stats().compactions > 0);
+        // a deleted counter starts over
+        lsm.delete("e".to_string())?;
+        assert_eq!(lsm.increment("e".to_string(), 5)?, 5);
+
+        // each round adds i and takes away 0, 1 or 2 in turn
+        let taken = (0..20).map(|round| round % 3).sum::<i64>();
+        for (i, counter) in counters.iter().take(4).enumerate() {
+            assert_eq!(lsm.get(counter)?, Some(20 * i as i64 - taken));
+        }
+
+        lsm.put("max".to_string(), i64::MAX - 1)?;
+        assert_eq!(lsm.increment("max".to_string(), 10)?, i64::MAX);
+        drop(lsm);
+
+        let options = Options {
+            counter_overflow: CounterOverflow::Wrap,
+            ..options
+        };
+        let mut lsm: Lsm<String, i64> = Lsm::open_with(db.manifest(), options)?;
+        assert_eq!(lsm.increment("max".to_string(), 1)?, i64::MIN);
+
+        Ok(())
+    }
+
+    #[test]
+    fn value_size_matches_encoding() -> anyhow::Result<()> {
+        let db = TestDb::new("value-size.lsm");
+        let mut lsm: Lsm<u32, String> = Lsm::open_or_create(db.manifest())?;
+        let long = "x".repeat(300);
+        lsm.put(1, long.clone())?;
+        lsm.put(2, String::new())?;
+        lsm.flush()?;
+        lsm.put(3, "in the memtable".to_string())?;
+        lsm.put(2, "deleted".to_string())?;
+        lsm.delete(2)?;
+
+        let encoded = |value: &str| {
+            bincode::encode_to_vec(value, format::config())
+                .unwrap()
+                .len()
+        };
+        // 300 takes a multi-byte length prefix
+        assert_eq!(lsm.value_size(&1)?, Some(encoded(&long)));
+        assert_eq!(lsm.value_size(&1)?, Some(303));
+        assert_eq!(lsm.value_size(&3)?, Some(encoded("in the memtable")));
+        assert_eq!(lsm.value_size(&2)?, None);
+        assert_eq!(lsm.value_size(&4)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn ssts_live_beside_the_manifest() -> anyhow::Result<()> {
+        // a directory name that isn't UTF-8, where the platform allows one
+        #[cfg(unix)]
+        let dir = {
+            use std::os::unix::ffi::OsStrExt;
+            PathBuf::from(std::ffi::OsStr::from_bytes(b"nested-\xff"))
+        };
+        #[cfg(not(unix))]
+        let dir = PathBuf::from("nested");
+        let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir)?;
+        let manifest = dir.join("nested.lsm");
+
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(
+            &manifest,
+            Options {
+                max_size: Some(2),
+                ..Default::default()
+            },
+        )?;
+        for key in 0..5 {
+            lsm.put(key, key * 10)?;
+        }
+        let layout = lsm.sst_layout()?;
+        assert_eq!(layout.len(), 2);
+        for info in &layout {
+            assert!(dir.join(&info.name).is_file());
+            assert!(!Path::new(&info.name).exists());
+        }
+        assert!(dir.join(".log").is_file());
+        drop(lsm);
+
+        let lsm: Lsm<u32, u32> = Lsm::open(&manifest)?;
+        assert_eq!(lsm.get(&3)?, Some(30));
+        assert_eq!(lsm.get(&4)?, Some(40));
+
+        // an SST gone from under the manifest is reported as such
+        fs::remove_file(dir.join(&layout[0].name))?;
+        let err = lsm.get(&1).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::MissingSst(std::path::absolute(
+                dir.join(&layout[0].name)
+            )?))
+        );
+
+        drop(lsm);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn count_range_matches_a_scan() -> anyhow::Result<()> {
+        let db = TestDb::new("count-range.lsm");
+        let mut lsm: Lsm<u32, String> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(8),
+                ..Default::default()
+            },
+        )?;
+        let mut reference = BTreeMap::new();
+        for i in 0..40u32 {
+            let key = i * 7 % 23;
+            if i % 5 == 3 {
+                lsm.delete(key)?;
+                reference.remove(&key);
+            } else {
+                lsm.put(key, format!("v{i}"))?;
+                reference.insert(key, ());
+            }
+        }
+        assert!(lsm.tables().len() > 1 && !lsm.memtable.is_empty());
+
+        let ranges = [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(5), Bound::Excluded(30)),
+            (Bound::Excluded(7), Bound::Included(22)),
+            (Bound::Included(30), Bound::Unbounded),
+            (Bound::Included(20), Bound::Included(20)),
+        ];
+        for range in ranges {
+            let expected = reference.range(range).count();
+            assert_eq!(lsm.count_range(range)?, expected, "{range:?}");
+            assert_eq!(lsm.scan(range)?.count(), expected, "{range:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_tombstones_reports_deleted_keys() -> anyhow::Result<()> {
+        let db = TestDb::new("tombstones.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(4),
+                ..Default::default()
+            },
+        )?;
+        for i in 0..10 {
+            lsm.put(i, i)?;
+        }
+        lsm.delete(2)?;
+        lsm.delete(7)?;
+        lsm.delete(5)?;
+        // never there to begin with, but the tombstone is all the same
+        lsm.delete(12)?;
+        lsm.flush()?;
+        lsm.delete(8)?;
+
+        let tombstones = |lsm: &Lsm<u32, u32>, range| -> anyhow::Result<Vec<u32>> {
+            Ok(lsm.scan_tombstones(range)?.collect())
+        };
+        assert_eq!(tombstones(&lsm, 0..100)?, vec![2, 5, 7, 8, 12]);
+        assert_eq!(tombstones(&lsm, 3..8)?, vec![5, 7]);
+
+        // a newer put shadows the tombstone in turn
+        lsm.put(5, 50)?;
+        assert_eq!(tombstones(&lsm, 0..100)?, vec![2, 7, 8, 12]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn recover_picks_up_appended_records() -> anyhow::Result<()> {
+        use std::io::Write;
+
+        let db = TestDb::new("recover.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(100),
+                ..Default::default()
+            },
+        )?;
+        lsm.put(1, 10)?;
+        lsm.put(2, 20)?;
+        lsm.put(3, 30)?;
+        assert_eq!(lsm.recover()?.applied, 0);
+
+        // another writer carries on the numbering; one stale record slips in
+        let seq = lsm.seq;
+        let records: [LogEntry<u32, u32>; 3] = [
+            LogEntry::new(seq + 1, 4, Some(40)),
+            LogEntry::new(seq + 2, 2, None),
+            LogEntry::new(seq - 2, 3, Some(0)),
+        ];
+        let mut appended = vec![];
+        for record in &records {
+            appended.extend(record.encode()?);
+        }
+        let torn = LogEntry::new(seq + 3, 5u32, Some(50u32)).encode()?;
+        appended.extend(&torn[..torn.len() / 2]);
+        OpenOptions::new()
+            .append(true)
+            .open(&lsm.wal_path)?
+            .write_all(&appended)?;
+
+        let report = lsm.recover()?;
+        assert_eq!(
+            report,
+            RecoveryReport {
+                records: 6,
+                applied: 2,
+                skipped: vec![],
+                trailing_bytes: torn.len() / 2,
+            }
+        );
+        assert_eq!(lsm.get(&4)?, Some(40));
+        assert_eq!(lsm.get(&2)?, None);
+        assert_eq!(lsm.get(&3)?, Some(30));
+        assert_eq!(lsm.get(&5)?, None);
+
+        // the handle's own writes number on from the recovered ones
+        lsm.put(6, 60)?;
+        assert_eq!(
+            lsm.get_with_metadata(&6)?.map(|meta| meta.seq),
+            Some(seq + 3)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_entries_are_rejected() -> anyhow::Result<()> {
+        let db = TestDb::new("entry-size.lsm");
+        let mut lsm: Lsm<String, String> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_key_size: Some(10),
+                max_value_size: Some(100),
+                ..Default::default()
+            },
+        )?;
+        // strings this short encode with a one byte length
+        let (key, long_key) = ("k".repeat(9), "k".repeat(10));
+        let (value, long_value) = ("v".repeat(99), "v".repeat(100));
+
+        lsm.put(key.clone(), value.clone())?;
+        assert_eq!(lsm.get(&key)?, Some(value.clone()));
+
+        let err = lsm.put(key.clone(), long_value.clone()).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::ValueTooLarge {
+                size: 101,
+                limit: 100
+            })
+        );
+        let err = lsm.delete(long_key.clone()).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::KeyTooLarge {
+                size: 11,
+                limit: 10
+            })
+        );
+
+        // one oversized operation turns away the whole batch
+        let mut batch = WriteBatch::new();
+        batch.put("other".to_string(), value.clone());
+        batch.put(key.clone(), long_value);
+        assert!(lsm.commit(batch).is_err());
+        assert_eq!(lsm.get(&"other".to_string())?, None);
+        assert_eq!(lsm.get(&key)?, Some(value));
+
+        Ok(())
+    }
+
+    #[test]
+    fn sst_metadata_comes_from_the_manifest() -> anyhow::Result<()> {
+        let db = TestDb::new("sst-metadata.lsm");
+        let options = || Options {
+            max_size: Some(4),
+            prefix_bloom_len: Some(1),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<Vec<u8>, u32> = Lsm::open_or_create_with(db.manifest(), options())?;
+        for i in 0..20u32 {
+            lsm.put(vec![b'a' + (i % 4) as u8, i as u8], i)?;
+        }
+        lsm.flush()?;
+        drop(lsm);
+
+        // bounds, counts, sizes and blooms are all known before any SST is opened
+        let lsm: Lsm<Vec<u8>, u32> = Lsm::open_with(db.manifest(), options())?;
+        let layout = lsm.sst_layout()?;
+        assert!(layout.len() > 1);
+        assert!(layout.iter().all(|info| info.bytes > 0));
+        lsm.estimate_live_data_size()?;
+        assert_eq!(lsm.get(&vec![b'z'])?, None);
+        assert_eq!(lsm.scan_prefix(b"q")?.count(), 0);
+        assert_eq!(lsm.stats().sst_reads, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_returns_the_replaced_value() -> anyhow::Result<()> {
+        let db = TestDb::new("insert.lsm");
+        let mut lsm: Lsm<u32, String> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(4),
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!(lsm.insert(1, "one".to_string())?, None);
+        assert_eq!(lsm.insert(1, "uno".to_string())?, Some("one".to_string()));
+
+        lsm.insert(2, "two".to_string())?;
+        lsm.delete(3)?;
+        lsm.flush()?;
+        assert!(lsm.memtable.is_empty());
+        assert_eq!(lsm.insert(2, "dos".to_string())?, Some("two".to_string()));
+        // a tombstone in an SST counts as no value
+        assert_eq!(lsm.insert(3, "tres".to_string())?, None);
+        assert_eq!(lsm.get(&2)?, Some("dos".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_splits_output_to_target_size() -> anyhow::Result<()> {
+        let db = TestDb::new("split.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(16),
+                sst_target_entries: Some(40),
+                ..Default::default()
+            },
+        )?;
+        let mut reference = BTreeMap::new();
+        for i in 0..600u32 {
+            let key = i * 13 % 250;
+            lsm.put(key, i)?;
+            reference.insert(key, i);
+        }
+        lsm.compact_range(..)?;
+
+        // 250 keys evened out over seven SSTs
+        let layout = lsm.sst_layout()?;
+        assert_eq!(layout.len(), 7);
+        assert!(layout.iter().all(|info| info.entries <= 40));
+        assert_eq!(layout.iter().map(|info| info.entries).sum::<usize>(), 250);
+        for pair in layout.windows(2) {
+            assert!(pair[0].max_key < pair[1].min_key);
+        }
+        assert_eq!(lsm.iter()?.collect::<BTreeMap<_, _>>(), reference);
+        drop(lsm);
+
+        // and the manifest replays the split
+        let lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.sst_layout()?, layout);
+        assert_eq!(lsm.get(&117)?, reference.get(&117).copied());
+
+        Ok(())
+    }
+
+    #[test]
+    fn watch_prefix_sees_matching_writes_in_order() -> anyhow::Result<()> {
+        let db = TestDb::new("watch.lsm");
+        let mut lsm: Lsm<Vec<u8>, u32> = Lsm::open_or_create(db.manifest())?;
+        let users = lsm.watch_prefix(b"user/");
+        let everything = lsm.watch(..);
+
+        lsm.put(b"user/1".to_vec(), 1)?;
+        lsm.put(b"order/1".to_vec(), 10)?;
+        lsm.put(b"user/2".to_vec(), 2)?;
+        lsm.delete(b"user/1".to_vec())?;
+        let mut batch = WriteBatch::new();
+        batch.put(b"user/3".to_vec(), 3);
+        batch.put(b"users".to_vec(), 4);
+        batch.put(b"user/2".to_vec(), 5);
+        lsm.commit(batch)?;
+
+        let write = |key: &[u8], value| Change::Write(key.to_vec(), value);
+        assert_eq!(
+            users.try_iter().collect::<Vec<_>>(),
+            vec![
+                write(b"user/1", Some(1)),
+                write(b"user/2", Some(2)),
+                write(b"user/1", None),
+                write(b"user/3", Some(3)),
+                write(b"user/2", Some(5)),
+            ]
+        );
+
+        // a receiver that falls behind hears how much it missed, where it missed it
+        let _ = everything.try_iter().count();
+        for i in 0..WATCH_CAPACITY as u32 + 5 {
+            lsm.put(b"user/4".to_vec(), i)?;
+        }
+        assert_eq!(everything.try_iter().count(), WATCH_CAPACITY);
+        lsm.put(b"user/5".to_vec(), 0)?;
+        assert_eq!(
+            everything.try_iter().collect::<Vec<_>>(),
+            vec![Change::Lagged(5), write(b"user/5", Some(0))]
+        );
+        assert_eq!(users.try_iter().count(), WATCH_CAPACITY);
+
+        // a dropped receiver doesn't hold up writes
+        drop(users);
+        lsm.put(b"user/6".to_vec(), 6)?;
+        assert_eq!(lsm.get(&b"user/6".to_vec())?, Some(6));
+
+        Ok(())
+    }
+
+    #[test]
+    fn retries_ride_out_transient_failures() -> anyhow::Result<()> {
+        // the working directory only stays put while the lock is held
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        use std::time::Duration;
+
+        let path = Path::new("retry.lsm");
+        let options = |attempts| Options {
+            max_size: Some(100),
+            sync_writes: true,
+            io_retry: RetryPolicy {
+                attempts,
+                backoff: Duration::ZERO,
+            },
+            ..Default::default()
+        };
+
+        // by default the first failure is the caller's
+        let sim = Arc::new(Memory::default());
+        let mut lsm = Lsm::<u32, u32>::open_in(sim.clone(), path, options(1))?;
+        sim.fail_next(1);
+        assert!(lsm.put(1, 1).is_err());
+
+        let sim = Arc::new(Memory::default());
+        let mut lsm = Lsm::<u32, u32>::open_in(sim.clone(), path, options(3))?;
+        lsm.put(1, 10)?;
+        // a torn append, then a failed fsync, each tried again
+        sim.fail_next(1);
+        lsm.put(2, 20)?;
+        sim.fail_next(1);
+        lsm.flush_wal()?;
+        lsm.put(3, 30)?;
+        // the SST write of a flush, twice over
+        sim.fail_next(2);
+        lsm.flush()?;
+        lsm.put(4, 40)?;
+        drop(lsm);
+
+        let lsm = Lsm::<u32, u32>::open_in(Arc::new(sim.crash()), path, options(1))?;
+        assert_eq!(lsm.tables().len(), 1);
+        assert_eq!(
+            lsm.iter()?.collect::<Vec<_>>(),
+            vec![(1, 10), (2, 20), (3, 30), (4, 40)]
+        );
+
+        // past the attempts allowed, the error comes through
+        let sim = Arc::new(Memory::default());
+        let mut lsm = Lsm::<u32, u32>::open_in(sim.clone(), path, options(3))?;
+        sim.fail_next(3);
+        assert!(lsm.flush_wal().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_map_transforms_lazily() -> anyhow::Result<()> {
+        let db = TestDb::new("scan-map.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(4),
+                ..Default::default()
+            },
+        )?;
+        for i in 0..10 {
+            lsm.put(i, i * 100)?;
+        }
+        lsm.delete(3)?;
+        lsm.delete(4)?;
+
+        let calls = std::cell::Cell::new(0);
+        let mut values = lsm.scan_map(2..8, |_, value| {
+            calls.set(calls.get() + 1);
+            value
+        })?;
+        assert_eq!(calls.get(), 0);
+        assert_eq!(values.next(), Some(200));
+        assert_eq!(values.next(), Some(500));
+        // the deleted keys in between never got to it
+        assert_eq!(calls.get(), 2);
+        assert_eq!(values.next_back(), Some(700));
+        assert_eq!(calls.get(), 3);
+        drop(values);
+
+        let all: Vec<String> = lsm
+            .scan_map(.., |key, value| format!("{key}={value}"))?
+            .collect();
+        assert_eq!(all.len(), 8);
+        assert_eq!(all[3], "5=500");
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_consistent_never_sees_half_an_update() -> anyhow::Result<()> {
+        use std::sync::RwLock;
+
+        let db = TestDb::new("consistent.lsm");
+        let mut lsm: Lsm<String, u32> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(8),
+                ..Default::default()
+            },
+        )?;
+        let keys = ["checking".to_string(), "savings".to_string()];
+        lsm.put(keys[0].clone(), 100)?;
+        lsm.put(keys[1].clone(), 0)?;
+        let lsm = Arc::new(RwLock::new(lsm));
+
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        // moves money between the two, always keeping the total at 100,
+        // until the reader has seen enough
+        let writer = {
+            let (lsm, keys, done) = (lsm.clone(), keys.clone(), done.clone());
+            std::thread::spawn(move || -> anyhow::Result<()> {
+                let mut i = 0;
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    let mut batch = WriteBatch::new();
+                    batch.put(keys[0].clone(), 100 - i % 100);
+                    batch.put(keys[1].clone(), i % 100);
+                    lsm.write().unwrap().commit(batch)?;
+                    i += 1;
+                }
+                Ok(())
+            })
+        };
+        for _ in 0..100 {
+            let (balances, seq) = {
+                let lsm = lsm.read().unwrap();
+                (lsm.get_consistent(&keys)?, lsm.last_seq())
+            };
+            let total: u32 = balances.into_iter().flatten().sum();
+            assert_eq!(total, 100);
+            // every read lands between writes, and not all before or after them
+            while lsm.read().unwrap().last_seq() == seq && !writer.is_finished() {
+                std::thread::yield_now();
+            }
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        writer.join().unwrap()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn placement_routes_ssts_by_level() -> anyhow::Result<()> {
+        let db = TestDb::new("placed.lsm");
+        for dir in ["hot-tier", "cold-tier"] {
+            let _ = fs::remove_dir_all(dir);
+            fs::create_dir(dir)?;
+        }
+        let options = Options {
+            max_size: Some(2),
+            l0_compaction_trigger: Some(2),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options.clone())?;
+        lsm.place_ssts(|sst| match sst.level {
+            0 => PathBuf::from("hot-tier"),
+            _ => PathBuf::from("cold-tier"),
+        });
+        // enough flushes that some have been compacted into L1, and one hasn't
+        for key in 0..10 {
+            lsm.put(key, key * 10)?;
+        }
+        let layout = lsm.sst_layout()?;
+        let cold: Vec<_> = layout.iter().filter(|sst| sst.level > 0).collect();
+        let hot: Vec<_> = layout.iter().filter(|sst| sst.level == 0).collect();
+        assert!(!cold.is_empty() && !hot.is_empty());
+        for info in &cold {
+            assert!(Path::new(&info.name).starts_with("cold-tier"));
+            assert!(Path::new(&info.name).is_file());
+        }
+        for info in &hot {
+            assert!(Path::new(&info.name).starts_with("hot-tier"));
+            assert!(Path::new(&info.name).is_file());
+        }
+        drop(lsm);
+
+        // the manifest remembers where they went
+        let lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+        for key in 0..10 {
+            assert_eq!(lsm.get(&key)?, Some(key * 10));
+        }
+
+        drop(lsm);
+        for dir in ["hot-tier", "cold-tier"] {
+            fs::remove_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn lifecycle_in_memory() -> anyhow::Result<()> {
+        // the working directory only stays put while the lock is held
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = Path::new("in-memory.lsm");
+        let storage = Arc::new(Memory::default());
+        let options = Options {
+            max_size: Some(4),
+            l0_compaction_trigger: Some(2),
+            ..Default::default()
+        };
+
+        let mut lsm: Lsm<u32, String> = Lsm::open_in(storage.clone(), path, options.clone())?;
+        for key in 0..20 {
+            lsm.put(key, format!("v{key}"))?;
+        }
+        lsm.delete(3)?;
+        lsm.compact_range(..)?;
+        lsm.put(3, "again".to_string())?;
+        lsm.delete(7)?;
+        drop(lsm);
+
+        // nothing touched the disk, and it all comes back from the same storage
+        assert!(!path.exists());
+        assert!(storage.exists(&std::path::absolute(path)?));
+        let mut lsm: Lsm<u32, String> = Lsm::open_in(storage.clone(), path, options)?;
+        assert!(!lsm.sst_layout()?.is_empty());
+        assert_eq!(lsm.get(&3)?, Some("again".to_string()));
+        assert_eq!(lsm.get(&7)?, None);
+        assert_eq!(lsm.get(&19)?, Some("v19".to_string()));
+        assert_eq!(lsm.scan(..)?.count(), 19);
+        lsm.flush()?;
+        assert_eq!(lsm.get(&3)?, Some("again".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn value_cache_serves_hot_keys() -> anyhow::Result<()> {
+        let db = TestDb::new("value_cache.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(2),
+                value_cache_bytes: Some(64),
+                ..Default::default()
+            },
+        )?;
+        // the hot key ends up in the oldest SST, past the ones kept decoded anyway
+        for key in 0..12 {
+            lsm.put(key, key * 10)?;
+        }
+        assert!(lsm.tables().len() > RECENT_SSTS);
+
+        let reads = lsm.stats().sst_reads;
+        assert_eq!(lsm.get(&1)?, Some(10));
+        assert_eq!(lsm.stats().sst_reads, reads + 1);
+        for _ in 0..10 {
+            assert_eq!(lsm.get(&1)?, Some(10));
+        }
+        assert_eq!(lsm.stats().sst_reads, reads + 1);
+
+        // a write takes it out, flushed or not
+        lsm.put(1, 11)?;
+        lsm.flush()?;
+        assert_eq!(lsm.get(&1)?, Some(11));
+        lsm.delete(1)?;
+        lsm.flush()?;
+        assert_eq!(lsm.get(&1)?, None);
+
+        // and a compaction empties it
+        assert_eq!(lsm.get(&2)?, Some(20));
+        lsm.retain(|key, _| *key != 2)?;
+        assert_eq!(lsm.get(&2)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_filter_skips_cloning_rejected_values() -> anyhow::Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CLONES: AtomicUsize = AtomicUsize::new(0);
+
+        #[derive(Encode, Decode, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+        struct Counted(u32);
+
+        impl Clone for Counted {
+            fn clone(&self) -> Counted {
+                CLONES.fetch_add(1, Ordering::Relaxed);
+                Counted(self.0)
+            }
+        }
+
+        let db = TestDb::new("scan_filter.lsm");
+        let mut lsm: Lsm<u32, Counted> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(1000),
+                ..Default::default()
+            },
+        )?;
+        for key in 0..40 {
+            lsm.put(key, Counted(key))?;
+        }
+        lsm.flush()?;
+        // memtable versions over the SST ones, some of them now rejected
+        for key in 0..20 {
+            lsm.put(key, Counted(key + 1))?;
+        }
+        lsm.delete(31)?;
+
+        let odd = |_: &u32, value: &Counted| value.0 % 2 == 1;
+        let expected: Vec<(u32, Counted)> = lsm
+            .scan(5..35)?
+            .filter(|(key, value)| odd(key, value))
+            .collect();
+
+        CLONES.store(0, Ordering::Relaxed);
+        let filtered: Vec<(u32, Counted)> = lsm.scan_filter(5..35, odd)?.collect();
+        assert_eq!(filtered, expected);
+        // only the memtable values that were kept: 6, 8, ... 18
+        assert_eq!(CLONES.load(Ordering::Relaxed), 7);
+        assert_eq!(filtered.iter().filter(|(key, _)| *key < 20).count(), 7);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact_to_level_empties_the_levels_above() -> anyhow::Result<()> {
+        let db = TestDb::new("to_level.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(4),
+                sst_target_entries: Some(8),
+                ..Default::default()
+            },
+        )?;
+        let mut expected = BTreeMap::new();
+        let mut write = |lsm: &mut Lsm<u32, u32>, round: u32| -> anyhow::Result<()> {
+            for i in 0..20 {
+                let key = (i * 7 + round) % 40;
+                lsm.put(key, round)?;
+                expected.insert(key, round);
+            }
+            Ok(())
+        };
+        // one level each: 2 oldest, then 1, then what's been flushed since
+        write(&mut lsm, 0)?;
+        lsm.compact_to_level(2)?;
+        write(&mut lsm, 1)?;
+        lsm.compact_to_level(1)?;
+        write(&mut lsm, 2)?;
+        let levels = |lsm: &Lsm<u32, u32>| -> Vec<usize> {
+            lsm.tables().iter().map(|table| table.level).collect()
+        };
+        assert!(levels(&lsm).contains(&0));
+        let deepest: Vec<String> = lsm
+            .tables()
+            .iter()
+            .filter(|table| table.level == 2)
+            .map(|table| table.name.clone())
+            .collect();
+        assert!(!deepest.is_empty());
+
+        lsm.compact_to_level(1)?;
+        assert!(lsm.memtable.is_empty());
+        assert!(levels(&lsm).iter().all(|&level| level >= 1));
+        // below is left alone
+        let untouched: Vec<String> = lsm
+            .tables()
+            .iter()
+            .filter(|table| table.level == 2)
+            .map(|table| table.name.clone())
+            .collect();
+        assert_eq!(untouched, deepest);
+        // and the level is sorted runs with disjoint bounds
+        let l1: Vec<SstInfo<u32>> = lsm
+            .sst_layout()?
+            .into_iter()
+            .filter(|info| info.level == 1)
+            .collect();
+        assert!(l1.len() > 1);
+        for pair in l1.windows(2) {
+            assert!(pair[0].max_key < pair[1].min_key);
+        }
+        let got: BTreeMap<u32, u32> = lsm.iter()?.collect();
+        assert_eq!(got, expected);
+
+        lsm.compact_to_level(3)?;
+        assert!(levels(&lsm).iter().all(|&level| level == 3));
+        let got: BTreeMap<u32, u32> = lsm.iter()?.collect();
+        assert_eq!(got, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn scans_match_a_btreemap_under_random_writes() -> anyhow::Result<()> {
+        // xorshift, so a failing seed can be replayed
+        struct Rng(u64);
+        impl Rng {
+            fn below(&mut self, n: u64) -> u64 {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                self.0 % n
+            }
+        }
+
+        let db = TestDb::new("ordering.lsm");
+        for seed in 1..=20u64 {
+            let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            remove_db_files(db.name);
+            let options = Options {
+                max_size: Some(1 + rng.below(8) as usize),
+                l0_compaction_trigger: [None, Some(2), Some(4)][rng.below(3) as usize],
+                sst_target_entries: Some(1 + rng.below(16) as usize),
+                ..Default::default()
+            };
+            let mut lsm: Lsm<u16, u32> = Lsm::open_or_create_with(db.manifest(), options.clone())?;
+            let mut reference: BTreeMap<u16, u32> = BTreeMap::new();
+
+            for step in 0..300u32 {
+                let key = rng.below(64) as u16;
+                match rng.below(20) {
+                    0..=10 => {
+                        lsm.put(key, step)?;
+                        reference.insert(key, step);
+                    }
+                    11..=15 => {
+                        lsm.delete(key)?;
+                        reference.remove(&key);
+                    }
+                    16 => lsm.flush()?,
+                    17 => {
+                        let end = key + rng.below(16) as u16;
+                        lsm.compact_range(key..end)?;
+                    }
+                    18 => lsm.compact_to_level(1 + rng.below(2) as usize)?,
+                    _ => {
+                        drop(lsm);
+                        lsm = Lsm::open_or_create_with(db.manifest(), options.clone())?;
+                    }
+                }
+
+                if step % 10 == 0 {
+                    let start = rng.below(64) as u16;
+                    let end = start + rng.below(64) as u16;
+                    let expected: Vec<(u16, u32)> =
+                        reference.range(start..end).map(|(k, v)| (*k, *v)).collect();
+                    let got: Vec<(u16, u32)> = lsm.scan(start..end)?.collect();
+                    assert_eq!(got, expected, "seed {seed}, step {step}");
+                    let mut backwards: Vec<(u16, u32)> = lsm.scan(start..end)?.rev().collect();
+                    backwards.reverse();
+                    assert_eq!(backwards, expected, "seed {seed}, step {step}");
+                }
+            }
+            let all: Vec<(u16, u32)> = lsm.iter()?.collect();
+            let expected: Vec<(u16, u32)> = reference.into_iter().collect();
+            assert_eq!(all, expected, "seed {seed}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_flush_flips_at_the_threshold() -> anyhow::Result<()> {
+        let db = TestDb::new("should_flush.lsm");
+        let mut lsm: Lsm<u32, String> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(3),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(lsm.memtable_size(), (0, 0));
+
+        lsm.put(1, "a".to_string())?;
+        lsm.put(2, "b".to_string())?;
+        // overwrites don't add an entry
+        lsm.put(2, "bb".to_string())?;
+        assert!(!lsm.should_flush());
+        let (entries, bytes) = lsm.memtable_size();
+        assert_eq!(entries, 2);
+        // a byte of key, one for Some, one of length and the string
+        assert_eq!(bytes, (1 + 1 + 1 + 1) + (1 + 1 + 1 + 2));
+
+        lsm.delete(3)?;
+        assert!(lsm.should_flush());
+        assert_eq!(lsm.memtable_size(), (3, bytes + 2));
+
+        // left alone, the next write does it
+        lsm.put(4, "d".to_string())?;
+        assert_eq!(lsm.tables().len(), 1);
+        assert!(!lsm.should_flush());
+        assert_eq!(lsm.memtable_size().0, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_shared_hands_out_one_handle_per_path() -> anyhow::Result<()> {
+        let db = TestDb::new("shared.lsm");
+        let first: SharedLsm<u32, u32> = Lsm::open_shared(db.manifest(), Options::default())?;
+        // the same database, however the path is spelled
+        let second: SharedLsm<u32, u32> =
+            Lsm::open_shared(&Path::new(".").join(db.name), Options::default())?;
+        assert!(Arc::ptr_eq(&first, &second));
+
+        first.write().unwrap().put(1, 10)?;
+        assert_eq!(second.read().unwrap().get(&1)?, Some(10));
+
+        let err = Lsm::<String, u32>::open_shared(db.manifest(), Options::default())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::SchemaMismatch { .. })
+        ));
+
+        // once every clone is gone the next open starts over, from disk
+        drop((first, second));
+        let reopened: SharedLsm<u32, u32> = Lsm::open_shared(db.manifest(), Options::default())?;
+        assert_eq!(reopened.read().unwrap().get(&1)?, Some(10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_as_of_reads_past_versions() -> anyhow::Result<()> {
+        let db = TestDb::new("as_of.lsm");
+        let mut lsm: Lsm<u32, String> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(100),
+                ..Default::default()
+            },
+        )?;
+        lsm.put(2, "other".to_string())?;
+        let before = lsm.last_seq();
+        // flushed too, or nothing shows key 1 wasn't written before it
+        lsm.flush()?;
+        // each version flushed into an SST of its own
+        let mut versions = vec![];
+        for version in ["one", "two", "three"] {
+            lsm.put(1, version.to_string())?;
+            versions.push((lsm.last_seq(), version));
+            lsm.flush()?;
+        }
+        lsm.delete(1)?;
+        let deleted = lsm.last_seq();
+
+        assert_eq!(lsm.get_as_of(&1, before)?, None);
+        for (seq, version) in &versions {
+            assert_eq!(lsm.get_as_of(&1, *seq)?.as_deref(), Some(*version));
+            assert_eq!(lsm.get_as_of(&2, *seq)?.as_deref(), Some("other"));
+        }
+        assert_eq!(lsm.get_as_of(&1, deleted)?, None);
+        let (seq, _) = versions[1];
+        let at: Vec<(u32, String)> = lsm.scan_as_of(.., seq)?.collect();
+        assert_eq!(at, vec![(1, "two".to_string()), (2, "other".to_string())]);
+
+        // an overwrite before the next flush replaces the version in between
+        lsm.put(1, "four".to_string())?;
+        lsm.put(1, "five".to_string())?;
+        let err = lsm.get_as_of(&1, deleted + 1).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::HistoryUnavailable(deleted + 1))
+        );
+
+        // and so does merging the SSTs, for all but the newest
+        lsm.compact_range(..)?;
+        assert!(lsm.get_as_of(&1, versions[0].0).is_err());
+        assert_eq!(lsm.get_as_of(&1, lsm.last_seq())?.as_deref(), Some("five"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn debug_summarizes_without_keys() -> anyhow::Result<()> {
+        let db = TestDb::new("summary.lsm");
+        let mut lsm: Lsm<String, String> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(3),
+                ..Default::default()
+            },
+        )?;
+        // the fourth, seventh and tenth writes each flush first
+        for i in 0..10 {
+            lsm.put(format!("key-{i}"), format!("secret-{i}"))?;
+        }
+        lsm.compact_to_level(1)?;
+        lsm.put("key-10".to_string(), "secret-10".to_string())?;
+        lsm.put("key-11".to_string(), "secret-11".to_string())?;
+        lsm.flush()?;
+        lsm.put("key-12".to_string(), "secret-12".to_string())?;
+
+        let summary = format!("{lsm:?}");
+        let bytes: u64 = lsm.tables().iter().map(|table| table.bytes).sum();
+        assert!(summary.contains("memtable: 1/3"), "{summary}");
+        assert!(summary.contains("ssts_by_level: {0: 1, 1: 1}"), "{summary}");
+        assert!(
+            summary.contains(&format!("sst_bytes: {bytes}")),
+            "{summary}"
+        );
+        assert!(summary.contains("last_seq: 13"), "{summary}");
+        assert!(summary.contains("flush_due: false"), "{summary}");
+        assert!(!summary.contains("key-") && !summary.contains("secret"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_skips_log_records_already_in_ssts() -> anyhow::Result<()> {
+        let db = TestDb::new("reconcile.lsm");
+        let options = Options {
+            max_size: Some(2),
+            ..Default::default()
+        };
+
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options.clone())?;
+        lsm.put(1, 10)?;
+        lsm.put(2, 20)?;
+        lsm.crash_at = Some(CrashPoint::ManifestLogged);
+        assert!(lsm.put(3, 30).is_err());
+        drop(lsm);
+
+        // the SST has both, so the log's copies aren't applied a second time
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options.clone())?;
+        assert_eq!(lsm.tables().len(), 1);
+        assert!(lsm.memtable.is_empty());
+        assert_eq!(lsm.wal_records, 0);
+        assert_eq!(lsm.last_seq(), 2);
+        lsm.put(1, 11)?;
+        assert_eq!(lsm.last_seq(), 3);
+
+        // retain dying before it clears the log, which still has key 2 in it
+        lsm.put(4, 40)?;
+        let log = fs::read(".log")?;
+        lsm.retain(|key, _| *key != 2)?;
+        drop(lsm);
+        fs::write(".log", log)?;
+
+        let lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+        assert_eq!(lsm.get(&2)?, None);
+        assert_eq!(lsm.get(&1)?, Some(11));
+        assert_eq!(lsm.get(&4)?, Some(40));
+        assert!(lsm.memtable.is_empty());
+        assert_eq!(lsm.last_seq(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_cow_borrows_from_the_memtable() -> anyhow::Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CLONES: AtomicUsize = AtomicUsize::new(0);
+
+        #[derive(Encode, Decode, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+        struct Counted(u32);
+
+        impl Clone for Counted {
+            fn clone(&self) -> Counted {
+                CLONES.fetch_add(1, Ordering::Relaxed);
+                Counted(self.0)
+            }
+        }
+
+        let db = TestDb::new("get_cow.lsm");
+        let mut lsm: Lsm<u32, Counted> = Lsm::open_or_create(db.manifest())?;
+        lsm.put(1, Counted(10))?;
+        lsm.flush()?;
+        lsm.put(2, Counted(20))?;
+        lsm.delete(3)?;
+
+        CLONES.store(0, Ordering::Relaxed);
+        let value = lsm.get_cow(&2)?.unwrap();
+        assert!(matches!(value, Cow::Borrowed(Counted(20))));
+        assert_eq!(CLONES.load(Ordering::Relaxed), 0);
+
+        let value = lsm.get_cow(&1)?.unwrap();
+        assert!(matches!(value, Cow::Owned(Counted(10))));
+        assert_eq!(lsm.get_cow(&3)?, None);
+        assert_eq!(lsm.get_cow(&4)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn log_size_triggers_a_flush() -> anyhow::Result<()> {
+        let db = TestDb::new("wal_limit.lsm");
+        let mut lsm: Lsm<u32, String> = Lsm::open_or_create_with(
+            db.manifest(),
+            Options {
+                max_size: Some(1000),
+                max_wal_bytes: Some(200),
+                ..Default::default()
+            },
+        )?;
+
+        let mut key = 0;
+        while fs::metadata(".log")?.len() < 200 {
+            assert!(!lsm.should_flush());
+            lsm.put(key, "x".repeat(20))?;
+            key += 1;
+        }
+        assert!(lsm.tables().is_empty());
+        assert!(lsm.should_flush());
+
+        // the next write checkpoints the memtable first
+        let before = fs::metadata(".log")?.len();
+        lsm.put(key, "x".repeat(20))?;
+        assert_eq!(lsm.tables().len(), 1);
+        assert_eq!(lsm.stats().flushes, 1);
+        assert_eq!(lsm.memtable_size().0, 1);
+        let after = fs::metadata(".log")?.len();
+        assert!(after < before / 4, "{after} of {before}");
+        assert!(!lsm.should_flush());
+
+        drop(lsm);
+        let lsm: Lsm<u32, String> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.iter()?.count(), key as usize + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn arc_values_are_shared_not_copied() -> anyhow::Result<()> {
+        let db = TestDb::new("arc_values.lsm");
+        let mut lsm: Lsm<u32, Arc<[u8]>> = Lsm::open_or_create(db.manifest())?;
+        let blob: Arc<[u8]> = vec![7; 4 << 20].into();
+        lsm.put(1, blob.clone())?;
+
+        // out of the memtable: the very same bytes
+        let first = lsm.get(&1)?.unwrap();
+        let second = lsm.get(&1)?.unwrap();
+        assert!(Arc::ptr_eq(&first, &blob) && Arc::ptr_eq(&second, &blob));
+        drop((first, second));
+
+        // out of an SST, one decoded copy that every get shares
+        lsm.flush()?;
+        let first = lsm.get(&1)?.unwrap();
+        let second = lsm.get(&1)?.unwrap();
+        assert!(!Arc::ptr_eq(&first, &blob));
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.len(), 4 << 20);
+        assert_eq!(lsm.stats().sst_reads, 1);
+
+        drop(lsm);
+        let lsm: Lsm<u32, Arc<[u8]>> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.get(&1)?.as_deref(), Some(&blob[..]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_drops_keys_under_a_prefix() -> anyhow::Result<()> {
+        let db = TestDb::new("drop_prefix.lsm");
+        let options = Options {
+            max_size: Some(100),
+            compaction_drop_prefixes: vec![b"tmp/".to_vec()],
+            ..Default::default()
+        };
+        let mut lsm: Lsm<Vec<u8>, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+        let key = |key: &str| key.as_bytes().to_vec();
+
+        // oldest to newest: [tmp/old], [u1, u2], [tmp/a, u3]
+        lsm.put(key("tmp/old"), 1)?;
+        lsm.flush()?;
+        lsm.put(key("u1"), 2)?;
+        lsm.put(key("u2"), 3)?;
+        lsm.flush()?;
+        lsm.put(key("tmp/a"), 4)?;
+        lsm.put(key("u3"), 5)?;
+        lsm.flush()?;
+        // flushes alone keep them
+        assert_eq!(lsm.get(&key("tmp/old"))?, Some(1));
+
+        // merges the two newest, leaving one older SST underneath
+        lsm.compact_range(key("u")..)?;
+        assert_eq!(lsm.get(&key("tmp/a"))?, None);
+        assert_eq!(lsm.get(&key("tmp/old"))?, Some(1));
+
+        lsm.compact_to_level(1)?;
+        let left: Vec<(Vec<u8>, u32)> = lsm.iter()?.collect();
+        assert_eq!(left, vec![(key("u1"), 2), (key("u2"), 3), (key("u3"), 5)]);
+        assert_eq!(
+            lsm.sst_layout()?
+                .iter()
+                .map(|info| info.tombstones)
+                .sum::<usize>(),
+            0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn drop_prefixes_need_byte_keys() {
+        let db = TestDb::new("drop_prefix_keys.lsm");
+        let options = Options {
+            compaction_drop_prefixes: vec![b"tmp/".to_vec()],
+            ..Default::default()
+        };
+        let err = Lsm::<String, u32>::open_or_create_with(db.manifest(), options)
+            .map(|_| ())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn follower_applies_the_leaders_sequence_numbers() -> anyhow::Result<()> {
+        let db = TestDb::new("follower.lsm");
+        // the two share the log's name, so the leader is done before the follower starts
+        remove_db_files("leader.lsm");
+        let options = || Options {
+            max_size: Some(3),
+            ..Default::default()
+        };
+        let mut leader: Lsm<u32, u32> =
+            Lsm::open_or_create_with(Path::new("leader.lsm"), options())?;
+
+        // what a leader would ship: each write with the sequence it got
+        let mut shipped: Vec<(u32, Option<u32>, u64)> = vec![];
+        for i in 0..20u32 {
+            let key = i % 7;
+            let value = (i % 5 != 0).then_some(i);
+            match value {
+                Some(value) => {
+                    leader.put(key, value)?;
+                }
+                None => {
+                    leader.delete(key)?;
+                }
+            }
+            shipped.push((key, value, leader.last_seq()));
+        }
+        // a batch takes several sequence numbers at once, leaving a gap between shipped writes
+        let mut batch = WriteBatch::new();
+        batch.put(100, 1);
+        batch.put(101, 2);
+        leader.commit(batch)?;
+        leader.put(102, 3)?;
+        shipped.push((102, Some(3), leader.last_seq()));
+        leader.delete(100)?;
+        leader.delete(101)?;
+        let leader_state: Vec<(u32, u32)> = leader.iter()?.collect();
+        let leader_seq = leader.last_seq();
+        drop(leader);
+        remove_db_files("leader.lsm");
+
+        let mut follower: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options())?;
+        for &(key, value, seq) in &shipped {
+            match value {
+                Some(value) => follower.put_with_seq(key, value, seq)?,
+                None => follower.delete_with_seq(key, seq)?,
+            };
+            assert_eq!(follower.last_seq(), seq);
+        }
+        follower.delete_with_seq(100, leader_seq - 1)?;
+        follower.delete_with_seq(101, leader_seq)?;
+
+        // replays and sequences out of order are refused, and change nothing
+        for seq in [leader_seq, 1] {
+            let err = follower.put_with_seq(7, 7, seq).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::SequenceOutOfOrder { last, .. }) if *last == leader_seq
+            ));
+        }
+        assert_eq!(follower.get(&7)?, None);
+
+        let state: Vec<(u32, u32)> = follower.iter()?.collect();
+        assert_eq!(state, leader_state);
+        assert_eq!(follower.last_seq(), leader_seq);
+
+        drop(follower);
+        let mut follower: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(follower.last_seq(), leader_seq);
+        assert_eq!(follower.iter()?.collect::<Vec<(u32, u32)>>(), leader_state);
+        // writes of its own carry on from there
+        follower.put(8, 8)?;
+        assert_eq!(follower.last_seq(), leader_seq + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn wal_stream_tails_writes_after_a_sequence() -> anyhow::Result<()> {
+        let db = TestDb::new("wal_stream.lsm");
+        let options = || Options {
+            max_size: Some(1000),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options())?;
+        let streamed =
+            |lsm: &Lsm<u32, u32>, from: u64| -> anyhow::Result<Vec<(u64, u32, Option<u32>)>> {
+                Ok(lsm
+                    .wal_stream(from)?
+                    .map(|record| (record.seq, record.key, record.value))
+                    .collect())
+            };
+
+        for i in 1..=4u32 {
+            lsm.put(i, i * 10)?;
+        }
+        lsm.delete(2)?;
+        let mut batch = WriteBatch::new();
+        batch.put(5, 50);
+        batch.put(1, 11);
+        lsm.commit(batch)?;
+        assert_eq!(
+            streamed(&lsm, 3)?,
+            vec![
+                (4, 4, Some(40)),
+                (5, 2, None),
+                (6, 5, Some(50)),
+                (7, 1, Some(11))
+            ]
+        );
+        assert_eq!(streamed(&lsm, 7)?, vec![]);
+
+        // the flush takes what came before out of the log
+        lsm.flush()?;
+        lsm.put(6, 60)?;
+        let err = lsm.wal_stream(3).map(|_| ()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::HistoryUnavailable(3))
+        ));
+        assert_eq!(streamed(&lsm, 7)?, vec![(8, 6, Some(60))]);
+
+        drop(lsm);
+        let mut lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options())?;
+        assert_eq!(streamed(&lsm, 7)?, vec![(8, 6, Some(60))]);
+
+        // coalescing drops the versions later ones overwrote
+        for i in 0..wal::COALESCE_MIN_RECORDS as u32 {
+            lsm.put(7, i)?;
+        }
+        let last = lsm.last_seq();
+        assert!(lsm.wal_stream(8).is_err());
+        lsm.put(8, 80)?;
+        assert_eq!(streamed(&lsm, last)?, vec![(last + 1, 8, Some(80))]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn diff_reports_only_the_keys_that_differ() -> anyhow::Result<()> {
+        // the in-memory paths are still resolved against the working directory
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = Path::new("diff.lsm");
+        let options = Options {
+            max_size: Some(4),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> =
+            Lsm::open_in(Arc::new(Memory::default()), path, options.clone())?;
+        for key in 0..30u32 {
+            lsm.put(key, key * 2)?;
+        }
+        lsm.delete(5)?;
+        lsm.put(6, 600)?;
+
+        // a copy of the live pairs, laid out as one SST rather than many
+        let mut copy: Lsm<u32, u32> = Lsm::open_in(Arc::new(Memory::default()), path, options)?;
+        for (key, value) in lsm.iter()? {
+            copy.put(key, value)?;
+        }
+        copy.compact_to_level(1)?;
+        assert_ne!(lsm.sst_layout()?.len(), copy.sst_layout()?.len());
+        assert_eq!(lsm.diff(&copy)?.count(), 0);
+
+        copy.put(5, 1)?;
+        copy.delete(6)?;
+        copy.put(29, 0)?;
+        copy.put(40, 4)?;
+        let diffs: Vec<Diff<u32, u32>> = lsm.diff(&copy)?.collect();
+        assert_eq!(
+            diffs,
+            vec![
+                Diff::Added(5, 1),
+                Diff::Removed(6, 600),
+                Diff::Changed {
+                    key: 29,
+                    old: 58,
+                    new: 0
+                },
+                Diff::Added(40, 4),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn disk_full_leaves_the_database_consistent() -> anyhow::Result<()> {
+        // the working directory only stays put while the lock is held
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = Path::new("disk-full.lsm");
+        let storage = Arc::new(Memory::default());
+        let options = Options {
+            max_size: Some(4),
+            sst_target_entries: Some(2),
+            ..Default::default()
+        };
+        let is_disk_full =
+            |err: anyhow::Error| matches!(err.downcast_ref::<Error>(), Some(Error::DiskFull(_)));
+
+        let mut lsm: Lsm<u32, u32> = Lsm::open_in(storage.clone(), path, options.clone())?;
+        for key in 0..3u32 {
+            lsm.put(key, key)?;
+        }
+        lsm.flush()?;
+        for key in 3..6u32 {
+            lsm.put(key, key)?;
+        }
+
+        let seq = lsm.last_seq();
+        storage.set_full(true);
+        assert!(is_disk_full(lsm.flush().unwrap_err()));
+        assert!(is_disk_full(lsm.put(10, 10).unwrap_err()));
+        let mut batch = WriteBatch::new();
+        batch.put(12, 12);
+        batch.put(13, 13);
+        assert!(is_disk_full(lsm.commit(batch).unwrap_err()));
+        assert!(is_disk_full(lsm.compact_to_level(1).unwrap_err()));
+        for key in 0..6u32 {
+            assert_eq!(lsm.get(&key)?, Some(key));
+        }
+        assert_eq!(lsm.get(&10)?, None);
+        assert_eq!(lsm.get(&12)?, None);
+        assert_eq!(lsm.sst_layout()?.len(), 1);
+        // the failed writes didn't use up sequence numbers either
+        assert_eq!(lsm.last_seq(), seq);
+
+        // once there's room again it carries on where it was, with nothing
+        // torn left in the log for the next write to land behind
+        storage.set_full(false);
+        lsm.put_with_seq(11, 11, seq + 1)?;
+        drop(lsm);
+
+        let lsm: Lsm<u32, u32> = Lsm::open_in(storage.clone(), path, options)?;
+        let all: Vec<u32> = lsm.iter()?.map(|(key, _)| key).collect();
+        assert_eq!(all, vec![0, 1, 2, 3, 4, 5, 11]);
+        assert_eq!(lsm.sst_layout()?.len(), 1);
+        // and there's no gap in the log for the stream to trip over
+        let streamed: Vec<u64> = lsm.wal_stream(seq)?.map(|record| record.seq).collect();
+        assert_eq!(streamed, vec![seq + 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn manifest_syncs_apart_from_the_log() -> anyhow::Result<()> {
+        // the working directory only stays put while the lock is held
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = Path::new("manifest-sync.lsm");
+        let storage = Arc::new(Memory::default());
+        let options = Options {
+            max_size: Some(100),
+            sync_writes: false,
+            ..Default::default()
+        };
+
+        let mut lsm: Lsm<u32, u32> = Lsm::open_in(storage.clone(), path, options.clone())?;
+        for key in 0..3u32 {
+            lsm.put(key, key)?;
+        }
+        lsm.flush()?;
+        // never synced, so the crash takes these with it
+        for key in 3..5u32 {
+            lsm.put(key, key)?;
+        }
+        drop(lsm);
+
+        let crashed = Arc::new(storage.crash());
+        let lsm: Lsm<u32, u32> = Lsm::open_in(crashed, path, options.clone())?;
+        let left: Vec<u32> = lsm.iter()?.map(|(key, _)| key).collect();
+        assert_eq!(left, vec![0, 1, 2]);
+        assert_eq!(lsm.sst_layout()?.len(), 1);
+        drop(lsm);
+
+        // buffered, a flush leaves the manifest to the OS
+        let options = Options {
+            manifest_sync: ManifestSync::Buffered,
+            ..options
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_in(storage.clone(), path, options.clone())?;
+        let syncs = lsm.manifest.syncs();
+        lsm.put(5, 5)?;
+        lsm.flush()?;
+        assert_eq!(lsm.sst_layout()?.len(), 2);
+        assert_eq!(lsm.manifest.syncs(), syncs);
+        drop(lsm);
+
+        // and a crash can take the flush with it
+        let crashed = Arc::new(storage.crash());
+        let lsm: Lsm<u32, u32> = Lsm::open_in(crashed, path, options)?;
+        assert_eq!(lsm.sst_layout()?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn moving_the_working_directory_leaves_the_database_in_place() -> anyhow::Result<()> {
+        // puts the working directory back, even if the test fails
+        struct Restore(PathBuf);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let _ = std::env::set_current_dir(&self.0);
+            }
+        }
+
+        // every other test that touches the working directory holds the lock
+        // this does, so none of them sees it moved
+        let db = TestDb::new("cwd.lsm");
+        let moved = std::path::absolute("cwd-moved")?;
+        let _ = fs::remove_dir_all(&moved);
+        fs::create_dir(&moved)?;
+        let options = Options {
+            max_size: Some(3),
+            ..Default::default()
+        };
+
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+        lsm.put(0, 0)?;
+        {
+            let _restore = Restore(std::env::current_dir()?);
+            std::env::set_current_dir(&moved)?;
+            for key in 1..10u32 {
+                lsm.put(key, key)?;
+            }
+            lsm.flush()?;
+            for key in 0..10u32 {
+                assert_eq!(lsm.get(&key)?, Some(key));
+            }
+            // nothing was written relative to where it moved to
+            assert_eq!(fs::read_dir(&moved)?.count(), 0);
+        }
+
+        let layout = lsm.sst_layout()?;
+        assert!(layout.len() > 1);
+        for info in &layout {
+            assert!(Path::new(&info.name).is_file());
+        }
+        drop(lsm);
+        fs::remove_dir(&moved)?;
+
+        let lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.iter()?.count(), 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_raw_yields_the_encoded_pairs() -> anyhow::Result<()> {
+        let db = TestDb::new("scan_raw.lsm");
+        let options = Options {
+            max_size: Some(4),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<String, Vec<u32>> = Lsm::open_or_create_with(db.manifest(), options)?;
+        for i in 0..20u32 {
+            lsm.put(format!("k{i:02}"), vec![i; i as usize])?;
+        }
+        lsm.delete("k05".to_string())?;
+        lsm.put("k06".to_string(), vec![])?;
+
+        let range = "k03".to_string().."k15".to_string();
+        let decoded: Vec<(String, Vec<u32>)> = lsm
+            .scan_raw(range.clone())?
+            .map(|pair| -> anyhow::Result<_> {
+                let (key, value) = pair?;
+                let config = format::config();
+                let (decoded_key, read) = bincode::decode_from_slice(&key, config)?;
+                assert_eq!(read, key.len());
+                let (decoded_value, read) = bincode::decode_from_slice(&value, config)?;
+                assert_eq!(read, value.len());
+                Ok((decoded_key, decoded_value))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let scanned: Vec<(String, Vec<u32>)> = lsm.scan(range)?.collect();
+        assert_eq!(decoded, scanned);
+        assert_eq!(decoded.len(), 11);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_counters_add_up_once_idle() -> anyhow::Result<()> {
+        let db = TestDb::new("compaction-counters.lsm");
+        let options = Options {
+            max_size: Some(4),
+            l0_compaction_trigger: Some(2),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+        for key in 0..20u32 {
+            lsm.put(key, key)?;
+        }
+        lsm.wait_for_idle();
+
+        let layout = lsm.sst_layout()?;
+        assert!(layout.iter().filter(|info| info.level == 0).count() <= 2);
+        assert!(layout.iter().any(|info| info.level == 1));
+        let stats = lsm.stats();
+        assert!(stats.compactions > 0);
+        assert_eq!(stats.compactions_started, stats.compactions);
+        // what's at level 1 was all written out by compactions
+        let level1: u64 = layout
+            .iter()
+            .filter(|info| info.level == 1)
+            .map(|info| info.bytes)
+            .sum();
+        assert!(stats.compaction_bytes_written >= level1);
+        assert!(stats.compaction_bytes_read > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_keeps_to_its_byte_rate() -> anyhow::Result<()> {
+        let db = TestDb::new("compaction-rate.lsm");
+        let rate = 20_000;
+        let options = Options {
+            max_size: Some(50),
+            compaction_bytes_per_sec: Some(rate),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, Vec<u8>> = Lsm::open_or_create_with(db.manifest(), options.clone())?;
+        for key in 0..400u32 {
+            lsm.put(key, vec![0; 20])?;
+        }
+
+        lsm.reset_stats();
+        let started = std::time::Instant::now();
+        lsm.compact_to_level(1)?;
+        let elapsed = started.elapsed().as_secs_f64();
+        let stats = lsm.stats();
+        let moved = (stats.compaction_bytes_read + stats.compaction_bytes_written) as f64;
+        // all but the tenth of a second's worth the bucket can hold
+        let least = (moved - rate as f64 / 10.0) / rate as f64;
+        assert!(least > 0.3, "only {moved} bytes moved");
+        assert!(elapsed >= least, "{moved} bytes moved in {elapsed}s");
+        drop(lsm);
+
+        let err = Lsm::<u32, Vec<u8>>::open_with(
+            db.manifest(),
+            Options {
+                compaction_bytes_per_sec: Some(0),
+                ..options
+            },
+        )
+        .map(|_| ())
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidOptions(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn hysteresis_spaces_out_compactions_at_a_trigger() -> anyhow::Result<()> {
+        // deletes of keys that were never there: every flush is all tombstones
+        let compactions = |hysteresis: Option<usize>| -> anyhow::Result<(u64, u64)> {
+            let db = TestDb::new("hysteresis.lsm");
+            let options = Options {
+                max_size: Some(2),
+                l0_compaction_trigger: Some(8),
+                compaction_priority: Some(CompactionPriority::SpaceReclaimed),
+                compaction_hysteresis: hysteresis,
+                ..Default::default()
+            };
+            let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+            lsm.put(1000, 1)?;
+            for key in 0..40u32 {
+                lsm.delete(key)?;
+            }
+            assert_eq!(lsm.iter()?.collect::<Vec<(u32, u32)>>(), vec![(1000, 1)]);
+            let stats = lsm.stats();
+            Ok((stats.flushes, stats.compactions))
+        };
+
+        // all but the first flush, which holds the put
+        let (flushes, thrashing) = compactions(None)?;
+        assert_eq!(thrashing, flushes - 1);
+        let (flushes, spaced) = compactions(Some(4))?;
+        assert!(spaced > 0);
+        assert!(
+            spaced <= flushes.div_ceil(4),
+            "{spaced} compactions over {flushes} flushes"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_as_of_skip_ssts_written_after() -> anyhow::Result<()> {
+        let db = TestDb::new("as_of_pruning.lsm");
+        let options = Options {
+            max_size: Some(100),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+        for key in 0..3u32 {
+            lsm.put(key, key)?;
+        }
+        lsm.flush()?;
+        let snapshot = lsm.last_seq();
+        // several SSTs, each wholly newer than the snapshot
+        for round in 1..=5u32 {
+            lsm.put(0, round * 10)?;
+            lsm.put(round + 10, round)?;
+            lsm.flush()?;
+        }
+
+        let reads = lsm.stats().sst_reads;
+        assert_eq!(lsm.get_as_of(&0, snapshot)?, Some(0));
+        assert_eq!(lsm.get_as_of(&13, snapshot)?, None);
+        let as_of: Vec<(u32, u32)> = lsm.scan_as_of(.., snapshot)?.collect();
+        assert_eq!(as_of, vec![(0, 0), (1, 1), (2, 2)]);
+        // the first SST is read for the key and the scan in it, and the rest
+        // aren't opened at all
+        assert_eq!(lsm.stats().sst_reads, reads + 2);
+
+        assert_eq!(lsm.get_as_of(&0, snapshot + 2)?, Some(10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn corruption_policy_fails_or_skips() -> anyhow::Result<()> {
+        let db = TestDb::new("corruption-policy.lsm");
+        let options = |policy| Options {
+            max_size: Some(10),
+            corruption_policy: Some(policy),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> =
+            Lsm::open_or_create_with(db.manifest(), options(CorruptionPolicy::Lenient))?;
+        for key in 0..30u32 {
+            lsm.put(key, key)?;
+        }
+        let damaged = lsm.sst_layout()?[1].clone();
+        assert_eq!((damaged.min_key, damaged.max_key), (10, 19));
+        let mut ends = vec![];
+        for key in 100..103u32 {
+            lsm.put(key, key)?;
+            ends.push(fs::metadata(".log")?.len() as usize);
+        }
+        drop(lsm);
+
+        // the middle log record, and the second SST
+        let mut log = fs::read(".log")?;
+        log[ends[1] - 1] ^= 1;
+        fs::write(".log", log)?;
+        let mut bytes = fs::read(&damaged.name)?;
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        fs::write(&damaged.name, bytes)?;
+
+        let err = Lsm::<u32, u32>::open_with(db.manifest(), options(CorruptionPolicy::Strict))
+            .map(|_| ())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::CorruptRecord { index: 1, .. })
+        ));
+        let both = Options {
+            quarantine_corrupt_ssts: true,
+            ..options(CorruptionPolicy::Strict)
+        };
+        let err = Lsm::<u32, u32>::open_with(db.manifest(), both)
+            .map(|_| ())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidOptions(_))
+        ));
+
+        let lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options(CorruptionPolicy::Lenient))?;
+        assert_eq!(lsm.skipped_log_records().len(), 1);
+        assert_eq!(lsm.skipped_log_records()[0].index, 1);
+        assert_eq!(lsm.quarantined(), std::slice::from_ref(&damaged));
+        assert_eq!(lsm.get(&100)?, Some(100));
+        assert_eq!(lsm.get(&101)?, None);
+        assert_eq!(lsm.get(&102)?, Some(102));
+        assert_eq!(lsm.get(&5)?, Some(5));
+        assert_eq!(lsm.get(&15)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete_prefix_removes_only_that_prefix() -> anyhow::Result<()> {
+        let db = TestDb::new("delete_prefix.lsm");
+        let options = Options {
+            max_size: Some(100),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<Vec<u8>, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+        let keys: [&[u8]; 7] = [
+            b"a/1",
+            b"b",
+            b"b/1",
+            b"b/2",
+            b"c/1",
+            b"\xFF\xFF",
+            b"\xFF\xFF/1",
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            lsm.put(key.to_vec(), i as u32)?;
+            // some in SSTs, some still in the memtable
+            if i == 3 {
+                lsm.flush()?;
+            }
+        }
+
+        lsm.delete_prefix(b"b/")?;
+        lsm.delete_prefix(b"\xFF\xFF")?;
+        let left = |lsm: &Lsm<Vec<u8>, u32>| -> anyhow::Result<Vec<Vec<u8>>> {
+            Ok(lsm.iter()?.map(|(key, _)| key).collect())
+        };
+        let expected = vec![b"a/1".to_vec(), b"b".to_vec(), b"c/1".to_vec()];
+        assert_eq!(left(&lsm)?, expected);
+        assert_eq!(lsm.get(&b"b/1".to_vec())?, None);
+        assert_eq!(lsm.get(&b"b".to_vec())?, Some(1));
+
+        lsm.compact_to_level(1)?;
+        assert_eq!(left(&lsm)?, expected);
+        let layout = lsm.sst_layout()?;
+        assert_eq!(layout.iter().map(|info| info.entries).sum::<usize>(), 3);
+        assert_eq!(layout.iter().map(|info| info.tombstones).sum::<usize>(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn consistency_check_finds_a_missing_sst() -> anyhow::Result<()> {
+        let db = TestDb::new("consistency-missing.lsm");
+        let options = Options {
+            max_size: Some(10),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+        for key in 0..30u32 {
+            lsm.put(key, key)?;
+        }
+        lsm.check_consistency()?;
+
+        let gone = lsm.sst_layout()?[1].name.clone();
+        fs::remove_file(&gone)?;
+        let err = lsm.check_consistency().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::MissingSst(std::path::absolute(&gone)?))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn consistency_check_finds_orphan_ssts() -> anyhow::Result<()> {
+        let db = TestDb::new("consistency-orphan.lsm");
+        let options = Options {
+            max_size: Some(10),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+        for key in 0..30u32 {
+            lsm.put(key, key)?;
+        }
+
+        // left behind by a flush that never made it into the manifest
+        let stem = Lsm::<u32, u32>::stem(db.manifest());
+        let orphan = FileNames::default().sst(99, &stem);
+        fs::copy(&lsm.sst_layout()?[0].name, &orphan)?;
+        // not named like an SST of this database, so not its business
+        fs::write(format!("{orphan}.bak"), b"")?;
+
+        let err = lsm.check_consistency().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::OrphanSsts(vec![std::path::absolute(&orphan)?]))
+        );
+        fs::remove_file(&orphan)?;
+        fs::remove_file(format!("{orphan}.bak"))?;
+        lsm.check_consistency()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_sst_policy_fails_or_drops_at_open() -> anyhow::Result<()> {
+        let db = TestDb::new("missing-policy.lsm");
+        let options = |missing_ssts| Options {
+            max_size: Some(10),
+            missing_ssts,
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> =
+            Lsm::open_or_create_with(db.manifest(), options(MissingSstPolicy::OnRead))?;
+        for key in 0..30u32 {
+            lsm.put(key, key)?;
+        }
+        let gone = lsm.sst_layout()?[1].clone();
+        drop(lsm);
+        fs::remove_file(&gone.name)?;
+
+        // by default only the reads that reach it fail
+        let lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options(MissingSstPolicy::OnRead))?;
+        assert!(lsm.get(&15).is_err());
+        drop(lsm);
+
+        let err = Lsm::<u32, u32>::open_with(db.manifest(), options(MissingSstPolicy::Fail))
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::MissingSst(std::path::absolute(&gone.name)?))
+        );
+
+        let lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options(MissingSstPolicy::Drop))?;
+        assert_eq!(lsm.quarantined(), std::slice::from_ref(&gone));
+        assert_eq!(lsm.get(&15)?, None);
+        assert_eq!(lsm.get(&5)?, Some(5));
+        assert_eq!(lsm.iter()?.count(), 20);
+        drop(lsm);
+
+        // the manifest no longer lists it
+        let lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options(MissingSstPolicy::Fail))?;
+        lsm.check_consistency()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_reuse_their_encode_buffer() -> anyhow::Result<()> {
+        let db = TestDb::new("encode-buffer.lsm");
+        let options = Options {
+            max_size: Some(100_000),
+            encode_buffer_hint: Some(64),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u64, Vec<u8>> = Lsm::open_or_create_with(db.manifest(), options)?;
+        let value = |key: u64| vec![key as u8; 40];
+        lsm.put(0, value(0))?;
+
+        let writes = 1000u64;
+        let before = allocations();
+        for key in 1..=writes {
+            lsm.put(key, value(key))?;
+        }
+        // less the value each iteration builds
+        let put = allocations() - before - writes as usize;
+        let before = allocations();
+        for key in 1..=writes {
+            LogEntry::new(key, key, Some(value(key))).encode()?;
+        }
+        let fresh = allocations() - before - writes as usize;
+        // compression allocates its output for every record
+        if !cfg!(feature = "wal-compression") {
+            assert!(
+                put < writes as usize / 2,
+                "{put} allocations for {writes} puts"
+            );
+            assert!(fresh >= 2 * writes as usize, "{fresh} allocations");
+        }
+
+        // what's in the log is the same as before
+        let entry = LogEntry::new(7, 3u64, Some(value(3)));
+        assert_eq!(
+            wal::Encoder::new(0).encode(&entry)?,
+            entry.encode()?.as_slice()
+        );
+        drop(lsm);
+        let lsm: Lsm<u64, Vec<u8>> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.iter()?.count(), writes as usize + 1);
+        assert_eq!(lsm.get(&500)?, Some(value(500)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn cloned_scan_carries_on_from_the_same_key() -> anyhow::Result<()> {
+        let db = TestDb::new("clone-scan.lsm");
+        let options = Options {
+            max_size: Some(4),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+        for key in 0..10u32 {
+            lsm.put(key, key * 10)?;
+        }
+        // spread over SSTs and the memtable, with some overwritten and deleted
+        lsm.put(4, 41)?;
+        lsm.delete(6)?;
+
+        let mut scan = lsm.scan(1..9)?;
+        assert_eq!(
+            scan.by_ref().take(3).collect::<Vec<_>>(),
+            [(1, 10), (2, 20), (3, 30)]
+        );
+        let reads = lsm.stats().sst_reads;
+        let mut fork = scan.clone();
+        assert_eq!(lsm.stats().sst_reads, reads);
+
+        assert_eq!(scan.next(), Some((4, 41)));
+        assert_eq!(fork.next_back(), Some((8, 80)));
+        assert_eq!(scan.collect::<Vec<_>>(), [(5, 50), (7, 70), (8, 80)]);
+        assert_eq!(fork.collect::<Vec<_>>(), [(4, 41), (5, 50), (7, 70)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fixed_workload_stays_within_its_work_bounds() -> anyhow::Result<()> {
+        let db = TestDb::new("workload.lsm");
+        let options = Options {
+            max_size: Some(100),
+            l0_compaction_trigger: Some(4),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u64, u64> = Lsm::open_or_create_with(db.manifest(), options)?;
+        // every key once, in an order that scatters each flush over the key space
+        for i in 0..2000u64 {
+            lsm.put(i * 7919 % 2000, i)?;
+        }
+        let stats = lsm.reset_stats();
+        assert_eq!(stats.flushes, 19);
+        assert!((1..=5).contains(&stats.compactions), "{stats:?}");
+        let layout = lsm.sst_layout()?;
+        let l0 = layout.iter().filter(|info| info.level == 0).count() as u64;
+        assert!(l0 <= 4, "{l0} SSTs in L0");
+
+        // a hit reads at most the one L1 SST holding it and the L0 ones
+        let gets = (0..2000u64).step_by(97).count() as u64;
+        for key in (0..2000u64).step_by(97) {
+            assert!(lsm.get(&key)?.is_some());
+        }
+        let reads = lsm.reset_stats().sst_reads;
+        assert!(
+            reads <= gets * (l0 + 1),
+            "{reads} SST reads for {gets} gets"
+        );
+        // past every SST's bounds, so none is opened
+        for key in 2000..2100u64 {
+            assert_eq!(lsm.get(&key)?, None);
+        }
+        assert_eq!(lsm.reset_stats().sst_reads, 0);
+        // and a scan reads each SST once
+        assert_eq!(lsm.iter()?.count(), 2000);
+        assert_eq!(lsm.reset_stats().sst_reads, layout.len() as u64);
+
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_commits_or_rolls_back_whole() -> anyhow::Result<()> {
+        let db = TestDb::new("txn.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+        lsm.put(1, 10)?;
+        lsm.put(2, 20)?;
+
+        let mut txn = lsm.begin();
+        txn.put(1, 11);
+        txn.delete(2);
+        txn.put(3, 30);
+        assert_eq!(txn.get(&1)?, Some(11));
+        assert_eq!(txn.get(&2)?, None);
+        txn.commit()?;
+
+        let mut txn = lsm.begin();
+        txn.put(1, 12);
+        txn.put(4, 40);
+        txn.rollback();
+
+        let expected = vec![(1, 11), (3, 30)];
+        assert_eq!(lsm.iter()?.collect::<Vec<_>>(), expected);
+        drop(lsm);
+        let lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.iter()?.collect::<Vec<_>>(), expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_without_its_commit_marker_is_left_out() -> anyhow::Result<()> {
+        use std::io::Write;
+
+        let db = TestDb::new("txn-crash.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+        lsm.put(1, 10)?;
+        drop(lsm);
+
+        // as if the process died after writing all but the commit marker
+        let entries = [LogEntry::new(2, 1, Some(11)), LogEntry::new(3, 2, Some(20))];
+        let txn = wal::encode_txn(&entries)?;
+        // an empty transaction is just the two markers, which are the same size
+        let marker = wal::encode_txn::<u32, u32>(&[])?.len() / 2;
+        let mut log = OpenOptions::new().append(true).open(".log")?;
+        log.write_all(&txn[..txn.len() - marker])?;
+        drop(log);
+
+        let mut lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.iter()?.collect::<Vec<_>>(), vec![(1, 10)]);
+        assert!(lsm.skipped_log_records().is_empty());
+
+        // a plain write after it isn't taken for part of it
+        lsm.put(3, 3)?;
+        drop(lsm);
+        let mut lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.iter()?.collect::<Vec<_>>(), vec![(1, 10), (3, 3)]);
+
+        // and a transaction begun after it still commits
+        let mut txn = lsm.begin();
+        txn.put(3, 30);
+        txn.commit()?;
+        drop(lsm);
+        let lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.iter()?.collect::<Vec<_>>(), vec![(1, 10), (3, 30)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn absent_keys_are_cached_until_written() -> anyhow::Result<()> {
+        let db = TestDb::new("negative-cache.lsm");
+        let options = Options {
+            max_size: Some(10),
+            negative_cache_keys: Some(2),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+        // SSTs of even keys, each spread over the whole range
+        for i in 0..40u32 {
+            lsm.put(i * 11 % 40 * 2, i)?;
+        }
+        lsm.reset_stats();
+
+        // the older SSTs cover 21 too, and aren't kept decoded
+        assert_eq!(lsm.get(&21)?, None);
+        let reads = lsm.reset_stats().sst_reads;
+        assert!(reads >= 2, "{reads} SST reads");
+        for _ in 0..10 {
+            assert_eq!(lsm.get(&21)?, None);
+        }
+        assert_eq!(lsm.reset_stats().sst_reads, 0);
+
+        lsm.put(21, 210)?;
+        assert_eq!(lsm.get(&21)?, Some(210));
+        // and still there once it's flushed into an SST
+        lsm.flush()?;
+        assert_eq!(lsm.get(&21)?, Some(210));
+        lsm.delete(21)?;
+        assert_eq!(lsm.get(&21)?, None);
+
+        // only two are remembered, the least recently read going first
+        for key in [23, 25, 27, 23] {
+            assert_eq!(lsm.get(&key)?, None);
+        }
+        lsm.reset_stats();
+        assert_eq!(lsm.get(&25)?, None);
+        assert!(lsm.reset_stats().sst_reads > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_after_a_torn_record_survive_reopening() -> anyhow::Result<()> {
+        let db = TestDb::new("torn-tail.lsm");
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create(db.manifest())?;
+        for key in 0..4 {
+            lsm.put(key, key)?;
+        }
+        drop(lsm);
+        let log = OpenOptions::new().write(true).open(".log")?;
+        log.set_len(log.metadata()?.len() - 2)?;
+
+        let mut lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.get(&3)?, None);
+        lsm.put(4, 4)?;
+        drop(lsm);
+        let lsm: Lsm<u32, u32> = Lsm::open(db.manifest())?;
+        assert_eq!(lsm.get(&4)?, Some(4));
+        assert_eq!(lsm.iter()?.count(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn changes_since_reads_split_compaction_output() -> anyhow::Result<()> {
+        let db = TestDb::new("changes-split.lsm");
+        let options = Options {
+            max_size: Some(100),
+            sst_target_entries: Some(13),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<String, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+        // written z to a, so the SST with the low keys has the newest writes
+        for (seq, key) in ('a'..='z').rev().enumerate() {
+            lsm.put(key.to_string(), seq as u32 + 1)?;
+        }
+        lsm.flush()?;
+        lsm.compact_to_level(1)?;
+        assert_eq!(lsm.sst_layout()?.len(), 2);
+
+        let changed: Vec<String> = lsm.changes_since(20)?.map(|(key, _)| key).collect();
+        assert_eq!(changed, ["a", "b", "c", "d", "e", "f"]);
+        assert_eq!(lsm.changes_since(0)?.count(), 26);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_as_of_weigh_each_split_sst_on_its_own() -> anyhow::Result<()> {
+        let db = TestDb::new("as-of-split.lsm");
+        let options = Options {
+            max_size: Some(100),
+            sst_target_entries: Some(13),
+            ..Default::default()
+        };
+        let mut lsm: Lsm<String, u32> = Lsm::open_or_create_with(db.manifest(), options)?;
+        // z to a, so the SST with the low keys ends up with the newest writes
+        for (seq, key) in ('a'..='z').rev().enumerate() {
+            lsm.put(key.to_string(), seq as u32 + 1)?;
+        }
+        lsm.flush()?;
+        lsm.put("z".to_string(), 27)?;
+        lsm.flush()?;
+        lsm.compact_to_level(1)?;
+        let layout = lsm.sst_layout()?;
+        assert_eq!(layout.len(), 2);
+        assert!(layout[0].max_key < layout[1].min_key);
+
+        // the merge replaced z's first version, though the SST before
+        // reaches past it
+        let err = lsm.get_as_of(&"z".to_string(), 5).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::HistoryUnavailable(5))
+        );
+        assert!(lsm.scan_as_of(.., 26).is_err());
+        // versions that made it through are still there
+        assert_eq!(lsm.get_as_of(&"y".to_string(), 5)?, Some(2));
+        assert_eq!(lsm.get_as_of(&"a".to_string(), 26)?, Some(26));
+        let low: Vec<(String, u32)> = lsm.scan_as_of(..="m".to_string(), 26)?.collect();
+        assert_eq!(low.len(), 13);
+
+        Ok(())
+    }
+
+    #[test]
+    fn damaged_log_length_is_skipped_or_fails_by_policy() -> anyhow::Result<()> {
+        let db = TestDb::new("damaged-length.lsm");
+        let options = |policy| Options {
+            max_size: Some(100),
+            corruption_policy: policy,
+            ..Default::default()
+        };
+        let mut lsm: Lsm<u32, u32> = Lsm::open_or_create_with(db.manifest(), options(None))?;
+        for key in 0..3u32 {
+            lsm.put(key, key)?;
+        }
+        drop(lsm);
+        // a frame claiming a length far past anything that can be allocated
+        let damage = || -> std::io::Result<()> {
+            let mut log = fs::read(".log")?;
+            log.extend([
+                0, 0, 0xFD, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 1, 2,
+            ]);
+            fs::write(".log", log)
+        };
+
+        damage()?;
+        let err =
+            Lsm::<u32, u32>::open_with(db.manifest(), options(Some(CorruptionPolicy::Strict)))
+                .map(|_| ())
+                .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::CorruptRecord { index: 3, .. })
+        ));
+
+        for (index, policy) in [(3, Some(CorruptionPolicy::Lenient)), (4, None)] {
+            let mut lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options(policy))?;
+            assert_eq!(lsm.skipped_log_records().len(), 1);
+            assert_eq!(lsm.skipped_log_records()[0].index, index);
+            assert_eq!(lsm.get(&2)?, Some(2));
+            // the damaged frame is cut off, so writes after it are read back
+            lsm.put(10 + index as u32, 0)?;
+            drop(lsm);
+            let lsm: Lsm<u32, u32> = Lsm::open_with(db.manifest(), options(policy))?;
+            assert!(lsm.skipped_log_records().is_empty());
+            assert_eq!(lsm.get(&(10 + index as u32))?, Some(0));
+            drop(lsm);
+            damage()?;
+        }
 
         Ok(())
     }
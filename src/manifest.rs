@@ -0,0 +1,527 @@
+//! The manifest records which SSTs make up the database.
+//!
+//! On disk it's an append-only log of edits that starts with a snapshot of the
+//! full state. Replaying the edits over the snapshot gives the current state.
+//! Once enough edits pile up, the file is rewritten as a single fresh snapshot.
+//!
+//! Edits are normally fsynced as they're logged. Inside a batch they're only
+//! written, and a commit marker fsynced at the end makes the whole batch
+//! durable at once; a batch without its marker is dropped on replay.
+//!
+//! Every record carries a CRC of its encoded edit. A record cut off at the
+//! tail is a torn append and dropped, but one that fails its CRC, or claims a
+//! length no record can have, fails the open: the manifest has no redundancy
+//! to fall back on, and guessing at which SSTs are live could lose data.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use bincode::{Decode, Encode};
+
+use crate::{
+    bloom::PrefixBloom,
+    format::{self, config},
+    storage::{self, Storage},
+    Error, FileNames,
+};
+
+/// Edits appended after the last snapshot before the manifest is rewritten.
+pub(crate) const COMPACTION_THRESHOLD: usize = 64;
+
+#[derive(Encode, Decode, Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ManifestState {
+    // fingerprint of the key/value types the database was written with
+    pub(crate) schema: u32,
+    // number for the next SST file name, so names are never reused
+    pub(crate) next_table: usize,
+    // highest sequence number that made it into an SST
+    pub(crate) seq: u64,
+    // memtable entries before a flush
+    pub(crate) max_size: usize,
+    // how the log and SSTs are named, fixed when the database is made
+    pub(crate) files: FileNames,
+    // current SSTs, oldest first
+    pub(crate) tables: Vec<TableMeta>,
+}
+
+/// What's known about an SST without opening it.
+#[derive(Encode, Decode, Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TableMeta {
+    pub(crate) name: String,
+    // 0 when flushed, 1 once compacted out of L0
+    pub(crate) level: usize,
+    // bounds are kept encoded, since the manifest doesn't know the key type
+    pub(crate) min_key: Vec<u8>,
+    pub(crate) max_key: Vec<u8>,
+    pub(crate) entries: usize,
+    pub(crate) tombstones: usize,
+    pub(crate) bytes: u64,
+    // oldest and newest sequence numbers among the entries
+    pub(crate) min_seq: u64,
+    pub(crate) max_seq: u64,
+    // every write that went into it, including ones a newer version replaced
+    // before it was written, came after this sequence number
+    pub(crate) written_after: u64,
+    // set when the database was opened with `Options::prefix_bloom_len`
+    pub(crate) prefix_bloom: Option<PrefixBloom>,
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Edit {
+    // the full state, always the first record in the file
+    Snapshot(ManifestState),
+    AddSst(TableMeta),
+    // by name
+    RemoveSst(String),
+    SetSequence(u64),
+    // no longer written, but manifests from before max_size was fixed may hold it
+    SetMaxSize(usize),
+    // removes the named SSTs, putting the new one where the oldest of them was
+    ReplaceSsts(Vec<String>, TableMeta),
+    // an SST from outside, which goes in as the oldest
+    IngestSst(TableMeta),
+    // the edits between these two only count once the second is there
+    BeginBatch,
+    CommitBatch,
+    // like `ReplaceSsts`, for output split over several SSTs, in key order
+    ReplaceSstsWith(Vec<String>, Vec<TableMeta>),
+}
+
+impl ManifestState {
+    fn apply(&mut self, edit: Edit) {
+        match edit {
+            Edit::Snapshot(state) => *self = state,
+            Edit::AddSst(table) => {
+                self.tables.push(table);
+                self.next_table += 1;
+            }
+            Edit::RemoveSst(name) => self.tables.retain(|table| table.name != name),
+            Edit::SetSequence(seq) => self.seq = seq,
+            Edit::SetMaxSize(max_size) => self.max_size = max_size,
+            Edit::ReplaceSsts(removed, table) => {
+                let index = self
+                    .tables
+                    .iter()
+                    .position(|table| removed.contains(&table.name))
+                    .unwrap_or(self.tables.len());
+                self.tables.retain(|table| !removed.contains(&table.name));
+                self.tables.insert(index, table);
+                self.next_table += 1;
+            }
+            Edit::IngestSst(table) => {
+                self.tables.insert(0, table);
+                self.next_table += 1;
+            }
+            Edit::ReplaceSstsWith(removed, tables) => {
+                let index = self
+                    .tables
+                    .iter()
+                    .position(|table| removed.contains(&table.name))
+                    .unwrap_or(self.tables.len());
+                self.tables.retain(|table| !removed.contains(&table.name));
+                self.next_table += tables.len();
+                self.tables.splice(index..index, tables);
+            }
+            Edit::BeginBatch | Edit::CommitBatch => {}
+        }
+    }
+}
+
+pub(crate) struct Manifest {
+    storage: Arc<dyn Storage>,
+    path: PathBuf,
+    state: ManifestState,
+    // edits appended since the snapshot
+    edits: usize,
+    // inside a batch, so edits aren't synced until it's committed
+    batching: bool,
+    // fsync edits as they're logged, outside a batch
+    sync_edits: bool,
+    #[cfg(test)]
+    syncs: usize,
+}
+
+impl Manifest {
+    /// Writes a brand new manifest holding just `state`.
+    pub(crate) fn create(
+        storage: Arc<dyn Storage>,
+        path: &Path,
+        state: ManifestState,
+    ) -> Result<Manifest> {
+        Self::write_snapshot(&*storage, path, &state)?;
+        Ok(Manifest {
+            storage,
+            path: path.to_path_buf(),
+            state,
+            edits: 0,
+            batching: false,
+            sync_edits: true,
+            #[cfg(test)]
+            syncs: 0,
+        })
+    }
+
+    /// Replays an existing manifest.
+    ///
+    /// A torn record at the tail (from a crash mid-append) is ignored, and the
+    /// file is rewritten so later appends don't land behind it. So is a batch
+    /// that never got its commit marker. A damaged record fails with
+    /// `Error::CorruptRecord`.
+    ///
+    pub(crate) fn open(storage: Arc<dyn Storage>, path: &Path) -> Result<Manifest> {
+        let content = storage.read(path)?;
+        format::check_header(format::MANIFEST, &content, path)?;
+        let corrupt = |index| Error::CorruptRecord {
+            path: path.to_path_buf(),
+            index,
+        };
+
+        let (mut state, mut offset) = match decode_record(&content[format::HEADER_LEN..]) {
+            Record::Edit(Edit::Snapshot(state), read) => (state, format::HEADER_LEN + read),
+            Record::Damaged => return Err(corrupt(0).into()),
+            _ => bail!("manifest {} doesn't start with a snapshot", path.display()),
+        };
+
+        let mut edits = 0;
+        // the state and edit count from before an uncommitted batch
+        let mut committed = None;
+        loop {
+            let (edit, read) = match decode_record(&content[offset..]) {
+                Record::Edit(edit, read) => (edit, read),
+                Record::Torn => break,
+                Record::Damaged => return Err(corrupt(edits + 1).into()),
+            };
+            match edit {
+                Edit::BeginBatch => committed = Some((state.clone(), edits)),
+                Edit::CommitBatch => committed = None,
+                _ => {}
+            }
+            state.apply(edit);
+            offset += read;
+            edits += 1;
+        }
+        let torn = offset < content.len() || committed.is_some();
+        if let Some((before, count)) = committed {
+            state = before;
+            edits = count;
+        }
+
+        let mut manifest = Manifest {
+            storage,
+            path: path.to_path_buf(),
+            state,
+            edits,
+            batching: false,
+            sync_edits: true,
+            #[cfg(test)]
+            syncs: 0,
+        };
+        if torn {
+            manifest.compact()?;
+        }
+
+        Ok(manifest)
+    }
+
+    pub(crate) fn state(&self) -> &ManifestState {
+        &self.state
+    }
+
+    /// Applies `edits` and durably appends them as one write, rewriting the
+    /// manifest as a snapshot if it's grown too long.
+    ///
+    /// Inside a batch they're appended but not synced, and the manifest isn't
+    /// rewritten until the batch is committed. Without `sync_edits` they're
+    /// never synced here at all.
+    ///
+    pub(crate) fn log(&mut self, edits: Vec<Edit>) -> Result<()> {
+        self.append(edits)?;
+        if self.batching {
+            return Ok(());
+        }
+        if self.sync_edits {
+            self.sync()?;
+        }
+
+        if self.edits >= COMPACTION_THRESHOLD {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts a batch, so the edits logged until `commit_batch` share one fsync.
+    pub(crate) fn begin_batch(&mut self) -> Result<()> {
+        if !self.batching {
+            self.append(vec![Edit::BeginBatch])?;
+            self.batching = true;
+        }
+        Ok(())
+    }
+
+    /// Ends the batch, making every edit in it durable.
+    pub(crate) fn commit_batch(&mut self) -> Result<()> {
+        if !self.batching {
+            return Ok(());
+        }
+        self.batching = false;
+        self.log(vec![Edit::CommitBatch])
+    }
+
+    pub(crate) fn batching(&self) -> bool {
+        self.batching
+    }
+
+    /// Whether `log` fsyncs the edits it appends.
+    pub(crate) fn sync_edits(&mut self, sync: bool) {
+        self.sync_edits = sync;
+    }
+
+    // only applied once they're written, and cut back off if they aren't all,
+    // so a failed append leaves neither the state nor a torn tail behind
+    fn append(&mut self, edits: Vec<Edit>) -> Result<()> {
+        let mut payload = vec![];
+        for edit in &edits {
+            payload.extend(encode_record(edit)?);
+        }
+        let len = self.storage.len(&self.path)?;
+        if let Err(e) = self.storage.append(&self.path, &payload) {
+            let _ = self.storage.truncate(&self.path, len);
+            return Err(storage::write_error(&self.path, e));
+        }
+        for edit in edits {
+            self.state.apply(edit);
+            self.edits += 1;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.storage.sync(&self.path)?;
+        #[cfg(test)]
+        {
+            self.syncs += 1;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the manifest as a single snapshot of the current state.
+    pub(crate) fn compact(&mut self) -> Result<()> {
+        Self::write_snapshot(&*self.storage, &self.path, &self.state)?;
+        self.edits = 0;
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn edits(&self) -> usize {
+        self.edits
+    }
+
+    #[cfg(test)]
+    pub(crate) fn syncs(&self) -> usize {
+        self.syncs
+    }
+
+    // written to the side and renamed over, so a crash leaves either the old or new manifest
+    fn write_snapshot(storage: &dyn Storage, path: &Path, state: &ManifestState) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut content = format::header(format::MANIFEST).to_vec();
+        content.extend(encode_record(&Edit::Snapshot(state.clone()))?);
+        if let Err(e) = storage.write(&tmp, &content) {
+            let _ = storage.remove(&tmp);
+            return Err(storage::write_error(&tmp, e));
+        }
+        storage.rename(&tmp, path)?;
+
+        Ok(())
+    }
+}
+
+/// One record read back from the start of some manifest bytes.
+enum Record {
+    /// The edit, and how many bytes its record took.
+    Edit(Edit, usize),
+    /// Cut off, as by a crash mid-append, or nothing left at all.
+    Torn,
+    /// Fails its CRC, or claims a length no record can have.
+    Damaged,
+}
+
+// the edit is encoded on its own first, so its CRC can be checked before
+// anything is decoded from it
+fn encode_record(edit: &Edit) -> Result<Vec<u8>> {
+    let bytes = bincode::encode_to_vec(edit, config())?;
+    Ok(bincode::encode_to_vec(
+        (crc32fast::hash(&bytes), bytes),
+        config(),
+    )?)
+}
+
+fn decode_record(content: &[u8]) -> Record {
+    let Ok(((crc, len), read)) = bincode::decode_from_slice::<(u32, u64), _>(content, config())
+    else {
+        return Record::Torn;
+    };
+    if len > format::DECODE_LIMIT as u64 {
+        return Record::Damaged;
+    }
+    let Some(bytes) = content[read..].get(..len as usize) else {
+        return Record::Torn;
+    };
+    if crc32fast::hash(bytes) != crc {
+        return Record::Damaged;
+    }
+    match bincode::decode_from_slice::<Edit, _>(bytes, config()) {
+        Ok((edit, used)) if used == bytes.len() => Record::Edit(edit, read + bytes.len()),
+        _ => Record::Damaged,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, OpenOptions},
+        io::Write,
+    };
+
+    use super::*;
+    use crate::storage::Disk;
+
+    #[test]
+    fn replays_edits_across_compaction() -> Result<()> {
+        let path = std::env::temp_dir().join("mini-lsm-manifest-replay.manifest");
+        let _ = fs::remove_file(&path);
+
+        let mut manifest =
+            Manifest::create(Arc::new(Disk::default()), &path, ManifestState::default())?;
+        let mut expected = ManifestState::default();
+
+        // enough edits to go through a compaction, with some left over after it
+        for i in 0..COMPACTION_THRESHOLD + 10 {
+            let table = TableMeta {
+                name: format!("sst{i}"),
+                entries: i,
+                ..Default::default()
+            };
+            let mut edits = vec![Edit::AddSst(table), Edit::SetSequence(i as u64)];
+            if i % 3 == 0 {
+                edits.push(Edit::RemoveSst(format!("sst{}", i / 2)));
+            }
+            if i % 5 == 0 {
+                let merged = TableMeta {
+                    name: format!("merged{i}"),
+                    ..Default::default()
+                };
+                edits.push(Edit::ReplaceSsts(
+                    vec![format!("sst{}", i / 3), format!("sst{}", i / 4)],
+                    merged,
+                ));
+            }
+            for edit in edits.clone() {
+                expected.apply(edit);
+            }
+            manifest.log(edits)?;
+        }
+        assert_eq!(manifest.state(), &expected);
+        assert!(manifest.edits() < COMPACTION_THRESHOLD);
+
+        let reopened = Manifest::open(Arc::new(Disk::default()), &path)?;
+        assert_eq!(reopened.state(), &expected);
+        assert_eq!(reopened.edits(), manifest.edits());
+
+        // a torn append is dropped and the rest of the state survives
+        drop(reopened);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(&[2])?;
+        let reopened = Manifest::open(Arc::new(Disk::default()), &path)?;
+        assert_eq!(reopened.state(), &expected);
+        assert_eq!(reopened.edits(), 0);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn damaged_edit_fails_to_open() -> Result<()> {
+        let path = std::env::temp_dir().join("mini-lsm-manifest-damaged.manifest");
+        let _ = fs::remove_file(&path);
+
+        let mut manifest =
+            Manifest::create(Arc::new(Disk::default()), &path, ManifestState::default())?;
+        let table = TableMeta {
+            name: "sst0".to_string(),
+            ..Default::default()
+        };
+        manifest.log(vec![Edit::AddSst(table)])?;
+        drop(manifest);
+
+        // flip a byte of the edit, leaving its length alone
+        let mut content = fs::read(&path)?;
+        let last = content.len() - 1;
+        content[last] ^= 0xFF;
+        fs::write(&path, &content)?;
+        let err = Manifest::open(Arc::new(Disk::default()), &path)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::CorruptRecord { index: 1, .. })
+        ));
+
+        // and a length past anything a record can have
+        content.truncate(
+            format::HEADER_LEN + encode_record(&Edit::Snapshot(ManifestState::default()))?.len(),
+        );
+        content.extend([0, 0xFD, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 1]);
+        fs::write(&path, &content)?;
+        let err = Manifest::open(Arc::new(Disk::default()), &path)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::CorruptRecord { index: 1, .. })
+        ));
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn batch_counts_only_once_committed() -> Result<()> {
+        let path = std::env::temp_dir().join("mini-lsm-manifest-batch.manifest");
+        let _ = fs::remove_file(&path);
+
+        let table = |name: &str| TableMeta {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let mut manifest =
+            Manifest::create(Arc::new(Disk::default()), &path, ManifestState::default())?;
+        manifest.begin_batch()?;
+        for i in 0..10 {
+            manifest.log(vec![Edit::AddSst(table(&format!("sst{i}")))])?;
+        }
+        manifest.commit_batch()?;
+        assert_eq!(manifest.syncs(), 1);
+        let committed = manifest.state().clone();
+        assert_eq!(committed.tables.len(), 10);
+
+        // a batch cut off before its commit marker leaves no trace
+        manifest.begin_batch()?;
+        manifest.log(vec![Edit::RemoveSst("sst0".to_string())])?;
+        manifest.log(vec![Edit::AddSst(table("sst10"))])?;
+        drop(manifest);
+        let reopened = Manifest::open(Arc::new(Disk::default()), &path)?;
+        assert_eq!(reopened.state(), &committed);
+        assert!(!reopened.batching());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
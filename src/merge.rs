@@ -0,0 +1,441 @@
+//! Merging several key-sorted sources into one sorted stream.
+
+use std::{
+    cmp::Ordering,
+    collections::VecDeque,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+use crate::Entry;
+
+/// A key-sorted run of pairs that can be walked from either end.
+pub(crate) type Sorted<'a, K, T> = Box<dyn Run<'a, K, T> + 'a>;
+
+/// A walk over a key-sorted run that can be copied where it stands, to carry
+/// on from there twice.
+pub(crate) trait Run<'a, K, T>: DoubleEndedIterator<Item = (K, T)> {
+    fn clone_run(&self) -> Sorted<'a, K, T>;
+}
+
+impl<'a, K, T, I> Run<'a, K, T> for I
+where
+    I: DoubleEndedIterator<Item = (K, T)> + Clone + 'a,
+{
+    fn clone_run(&self) -> Sorted<'a, K, T> {
+        Box::new(self.clone())
+    }
+}
+
+impl<'a, K: 'a, T: 'a> Clone for Sorted<'a, K, T> {
+    fn clone(&self) -> Sorted<'a, K, T> {
+        (**self).clone_run()
+    }
+}
+
+/// Walks a run it shares with its copies, so copying the walk copies two
+/// offsets rather than the run. Pairs are cloned out while there's a copy to
+/// share them with, and moved out once there isn't.
+pub(crate) struct Shared<K, T> {
+    run: Arc<VecDeque<(K, T)>>,
+    // the next pair from the front, and one past the next from the back
+    front: usize,
+    back: usize,
+}
+
+impl<K, T> Shared<K, T> {
+    pub(crate) fn new(run: Vec<(K, T)>) -> Shared<K, T> {
+        let back = run.len();
+        Shared {
+            run: Arc::new(run.into()),
+            front: 0,
+            back,
+        }
+    }
+
+    /// The run to take pairs from, if no copy shares it, with nothing outside
+    /// the offsets left in it.
+    fn unshared(&mut self) -> Option<&mut VecDeque<(K, T)>> {
+        let run = Arc::get_mut(&mut self.run)?;
+        run.truncate(self.back);
+        run.drain(..self.front);
+        self.front = 0;
+        self.back = run.len();
+        Some(run)
+    }
+}
+
+impl<K, T> Clone for Shared<K, T> {
+    fn clone(&self) -> Shared<K, T> {
+        Shared {
+            run: self.run.clone(),
+            front: self.front,
+            back: self.back,
+        }
+    }
+}
+
+impl<K: Clone, T: Clone> Iterator for Shared<K, T> {
+    type Item = (K, T);
+
+    fn next(&mut self) -> Option<(K, T)> {
+        if self.front == self.back {
+            return None;
+        }
+        if let Some(run) = self.unshared() {
+            let pair = run.pop_front();
+            self.back -= 1;
+            return pair;
+        }
+        self.front += 1;
+        Some(self.run[self.front - 1].clone())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.back - self.front;
+        (left, Some(left))
+    }
+}
+
+impl<K: Clone, T: Clone> DoubleEndedIterator for Shared<K, T> {
+    fn next_back(&mut self) -> Option<(K, T)> {
+        if self.front == self.back {
+            return None;
+        }
+        if let Some(run) = self.unshared() {
+            let pair = run.pop_back();
+            self.back -= 1;
+            return pair;
+        }
+        self.back -= 1;
+        Some(self.run[self.back].clone())
+    }
+}
+
+/// A key-sorted run of entries, like the memtable or an SST.
+pub(crate) type Source<'a, K, V> = Sorted<'a, K, Entry<V>>;
+
+/// Narrows a key-sorted run down to the keys within `bounds`.
+pub(crate) fn bounded<'a, K, V>(
+    source: impl DoubleEndedIterator<Item = (K, Entry<V>)> + Clone + 'a,
+    bounds: (Bound<K>, Bound<K>),
+) -> Source<'a, K, V>
+where
+    K: Ord + Clone + 'a,
+    V: 'a,
+{
+    Box::new(Bounded {
+        source,
+        bounds,
+        done: false,
+    })
+}
+
+#[derive(Clone)]
+struct Bounded<I, K> {
+    source: I,
+    bounds: (Bound<K>, Bound<K>),
+    // set once either end has walked out of the bounds
+    done: bool,
+}
+
+impl<K: Ord, T, I: Iterator<Item = (K, T)>> Iterator for Bounded<I, K> {
+    type Item = (K, T);
+
+    fn next(&mut self) -> Option<(K, T)> {
+        while !self.done {
+            let (key, value) = self.source.next()?;
+            if self.bounds.contains(&key) {
+                return Some((key, value));
+            }
+            // sorted, so the first key past the end means everything after is too
+            self.done = !before_start(&self.bounds.0, &key);
+        }
+        None
+    }
+}
+
+impl<K: Ord, T, I: DoubleEndedIterator<Item = (K, T)>> DoubleEndedIterator for Bounded<I, K> {
+    fn next_back(&mut self) -> Option<(K, T)> {
+        while !self.done {
+            let (key, value) = self.source.next_back()?;
+            if self.bounds.contains(&key) {
+                return Some((key, value));
+            }
+            // and the same going backwards from the start
+            self.done = !after_end(&self.bounds.1, &key);
+        }
+        None
+    }
+}
+
+fn before_start<K: Ord>(start: &Bound<K>, key: &K) -> bool {
+    match start {
+        Bound::Included(start) => key < start,
+        Bound::Excluded(start) => key <= start,
+        Bound::Unbounded => false,
+    }
+}
+
+fn after_end<K: Ord>(end: &Bound<K>, key: &K) -> bool {
+    match end {
+        Bound::Included(end) => key > end,
+        Bound::Excluded(end) => key >= end,
+        Bound::Unbounded => false,
+    }
+}
+
+/// A source with one item held back at each end, so either end can be looked
+/// at without taking it. Once the source runs dry, whatever one end holds is
+/// next in line for the other, so nothing comes out twice.
+struct Ends<I: Iterator> {
+    source: I,
+    front: Option<I::Item>,
+    back: Option<I::Item>,
+}
+
+impl<I: Iterator + Clone> Clone for Ends<I>
+where
+    I::Item: Clone,
+{
+    fn clone(&self) -> Ends<I> {
+        Ends {
+            source: self.source.clone(),
+            front: self.front.clone(),
+            back: self.back.clone(),
+        }
+    }
+}
+
+impl<I: Iterator> Ends<I> {
+    fn new(source: I) -> Ends<I> {
+        Ends {
+            source,
+            front: None,
+            back: None,
+        }
+    }
+
+    fn peek_front(&mut self) -> Option<&I::Item> {
+        if self.front.is_none() {
+            self.front = self.source.next().or_else(|| self.back.take());
+        }
+        self.front.as_ref()
+    }
+
+    fn take_front(&mut self) -> Option<I::Item> {
+        self.peek_front();
+        self.front.take()
+    }
+}
+
+impl<I: DoubleEndedIterator> Ends<I> {
+    fn peek_back(&mut self) -> Option<&I::Item> {
+        if self.back.is_none() {
+            self.back = self.source.next_back().or_else(|| self.front.take());
+        }
+        self.back.as_ref()
+    }
+
+    fn take_back(&mut self) -> Option<I::Item> {
+        self.peek_back();
+        self.back.take()
+    }
+}
+
+/// Merges sources that are each sorted by key, yielding every key once. When
+/// several sources hold a key, `resolve` folds their values together in source
+/// order, first source first, whichever end the key is reached from.
+pub(crate) struct Merge<I: Iterator, R> {
+    sources: Vec<Ends<I>>,
+    resolve: R,
+}
+
+impl<I: Iterator + Clone, R: Clone> Clone for Merge<I, R>
+where
+    I::Item: Clone,
+{
+    fn clone(&self) -> Merge<I, R> {
+        Merge {
+            sources: self.sources.clone(),
+            resolve: self.resolve.clone(),
+        }
+    }
+}
+
+/// Merges entries, keeping the one with the highest sequence number for each
+/// key. Tombstones are passed through.
+pub(crate) type MergeIter<'a, K, V> =
+    Merge<Source<'a, K, V>, fn(&K, Entry<V>, Entry<V>) -> Entry<V>>;
+
+impl<'a, K: Ord, V> MergeIter<'a, K, V> {
+    pub(crate) fn new(sources: Vec<Source<'a, K, V>>) -> MergeIter<'a, K, V> {
+        Merge::with(sources, newest)
+    }
+}
+
+fn newest<K, V>(_: &K, a: Entry<V>, b: Entry<V>) -> Entry<V> {
+    if b.seq > a.seq {
+        b
+    } else {
+        a
+    }
+}
+
+impl<K: Ord, T, I: Iterator<Item = (K, T)>, R: FnMut(&K, T, T) -> T> Merge<I, R> {
+    pub(crate) fn with(sources: Vec<I>, resolve: R) -> Merge<I, R> {
+        Merge {
+            sources: sources.into_iter().map(Ends::new).collect(),
+            resolve,
+        }
+    }
+}
+
+impl<K: Ord, T, I: Iterator<Item = (K, T)>, R: FnMut(&K, T, T) -> T> Iterator for Merge<I, R> {
+    type Item = (K, T);
+
+    fn next(&mut self) -> Option<(K, T)> {
+        // the first source holding the smallest key goes first; none before it has that key
+        let first = self
+            .sources
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, source)| source.peek_front().map(|(key, _)| (i, key)))
+            .min_by(|(_, a), (_, b)| a.cmp(b))?
+            .0;
+        let (key, mut value) = self.sources[first].take_front()?;
+
+        // every later match, in this source or another, is another version of it
+        for source in &mut self.sources[first..] {
+            while let Some((next, _)) = source.peek_front() {
+                if *next != key {
+                    break;
+                }
+                let (_, other) = source.take_front()?;
+                value = (self.resolve)(&key, value, other);
+            }
+        }
+
+        Some((key, value))
+    }
+}
+
+impl<K: Ord, T, I: DoubleEndedIterator<Item = (K, T)>, R: FnMut(&K, T, T) -> T> DoubleEndedIterator
+    for Merge<I, R>
+{
+    fn next_back(&mut self) -> Option<(K, T)> {
+        // the last source holding the largest key; none after it has that key
+        let last = self
+            .sources
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, source)| source.peek_back().map(|(key, _)| (i, key)))
+            .max_by(|(_, a), (_, b)| a.cmp(b))?
+            .0;
+        let (key, value) = self.sources[last].take_back()?;
+
+        // every earlier match is another version, gathered back to front
+        let mut versions = vec![value];
+        for source in self.sources[..=last].iter_mut().rev() {
+            while let Some((next, _)) = source.peek_back() {
+                if *next != key {
+                    break;
+                }
+                versions.push(source.take_back()?.1);
+            }
+        }
+
+        // folded in the same order as going forwards
+        let mut versions = versions.into_iter().rev();
+        let first = versions.next()?;
+        let value = versions.fold(first, |a, b| (self.resolve)(&key, a, b));
+        Some((key, value))
+    }
+}
+
+///
+/// Merges two key-sorted streams, such as the `iter` of two databases, into
+/// one. For a key both hold, `resolve` gets the key, the value from `a` and
+/// the value from `b`, and picks or combines them.
+///
+/// Streams lazily, so importing one database into another is a single pass
+/// of `put`s over the result.
+///
+pub fn merge_sorted<'a, K, V>(
+    a: impl IntoIterator<Item = (K, V)> + 'a,
+    b: impl IntoIterator<Item = (K, V)> + 'a,
+    resolve: impl FnMut(&K, V, V) -> V + 'a,
+) -> impl Iterator<Item = (K, V)> + 'a
+where
+    K: Ord + 'a,
+    V: 'a,
+{
+    let sources: Vec<Box<dyn Iterator<Item = (K, V)> + 'a>> =
+        vec![Box::new(a.into_iter()), Box::new(b.into_iter())];
+    Merge::with(sources, resolve)
+}
+
+/// How a key differs between two databases, as `Lsm::diff` reports it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diff<K, V> {
+    /// only the first database has the key
+    Removed(K, V),
+    /// only the second database has the key
+    Added(K, V),
+    /// both have the key, with different values
+    Changed { key: K, old: V, new: V },
+}
+
+/// The keys that differ between two key-sorted streams, in key order, found
+/// in one pass over both.
+pub(crate) struct DiffIter<A: Iterator, B: Iterator> {
+    a: std::iter::Peekable<A>,
+    b: std::iter::Peekable<B>,
+}
+
+impl<A: Iterator, B: Iterator> DiffIter<A, B> {
+    pub(crate) fn new(a: A, b: B) -> DiffIter<A, B> {
+        DiffIter {
+            a: a.peekable(),
+            b: b.peekable(),
+        }
+    }
+}
+
+impl<K, V, A, B> Iterator for DiffIter<A, B>
+where
+    K: Ord,
+    V: PartialEq,
+    A: Iterator<Item = (K, V)>,
+    B: Iterator<Item = (K, V)>,
+{
+    type Item = Diff<K, V>;
+
+    fn next(&mut self) -> Option<Diff<K, V>> {
+        loop {
+            let order = match (self.a.peek(), self.b.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((a, _)), Some((b, _))) => a.cmp(b),
+            };
+            match order {
+                Ordering::Less => {
+                    let (key, value) = self.a.next()?;
+                    return Some(Diff::Removed(key, value));
+                }
+                Ordering::Greater => {
+                    let (key, value) = self.b.next()?;
+                    return Some(Diff::Added(key, value));
+                }
+                Ordering::Equal => {
+                    let (key, old) = self.a.next()?;
+                    let (_, new) = self.b.next()?;
+                    if old != new {
+                        return Some(Diff::Changed { key, old, new });
+                    }
+                }
+            }
+        }
+    }
+}
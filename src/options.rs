@@ -0,0 +1,295 @@
+//! Settings for opening a database.
+
+use std::time::Duration;
+
+use bincode::{Decode, Encode};
+
+/// Memtable entries before a flush, for databases created without one set.
+pub const DEFAULT_MAX_SIZE: usize = 2;
+/// Entries per SST written by a bulk load or compaction, unless set otherwise.
+pub const DEFAULT_SST_TARGET_ENTRIES: usize = 1024;
+/// Largest encoded key a write accepts, unless set otherwise.
+pub const DEFAULT_MAX_KEY_SIZE: usize = 64 * 1024;
+/// Largest encoded value a write accepts, unless set otherwise.
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Settings passed to `Lsm::open_with` and `Lsm::open_or_create_with`.
+///
+/// Settings that shape what's on disk are recorded in the manifest when the
+/// database is made. Leaving one of those as `None` uses the recorded value,
+/// and setting it to a different one fails with `Error::InvalidOptions`. The
+/// rest only apply to the handle being opened.
+///
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// memtable entries before a flush; can't be changed once the database
+    /// is created
+    pub max_size: Option<usize>,
+    /// how the log and SST files are named; can only be set when the
+    /// database is created
+    pub files: Option<FileNames>,
+    /// L0 SSTs allowed before all of them are compacted into L1, which bounds
+    /// how many SSTs a read goes through; `None` leaves L0 to grow
+    pub l0_compaction_trigger: Option<usize>,
+    /// fsync the log on every write, rather than only handing it to the OS;
+    /// `Lsm::put_opts` can override this per write
+    pub sync_writes: bool,
+    /// when manifest edits are fsynced, separately from the log; the
+    /// default fsyncs each one, since losing the manifest loses everything
+    /// it points at
+    pub manifest_sync: ManifestSync,
+    /// let a bulk load and its compaction share one manifest fsync, rather
+    /// than syncing once per SST; old SSTs are only deleted after it
+    pub batch_manifest_syncs: bool,
+    /// on open, move aside any SST that can't be read and carry on without
+    /// it, rather than failing later; see `Lsm::quarantined`
+    pub quarantine_corrupt_ssts: bool,
+    /// what to do about log records and SSTs that fail their checksums;
+    /// `None` skips such log records, listing them in
+    /// `Lsm::skipped_log_records`, and leaves a damaged SST to fail the reads
+    /// that reach it
+    pub corruption_policy: Option<CorruptionPolicy>,
+    /// what opening does about SSTs the manifest lists that aren't on disk,
+    /// as a crash partway through deleting a compaction's inputs can leave
+    pub missing_ssts: MissingSstPolicy,
+    /// for `Vec<u8>` keys, give each SST written a bloom filter over the
+    /// first this many bytes of its keys, which `Lsm::scan_prefix` checks
+    /// to skip SSTs without the prefix; `None` writes no filters, and with
+    /// any other key type opening fails with `Error::InvalidOptions`
+    pub prefix_bloom_len: Option<usize>,
+    /// how the prefix filters of SSTs written from now on hash; each filter
+    /// records its own, so older SSTs are still read the way they were built
+    pub prefix_bloom_hash: BloomHash,
+    /// what `Lsm::increment` does when a counter would overflow
+    pub counter_overflow: CounterOverflow,
+    /// after each flush, also consider runs of similar-sized SSTs and SSTs
+    /// that are mostly tombstones for compaction, and run whichever due
+    /// compaction ranks highest by this; `None` only compacts L0 once it
+    /// passes its trigger
+    pub compaction_priority: Option<CompactionPriority>,
+    /// flushes after a compaction before another is considered, unless L0
+    /// has passed its trigger by this many SSTs, so a database sitting right
+    /// at a trigger isn't rewritten on every flush; `None` considers one
+    /// after every flush
+    pub compaction_hysteresis: Option<usize>,
+    /// bytes a second compactions may read and write SSTs at, so flushes
+    /// and reads keep a share of the disk; they run on the thread that set
+    /// them off, which sleeps to keep to it. `None` leaves them unlimited,
+    /// and 0 fails with `Error::InvalidOptions`
+    pub compaction_bytes_per_sec: Option<u64>,
+    /// largest key, in encoded bytes, a write accepts; `None` allows up to
+    /// `DEFAULT_MAX_KEY_SIZE`
+    pub max_key_size: Option<usize>,
+    /// largest value, in encoded bytes, a write accepts; `None` allows up
+    /// to `DEFAULT_MAX_VALUE_SIZE`
+    pub max_value_size: Option<usize>,
+    /// bytes to reserve up front for encoding log records; writes reuse the
+    /// buffers either way, so this only spares them growing to fit the first
+    /// few records. `None` starts them empty
+    pub encode_buffer_hint: Option<usize>,
+    /// entries per SST a bulk load or compaction writes, splitting its
+    /// output into several SSTs with disjoint bounds; `None` uses
+    /// `DEFAULT_SST_TARGET_ENTRIES`. Flushes write the memtable as one SST
+    pub sst_target_entries: Option<usize>,
+    /// how log and SST writes and fsyncs that fail for a reason that may
+    /// pass are tried again; the default never retries
+    pub io_retry: RetryPolicy,
+    /// bytes of values point reads have found in SSTs to keep decoded, by
+    /// their encoded size, evicting the least recently read first; `None`
+    /// keeps none
+    pub value_cache_bytes: Option<usize>,
+    /// keys point reads have found absent to remember, so asking again
+    /// doesn't go through the SSTs, evicting the least recently read first;
+    /// `None` remembers none
+    pub negative_cache_keys: Option<usize>,
+    /// bytes the log can grow to before the next write flushes the memtable
+    /// and starts it over, bounding how much a reopen has to replay; `None`
+    /// leaves it to `max_size`
+    pub max_wal_bytes: Option<u64>,
+    /// for `Vec<u8>` keys, prefixes whose keys compactions drop, so a whole
+    /// namespace can be deleted lazily; until a compaction reaches them the
+    /// keys still read as they were, and a later write under one of these
+    /// prefixes goes the same way; with any other key type, opening fails
+    /// with `Error::InvalidOptions`
+    pub compaction_drop_prefixes: Vec<Vec<u8>>,
+}
+
+/// The hash `Options::prefix_bloom_len` filters set their bits by.
+///
+/// Each sets a prefix's 7 bits by double hashing, stepping from one hash by a
+/// second, so the bits are only as independent as the two hashes are. Ten
+/// bits a prefix gives about a 0.8% false positive rate when they are; when
+/// they aren't, prefixes collide on more of their bits, the rate goes up, and
+/// more prefix scans read SSTs they didn't need to. These hash the same in
+/// every build, as filters are kept on disk.
+#[derive(Encode, Decode, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BloomHash {
+    /// two CRC-32s with different initial values. Quick, but for prefixes of
+    /// one length the two differ by a constant, which raises the rate, to
+    /// about 1.1% over sequential ids
+    #[default]
+    Crc32,
+    /// 64-bit FNV-1a, mixed and split into two halves. Closer to the rate
+    /// the filters are sized for, for a little more hashing
+    Fnv1a,
+}
+
+/// How `Lsm::increment` handles a sum that doesn't fit in an `i64`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CounterOverflow {
+    /// stop at `i64::MAX` or `i64::MIN`
+    #[default]
+    Saturate,
+    /// wrap around, as two's complement addition does
+    Wrap,
+}
+
+/// When the manifest's edits are fsynced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ManifestSync {
+    /// as each one is logged, so a flush or compaction is durable once it
+    /// returns: the SSTs it wrote, their names in the directory, and the
+    /// manifest edit listing them
+    #[default]
+    Always,
+    /// whenever the OS gets to it, or the manifest is next rewritten. A crash
+    /// can lose recent flushes and compactions along with the log records or
+    /// SSTs they replaced, so this is only for data that can be rebuilt
+    Buffered,
+}
+
+/// What opening a database does about an SST the manifest lists that isn't
+/// there.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingSstPolicy {
+    /// open anyway, and fail the reads that reach it with `Error::MissingSst`
+    #[default]
+    OnRead,
+    /// fail the open with `Error::MissingSst`
+    Fail,
+    /// drop it from the manifest and open without it, listing it in
+    /// `Lsm::quarantined`; the keys it held read as whatever older versions
+    /// are left, if any
+    Drop,
+}
+
+/// How a database treats data that fails its checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// fail: opening or `Lsm::recover` with `Error::CorruptRecord` on a
+    /// damaged log record, and reads that reach a damaged SST with
+    /// `Error::CorruptSst`; can't be combined with `quarantine_corrupt_ssts`
+    Strict,
+    /// carry on with what's intact, skipping damaged log records and moving
+    /// damaged SSTs aside on open as `quarantine_corrupt_ssts` does;
+    /// `Lsm::skipped_log_records` and `Lsm::quarantined` say what was left out
+    Lenient,
+}
+
+/// What ranks one compaction over another when several are due.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionPriority {
+    /// the most SSTs taken off the read path for the bytes rewritten
+    ReadAmp,
+    /// the most tombstone bytes dropped for the bytes rewritten
+    SpaceReclaimed,
+    /// the SSTs that have gone longest without a newer one among them
+    Staleness,
+}
+
+/// How often an I/O operation that failed for a passing reason, such as an
+/// interrupted call or a full disk, is tried again before its error is
+/// returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// tries in all, the first included; 1 never retries
+    pub attempts: u32,
+    /// wait before the first retry, doubled before each one after it
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 1,
+            backoff: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Settings for a single write, passed to `Lsm::put_opts`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    /// fsync the log before returning, whatever the database-wide setting is
+    pub sync: bool,
+}
+
+/// How a database names its files, so several can share a directory.
+///
+/// The log is `{prefix}.{wal_extension}`. SSTs are `{prefix}sst`, a three
+/// digit number and the manifest's file stem, then `.{sst_extension}` if that
+/// isn't empty. The defaults give the original `.log` and `sst000name`.
+///
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub struct FileNames {
+    pub prefix: String,
+    pub wal_extension: String,
+    pub sst_extension: String,
+}
+
+impl Default for FileNames {
+    fn default() -> FileNames {
+        FileNames {
+            prefix: String::new(),
+            wal_extension: "log".to_string(),
+            sst_extension: String::new(),
+        }
+    }
+}
+
+impl FileNames {
+    pub fn wal(&self) -> String {
+        format!("{}.{}", self.prefix, self.wal_extension)
+    }
+
+    /// The name of SST number `table` for the manifest with stem `stem`.
+    pub fn sst(&self, table: usize, stem: &str) -> String {
+        let mut name = format!("{}sst{table:03}{stem}", self.prefix);
+        if !self.sst_extension.is_empty() {
+            name.push('.');
+            name.push_str(&self.sst_extension);
+        }
+        name
+    }
+
+    /// Whether `name` is one of the SSTs named by this convention for the
+    /// manifest with stem `stem`.
+    pub fn is_sst(&self, name: &str, stem: &str) -> bool {
+        let suffix = match self.sst_extension.as_str() {
+            "" => stem.to_string(),
+            extension => format!("{stem}.{extension}"),
+        };
+        name.strip_prefix(&format!("{}sst", self.prefix))
+            .and_then(|rest| rest.strip_suffix(&suffix))
+            .is_some_and(|number| number.len() >= 3 && number.bytes().all(|b| b.is_ascii_digit()))
+    }
+
+    /// Checks that no file this names could be the manifest `manifest`, or
+    /// land outside the manifest's directory.
+    pub(crate) fn validate(&self, manifest: &str, stem: &str) -> Result<(), String> {
+        let parts = [&self.prefix, &self.wal_extension, &self.sst_extension];
+        if parts.iter().any(|part| part.contains(['/', '\\'])) {
+            return Err("file names can't contain path separators".to_string());
+        }
+        if self.wal_extension.is_empty() {
+            return Err("the log needs an extension".to_string());
+        }
+        if self.wal() == manifest || self.is_sst(manifest, stem) {
+            return Err(format!("file names collide with the manifest {manifest}"));
+        }
+        if self.is_sst(&self.wal(), stem) {
+            return Err("the log would be named like an SST".to_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+//! One handle per database in the process, for code that opens the same
+//! path from several places.
+//!
+//! Two handles on one path would each append to the same log and each write
+//! SSTs the other doesn't know about. Instead the first `Lsm::open_shared`
+//! opens the database and registers it, and later ones get the same handle
+//! for as long as any of them keeps it.
+
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock, Weak},
+};
+
+use anyhow::Result;
+
+use crate::Error;
+
+/// A database handle shared by everything in the process that opened it.
+pub type SharedLsm<K, V> = Arc<RwLock<crate::Lsm<K, V>>>;
+
+// the schema fingerprint of each open database, and its handle
+type Registry = BTreeMap<PathBuf, (u32, Weak<dyn Any + Send + Sync>)>;
+
+static OPEN: Mutex<Registry> = Mutex::new(BTreeMap::new());
+
+/// The handle registered for `path`, or the one `open` makes and registers.
+/// A handle registered with types other than `schema`'s is a mismatch.
+pub(crate) fn get_or_open<T: Any + Send + Sync>(
+    path: &Path,
+    schema: u32,
+    open: impl FnOnce() -> Result<T>,
+) -> Result<Arc<T>> {
+    let key = canonical(path)?;
+    // held through `open`, so two threads can't both open the path
+    let mut registry = OPEN.lock().unwrap_or_else(|e| e.into_inner());
+    registry.retain(|_, (_, handle)| handle.strong_count() > 0);
+
+    // the last clone may be dropped at any point, so only a live one counts
+    if let Some((found, Some(handle))) = registry
+        .get(&key)
+        .map(|(found, handle)| (*found, handle.upgrade()))
+    {
+        return handle.downcast::<T>().map_err(|_| {
+            Error::SchemaMismatch {
+                expected: schema,
+                found,
+            }
+            .into()
+        });
+    }
+
+    let handle = Arc::new(open()?);
+    let weak: Weak<dyn Any + Send + Sync> = Arc::downgrade(&handle) as _;
+    registry.insert(key, (schema, weak));
+    Ok(handle)
+}
+
+/// `path` with its directory resolved, since the manifest itself may not
+/// exist yet.
+fn canonical(path: &Path) -> io::Result<PathBuf> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    Ok(fs::canonicalize(dir)?.join(name))
+}
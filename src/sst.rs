@@ -0,0 +1,253 @@
+//! The SST file layout, a writer for preparing SSTs outside a database, and a
+//! dumper for looking inside one.
+//!
+//! An SST file is the format header, the bincode-encoded table, and a crc32 of
+//! that encoding, so a file that's been cut short or damaged is caught on read.
+
+use std::{
+    fmt::Debug,
+    fs::{self, OpenOptions},
+    hash::Hash,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use bincode::{Decode, Encode};
+
+use crate::{format, Comparator, Entry, Error, Lsm};
+
+/// Frames an encoded table as a whole SST file.
+pub(crate) fn file_bytes(body: &[u8]) -> Vec<u8> {
+    let mut bytes = format::header(format::SST).to_vec();
+    bytes.extend(body);
+    bytes.extend(crc32fast::hash(body).to_le_bytes());
+    bytes
+}
+
+/// The encoded table inside an SST file, once its header and checksum check out.
+pub(crate) fn body<'a>(bytes: &'a [u8], path: &Path) -> Result<&'a [u8]> {
+    format::check_header(format::SST, bytes, path)?;
+    let Some(end) = bytes
+        .len()
+        .checked_sub(4)
+        .filter(|&end| end >= format::HEADER_LEN)
+    else {
+        return Err(Error::CorruptSst(path.to_path_buf()).into());
+    };
+    let (body, crc) = bytes[format::HEADER_LEN..].split_at(end - format::HEADER_LEN);
+    if crc32fast::hash(body).to_le_bytes() != crc {
+        return Err(Error::CorruptSst(path.to_path_buf()).into());
+    }
+    Ok(body)
+}
+
+///
+/// Writes an SST file that a database can take in with `Lsm::ingest_sst`.
+///
+/// Keys have to be added in strictly ascending order. Each one is encoded as
+/// it's added, and the file is written out by `finish`. Entries carry sequence
+/// number 0, as they don't come from any database's writes.
+///
+pub struct SstWriter<K, V> {
+    path: PathBuf,
+    entries: usize,
+    encoded: Vec<u8>,
+    last: Option<K>,
+    _value: std::marker::PhantomData<V>,
+}
+
+impl<K, V> SstWriter<K, V>
+where
+    K: Encode + Decode + Hash + Ord + Clone + 'static,
+    V: Encode + Decode + Hash + Ord + Clone + 'static,
+{
+    pub fn new(path: &Path) -> SstWriter<K, V> {
+        SstWriter {
+            path: path.to_path_buf(),
+            entries: 0,
+            encoded: vec![],
+            last: None,
+            _value: std::marker::PhantomData,
+        }
+    }
+
+    /// Adds a pair, failing with `Error::UnsortedSst` if `key` doesn't come
+    /// after the last one.
+    pub fn add(&mut self, key: K, value: V) -> Result<()> {
+        if self.last.as_ref().is_some_and(|last| *last >= key) {
+            return Err(Error::UnsortedSst(self.path.display().to_string()).into());
+        }
+        let entry = Entry {
+            seq: 0,
+            value: Some(value),
+        };
+        self.encoded
+            .extend(bincode::encode_to_vec((&key, entry), format::config())?);
+        self.entries += 1;
+        self.last = Some(key);
+        Ok(())
+    }
+
+    /// Writes the file out and syncs it.
+    pub fn finish(self) -> Result<()> {
+        // laid out the way an encoded `Sst` is: comparator, schema, entry count, entries
+        let config = format::config();
+        let mut body = bincode::encode_to_vec(Comparator::Ord, config)?;
+        body.extend(bincode::encode_to_vec(
+            Lsm::<K, V>::schema_fingerprint(),
+            config,
+        )?);
+        body.extend(bincode::encode_to_vec(self.entries as u64, config)?);
+        body.extend(self.encoded);
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.path)?;
+        file.write_all(&file_bytes(&body))?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+///
+/// Writes a readable description of the SST file at `path` to `out`: its
+/// header, checksum, comparator, schema fingerprint, entry count and key
+/// bounds, and every entry too if `entries` is set.
+///
+/// Meant for debugging damaged or unexpected files, so it isn't stopped by a
+/// checksum that doesn't match; that's reported and the rest decoded anyway.
+/// Anything that can't be decoded fails the dump, after what came before it
+/// has been written out.
+///
+pub fn dump_sst<K, V>(path: &Path, mut out: impl Write, entries: bool) -> Result<()>
+where
+    K: Decode + Debug,
+    V: Decode + Debug,
+{
+    let bytes = fs::read(path)?;
+    writeln!(out, "file: {} ({} bytes)", path.display(), bytes.len())?;
+
+    let expected = format::header(format::SST);
+    let header = &bytes[..bytes.len().min(format::HEADER_LEN)];
+    if header != expected {
+        writeln!(out, "header: {header:02x?}, expected {expected:02x?}")?;
+        return Err(Error::UnsupportedFormat(path.to_path_buf()).into());
+    }
+    writeln!(out, "header: LSMS, config {}", format::CONFIG_ID)?;
+
+    let Some(end) = bytes
+        .len()
+        .checked_sub(4)
+        .filter(|&end| end >= format::HEADER_LEN)
+    else {
+        writeln!(out, "checksum: missing")?;
+        return Err(Error::CorruptSst(path.to_path_buf()).into());
+    };
+    let body = &bytes[format::HEADER_LEN..end];
+    let stored = u32::from_le_bytes(bytes[end..].try_into()?);
+    let computed = crc32fast::hash(body);
+    if stored == computed {
+        writeln!(out, "checksum: {stored:08x}, ok")?;
+    } else {
+        writeln!(
+            out,
+            "checksum: {stored:08x}, but the contents hash to {computed:08x}"
+        )?;
+    }
+
+    // the fields of an encoded `Sst`, one at a time so a failure says where
+    let config = format::config();
+    let (comparator, mut at): (Comparator, usize) =
+        bincode::decode_from_slice(body, config).context("decoding the comparator")?;
+    writeln!(out, "comparator: {comparator:?}")?;
+    let (schema, read): (u32, usize) =
+        bincode::decode_from_slice(&body[at..], config).context("decoding the schema")?;
+    at += read;
+    writeln!(out, "schema: {schema:08x}")?;
+    let (count, read): (u64, usize) =
+        bincode::decode_from_slice(&body[at..], config).context("decoding the entry count")?;
+    at += read;
+
+    let mut decoded: Vec<(K, Entry<V>)> = vec![];
+    let mut failure = None;
+    for i in 0..count {
+        match bincode::decode_from_slice(&body[at..], config) {
+            Ok((entry, read)) => {
+                decoded.push(entry);
+                at += read;
+            }
+            Err(e) => {
+                failure = Some(anyhow::Error::new(e).context(format!("decoding entry {i}")));
+                break;
+            }
+        }
+    }
+
+    let tombstones = decoded
+        .iter()
+        .filter(|(_, entry)| entry.value.is_none())
+        .count();
+    writeln!(out, "entries: {count} ({tombstones} tombstones)")?;
+    if let (Some((min, _)), Some((max, _))) = (decoded.first(), decoded.last()) {
+        writeln!(out, "keys: {min:?} to {max:?}")?;
+    }
+    if entries {
+        for (key, entry) in &decoded {
+            match &entry.value {
+                Some(value) => writeln!(out, "  {key:?} @{}: {value:?}", entry.seq)?,
+                None => writeln!(out, "  {key:?} @{}: deleted", entry.seq)?,
+            }
+        }
+    }
+    if let Some(failure) = failure {
+        writeln!(out, "only {} of {count} entries decode", decoded.len())?;
+        return Err(failure);
+    }
+    if at < body.len() {
+        writeln!(out, "{} bytes left over after the entries", body.len() - at)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump_reports_count_and_bounds() -> Result<()> {
+        let path = std::env::temp_dir().join("mini-lsm-dump.sst");
+        let mut writer = SstWriter::<u32, String>::new(&path);
+        for key in [3, 8, 21, 40] {
+            writer.add(key, format!("v{key}"))?;
+        }
+        writer.finish()?;
+
+        let mut out = vec![];
+        dump_sst::<u32, String>(&path, &mut out, true)?;
+        let dump = String::from_utf8(out)?;
+        assert!(dump.contains("checksum: "), "{dump}");
+        assert!(dump.contains(", ok"), "{dump}");
+        assert!(dump.contains("entries: 4 (0 tombstones)"), "{dump}");
+        assert!(dump.contains("keys: 3 to 40"), "{dump}");
+        assert!(dump.contains("  21 @0: \"v21\""), "{dump}");
+
+        // damage is reported, and what still decodes is shown
+        let mut bytes = fs::read(&path)?;
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&path, bytes)?;
+        let mut out = vec![];
+        dump_sst::<u32, String>(&path, &mut out, false)?;
+        let dump = String::from_utf8(out)?;
+        assert!(dump.contains("but the contents hash to"), "{dump}");
+        assert!(dump.contains("entries: 4"), "{dump}");
+        assert!(!dump.contains("@0"), "{dump}");
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+//! Activity counters for a database handle.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// What a handle has done over some stretch of time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub puts: u64,
+    pub deletes: u64,
+    pub gets: u64,
+    pub flushes: u64,
+    /// merges of SSTs begun, from `retain`, `compact_range` or the L0 trigger;
+    /// more than `compactions` only while one is running or after one failed
+    pub compactions_started: u64,
+    /// merges of SSTs that finished
+    pub compactions: u64,
+    /// bytes of the SSTs finished compactions read in
+    pub compaction_bytes_read: u64,
+    /// bytes of the SSTs finished compactions wrote out
+    pub compaction_bytes_written: u64,
+    /// bytes appended to the log
+    pub wal_bytes: u64,
+    /// SST files read from disk
+    pub sst_reads: u64,
+    /// time covered by the counters
+    pub elapsed: Duration,
+}
+
+impl Stats {
+    /// Puts and deletes per second over `elapsed`.
+    pub fn writes_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        (self.puts + self.deletes) as f64 / secs
+    }
+}
+
+/// Cumulative counters that can be bumped through a shared reference, plus
+/// where the current window started.
+#[derive(Debug)]
+pub(crate) struct Counters {
+    pub(crate) puts: AtomicU64,
+    pub(crate) deletes: AtomicU64,
+    pub(crate) gets: AtomicU64,
+    pub(crate) flushes: AtomicU64,
+    pub(crate) compactions_started: AtomicU64,
+    pub(crate) compactions: AtomicU64,
+    pub(crate) compaction_bytes_read: AtomicU64,
+    pub(crate) compaction_bytes_written: AtomicU64,
+    pub(crate) wal_bytes: AtomicU64,
+    pub(crate) sst_reads: AtomicU64,
+    opened: Instant,
+    // the totals when the window started
+    window: Mutex<(Instant, Stats)>,
+}
+
+impl Default for Counters {
+    fn default() -> Counters {
+        let now = Instant::now();
+        Counters {
+            puts: AtomicU64::default(),
+            deletes: AtomicU64::default(),
+            gets: AtomicU64::default(),
+            flushes: AtomicU64::default(),
+            compactions_started: AtomicU64::default(),
+            compactions: AtomicU64::default(),
+            compaction_bytes_read: AtomicU64::default(),
+            compaction_bytes_written: AtomicU64::default(),
+            wal_bytes: AtomicU64::default(),
+            sst_reads: AtomicU64::default(),
+            opened: now,
+            window: Mutex::new((now, Stats::default())),
+        }
+    }
+}
+
+impl Counters {
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Everything since the handle was opened.
+    pub(crate) fn totals(&self) -> Stats {
+        Stats {
+            puts: self.puts.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            gets: self.gets.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            compactions_started: self.compactions_started.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            compaction_bytes_read: self.compaction_bytes_read.load(Ordering::Relaxed),
+            compaction_bytes_written: self.compaction_bytes_written.load(Ordering::Relaxed),
+            wal_bytes: self.wal_bytes.load(Ordering::Relaxed),
+            sst_reads: self.sst_reads.load(Ordering::Relaxed),
+            elapsed: self.opened.elapsed(),
+        }
+    }
+
+    /// Everything since the window started.
+    pub(crate) fn window(&self) -> Stats {
+        let window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+        Self::since(&self.totals(), &window)
+    }
+
+    /// Starts a new window, returning the one that just ended.
+    pub(crate) fn reset(&self) -> Stats {
+        let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+        let totals = self.totals();
+        let ended = Self::since(&totals, &window);
+        *window = (Instant::now(), totals);
+        ended
+    }
+
+    fn since(totals: &Stats, (started, start): &(Instant, Stats)) -> Stats {
+        Stats {
+            puts: totals.puts - start.puts,
+            deletes: totals.deletes - start.deletes,
+            gets: totals.gets - start.gets,
+            flushes: totals.flushes - start.flushes,
+            compactions_started: totals.compactions_started - start.compactions_started,
+            compactions: totals.compactions - start.compactions,
+            compaction_bytes_read: totals.compaction_bytes_read - start.compaction_bytes_read,
+            compaction_bytes_written: totals.compaction_bytes_written
+                - start.compaction_bytes_written,
+            wal_bytes: totals.wal_bytes - start.wal_bytes,
+            sst_reads: totals.sst_reads - start.sst_reads,
+            elapsed: started.elapsed(),
+        }
+    }
+}
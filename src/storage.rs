@@ -0,0 +1,590 @@
+//! Where the database's files live.
+//!
+//! Every read and write of the log, the manifest and the SSTs goes through a
+//! `Storage`. `Disk` is the default, wrapped in `Retrying` when the database is
+//! opened with retries; `Memory` keeps everything in the process, and tests
+//! use it to fail on demand and forget whatever wasn't synced.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crate::{Error, RetryPolicy};
+
+/// A place to keep the database's files, for `Lsm::open_in`.
+///
+/// Files are named by path and written whole, appended to (only the log and
+/// the manifest are) or renamed over one another. A missing file is an error
+/// of kind `NotFound`.
+///
+/// A file's name is only as durable as its directory. Each operation below
+/// that makes a file durable makes its name durable too, and a rename or
+/// removal is durable once it returns.
+///
+pub trait Storage: Send + Sync {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Replaces the file's contents with `bytes` and syncs it.
+    fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()>;
+
+    /// Adds `bytes` to the end of the file, making it if it isn't there. Not
+    /// durable until `sync`.
+    fn append(&self, path: &Path, bytes: &[u8]) -> io::Result<()>;
+
+    /// Makes what was appended to the file durable.
+    fn sync(&self, path: &Path) -> io::Result<()>;
+
+    /// Cuts the file back to `len` bytes. Not durable until `sync`.
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()>;
+
+    /// Moves `from` over `to` in one step, replacing whatever was at `to`.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Deletes the file.
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    fn len(&self, path: &Path) -> io::Result<u64>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    /// The names of the files directly in `dir`, in no particular order.
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>>;
+}
+
+/// `e`, from writing `path`, as the error to hand back: `Error::DiskFull` if
+/// the disk filled up, so callers can tell that apart and free some space.
+pub(crate) fn write_error(path: &Path, e: io::Error) -> anyhow::Error {
+    if e.kind() == io::ErrorKind::StorageFull {
+        Error::DiskFull(path.to_path_buf()).into()
+    } else {
+        e.into()
+    }
+}
+
+/// The real filesystem.
+///
+/// Each file made, renamed or removed has its directory fsynced as well, as
+/// the first sync of a file opened for appending does, since until then a
+/// crash can lose the name even though the contents were synced.
+///
+#[derive(Default)]
+pub struct Disk {
+    // files being appended to, kept open so each append isn't an open too,
+    // and whether their directory still needs syncing
+    appending: Mutex<HashMap<PathBuf, (File, bool)>>,
+}
+
+impl Disk {
+    // whether the file was being appended to, and its name not synced yet
+    fn forget(&self, path: &Path) -> bool {
+        self.appending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(path)
+            .is_some_and(|(_, unsynced)| unsynced)
+    }
+}
+
+/// Syncs the directory `path` is in, making the names in it durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+// elsewhere a directory can't be opened to sync it
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+impl Storage for Disk {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        let unsynced = self.forget(path) || !path.exists();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        if unsynced {
+            sync_dir(path)?;
+        }
+        Ok(())
+    }
+
+    fn append(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        let mut appending = self.appending.lock().unwrap_or_else(|e| e.into_inner());
+        if !appending.contains_key(path) {
+            // another handle may have made it without syncing its name
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            appending.insert(path.to_path_buf(), (file, true));
+        }
+        appending
+            .get_mut(path)
+            .expect("just opened")
+            .0
+            .write_all(bytes)
+    }
+
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        let mut appending = self.appending.lock().unwrap_or_else(|e| e.into_inner());
+        match appending.get_mut(path) {
+            Some((file, unsynced)) => {
+                file.sync_data()?;
+                if *unsynced {
+                    sync_dir(path)?;
+                    *unsynced = false;
+                }
+                Ok(())
+            }
+            None => File::open(path)?.sync_all(),
+        }
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let appending = self.appending.lock().unwrap_or_else(|e| e.into_inner());
+        match appending.get(path) {
+            Some((file, _)) => file.set_len(len),
+            None => OpenOptions::new().write(true).open(path)?.set_len(len),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.forget(from);
+        self.forget(to);
+        fs::rename(from, to)?;
+        sync_dir(to)?;
+        if from.parent() != to.parent() {
+            sync_dir(from)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.forget(path);
+        fs::remove_file(path)?;
+        sync_dir(path)
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// Another storage with the operations that write tried again when they fail
+/// for a reason that may pass, such as an interrupted call or a full disk.
+///
+/// An append that fails partway is cut back to where it started before it's
+/// tried again, so a retry never leaves half a record behind it.
+///
+pub(crate) struct Retrying {
+    inner: Arc<dyn Storage>,
+    policy: RetryPolicy,
+}
+
+impl Retrying {
+    pub(crate) fn new(inner: Arc<dyn Storage>, policy: RetryPolicy) -> Retrying {
+        Retrying { inner, policy }
+    }
+
+    // runs `op`, then `undo` before each retry; an error from `undo` ends the retries
+    fn retry(
+        &self,
+        mut op: impl FnMut() -> io::Result<()>,
+        mut undo: impl FnMut() -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut backoff = self.policy.backoff;
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(e) if attempt < self.policy.attempts && transient(&e) => {
+                    if undo().is_err() {
+                        return Err(e);
+                    }
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether an error may go away if the operation is just tried again.
+fn transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::StorageFull
+            | io::ErrorKind::ResourceBusy
+    )
+}
+
+impl Storage for Retrying {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+
+    fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        self.retry(|| self.inner.write(path, bytes), || Ok(()))
+    }
+
+    fn append(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        let len = if self.inner.exists(path) {
+            self.inner.len(path)?
+        } else {
+            0
+        };
+        self.retry(
+            || self.inner.append(path, bytes),
+            || self.inner.truncate(path, len),
+        )
+    }
+
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        self.retry(|| self.inner.sync(path), || Ok(()))
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        self.inner.truncate(path, len)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        self.inner.len(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        self.inner.list(dir)
+    }
+}
+
+/// Files in memory, gone when the last handle to it is dropped.
+///
+/// For tests each file remembers what it held at its last sync, and each
+/// directory the names it held at its last sync; `crash` keeps only those.
+/// A directory is synced the way `Disk` syncs it: by writing or first syncing
+/// a file new to it, and by each rename and removal, as a step of its own
+/// that can fail after the change itself went through. Once `fail_after`
+/// operations have gone through, the next one fails (an append or a write
+/// getting half its bytes in) and so does everything after it, as if the
+/// process had died there. `fail_next` makes a few fail the same way for a
+/// passing reason, with the rest going through.
+///
+#[derive(Default)]
+pub struct Memory {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    // which file each name is for
+    names: HashMap<PathBuf, u64>,
+    // the names as of each one's directory's last sync
+    synced_names: HashMap<PathBuf, u64>,
+    files: HashMap<u64, MemoryFile>,
+    next_file: u64,
+    // operations that changed something, so far
+    ops: usize,
+    fail_after: Option<usize>,
+    // operations left to fail with an error worth retrying
+    flaky: usize,
+    // writes and appends fail for want of space, after getting half in
+    full: bool,
+}
+
+#[derive(Default, Clone)]
+struct MemoryFile {
+    data: Vec<u8>,
+    synced: Vec<u8>,
+}
+
+impl MemoryState {
+    fn file(&self, path: &Path) -> io::Result<&MemoryFile> {
+        let id = self.names.get(path).ok_or_else(|| missing(path))?;
+        Ok(&self.files[id])
+    }
+
+    fn file_mut(&mut self, path: &Path) -> io::Result<&mut MemoryFile> {
+        let id = self.names.get(path).ok_or_else(|| missing(path))?;
+        Ok(self.files.get_mut(id).expect("named files exist"))
+    }
+
+    // the file named `path`, made empty if there isn't one, and whether it
+    // was just made
+    fn create(&mut self, path: &Path) -> (&mut MemoryFile, bool) {
+        let made = !self.names.contains_key(path);
+        if made {
+            self.next_file += 1;
+            self.names.insert(path.to_path_buf(), self.next_file);
+            self.files.insert(self.next_file, MemoryFile::default());
+        }
+        (self.file_mut(path).expect("just made"), made)
+    }
+
+    fn name_synced(&self, path: &Path) -> bool {
+        self.synced_names.get(path) == self.names.get(path)
+    }
+
+    // and drops the files left without a name either way
+    fn sync_dir(&mut self, path: &Path) {
+        let dir = path.parent();
+        self.synced_names.retain(|name, _| name.parent() != dir);
+        for (name, id) in &self.names {
+            if name.parent() == dir {
+                self.synced_names.insert(name.clone(), *id);
+            }
+        }
+        let named: HashSet<u64> = self
+            .names
+            .values()
+            .chain(self.synced_names.values())
+            .copied()
+            .collect();
+        self.files.retain(|id, _| named.contains(id));
+    }
+}
+
+impl Memory {
+    #[cfg(test)]
+    pub(crate) fn failing_after(ops: usize) -> Memory {
+        Memory {
+            state: Mutex::new(MemoryState {
+                fail_after: Some(ops),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Makes the next `ops` changing operations fail as interrupted.
+    #[cfg(test)]
+    pub(crate) fn fail_next(&self, ops: usize) {
+        self.state().flaky = ops;
+    }
+
+    /// Makes writes and appends fail as if the disk were full, until it's
+    /// set back.
+    #[cfg(test)]
+    pub(crate) fn set_full(&self, full: bool) {
+        self.state().full = full;
+    }
+
+    /// Changing operations done so far, failed ones included.
+    #[cfg(test)]
+    pub(crate) fn ops(&self) -> usize {
+        self.state().ops
+    }
+
+    /// What would be left if only the process died: everything it wrote,
+    /// synced or not, torn writes included.
+    #[cfg(test)]
+    pub(crate) fn restart(&self) -> Memory {
+        let state = self.state();
+        Memory {
+            state: Mutex::new(MemoryState {
+                names: state.names.clone(),
+                synced_names: state.synced_names.clone(),
+                files: state.files.clone(),
+                next_file: state.next_file,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// What would be left after a power cut: only what was synced, under the
+    /// names that were.
+    #[cfg(test)]
+    pub(crate) fn crash(&self) -> Memory {
+        let state = self.state();
+        let files = state
+            .synced_names
+            .values()
+            .map(|id| {
+                let synced = MemoryFile {
+                    data: state.files[id].synced.clone(),
+                    synced: state.files[id].synced.clone(),
+                };
+                (*id, synced)
+            })
+            .collect();
+        Memory {
+            state: Mutex::new(MemoryState {
+                names: state.synced_names.clone(),
+                synced_names: state.synced_names.clone(),
+                files,
+                next_file: state.next_file,
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // counts a changing operation, failing it if it's past the limit
+    fn step(state: &mut MemoryState) -> io::Result<()> {
+        state.ops += 1;
+        if state.fail_after.is_some_and(|limit| state.ops > limit) {
+            return Err(io::Error::other("injected failure"));
+        }
+        if state.flaky > 0 {
+            state.flaky -= 1;
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "injected transient failure",
+            ));
+        }
+        Ok(())
+    }
+
+    // fails a write or an append, if the disk is full
+    fn space(state: &MemoryState) -> io::Result<()> {
+        if state.full {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "injected full disk",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn missing(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, path.display().to_string())
+}
+
+impl Storage for Memory {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        Ok(self.state().file(path)?.data.clone())
+    }
+
+    fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        let mut state = self.state();
+        let failed = Self::step(&mut state).and_then(|()| Self::space(&state));
+        let (file, made) = state.create(path);
+        if failed.is_err() {
+            // torn: the old contents are gone and only some of the new ones made it
+            file.data = bytes[..bytes.len() / 2].to_vec();
+            return failed;
+        }
+        file.data = bytes.to_vec();
+        file.synced = bytes.to_vec();
+        if made || !state.name_synced(path) {
+            Self::step(&mut state)?;
+            state.sync_dir(path);
+        }
+        Ok(())
+    }
+
+    fn append(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        let mut state = self.state();
+        let failed = Self::step(&mut state).and_then(|()| Self::space(&state));
+        let (file, _) = state.create(path);
+        match failed {
+            Ok(()) => file.data.extend(bytes),
+            Err(_) => file.data.extend(&bytes[..bytes.len() / 2]),
+        }
+        failed
+    }
+
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        Self::step(&mut state)?;
+        let file = state.file_mut(path)?;
+        file.synced = file.data.clone();
+        if !state.name_synced(path) {
+            Self::step(&mut state)?;
+            state.sync_dir(path);
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let mut state = self.state();
+        Self::step(&mut state)?;
+        state.file_mut(path)?.data.truncate(len as usize);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        Self::step(&mut state)?;
+        let id = state.names.remove(from).ok_or_else(|| missing(from))?;
+        state.names.insert(to.to_path_buf(), id);
+        Self::step(&mut state)?;
+        state.sync_dir(to);
+        state.sync_dir(from);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        Self::step(&mut state)?;
+        state.names.remove(path).ok_or_else(|| missing(path))?;
+        Self::step(&mut state)?;
+        state.sync_dir(path);
+        Ok(())
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        Ok(self.state().file(path)?.data.len() as u64)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.state().names.contains_key(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        Ok(self
+            .state()
+            .names
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+            .collect())
+    }
+}
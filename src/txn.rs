@@ -0,0 +1,59 @@
+//! Writes buffered against a database and committed together.
+
+use std::hash::Hash;
+
+use anyhow::Result;
+use bincode::{Decode, Encode};
+
+use crate::{Lsm, WriteBatch};
+
+///
+/// Puts and deletes buffered by `Lsm::begin`, applied together by `commit`
+/// or thrown away by `rollback`.
+///
+/// Nothing reaches the database or its log before `commit`, and dropping a
+/// transaction rolls it back. Its reads see its own writes over what the
+/// database holds. The database can't be used until the transaction ends.
+///
+pub struct Txn<'a, K: 'static, V: 'static> {
+    lsm: &'a mut Lsm<K, V>,
+    writes: WriteBatch<K, V>,
+}
+
+impl<'a, K, V> Txn<'a, K, V>
+where
+    K: Encode + Decode + Hash + Ord + Clone + 'static,
+    V: Encode + Decode + Hash + Ord + Clone + 'static,
+{
+    pub(crate) fn new(lsm: &'a mut Lsm<K, V>) -> Txn<'a, K, V> {
+        Txn {
+            lsm,
+            writes: WriteBatch::new(),
+        }
+    }
+
+    pub fn put(&mut self, key: K, value: V) {
+        self.writes.put(key, value);
+    }
+
+    pub fn delete(&mut self, key: K) {
+        self.writes.delete(key);
+    }
+
+    /// The value of `key` with this transaction's writes applied.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.writes.ops.iter().rev().find(|(k, _)| k == key) {
+            Some((_, value)) => Ok(value.clone()),
+            None => self.lsm.get(key),
+        }
+    }
+
+    /// Logs the writes between transaction markers and applies them, as
+    /// `Lsm::commit` applies a batch. Nothing is applied if this fails.
+    pub fn commit(self) -> Result<()> {
+        self.lsm.commit_ops(self.writes, true)
+    }
+
+    /// Throws the writes away.
+    pub fn rollback(self) {}
+}
@@ -0,0 +1,451 @@
+//! The write-ahead log: one CRC-checked record per write, appended in order.
+//!
+//! Each record is a frame holding the bincode-encoded entry, possibly
+//! compressed, with a marker saying how. A write batch is one frame holding
+//! all of its entries back to back, so it's replayed whole or not at all. The
+//! CRC covers the bytes exactly as they're stored, so it's checked before
+//! anything is decompressed or decoded.
+//!
+//! A transaction's records are framed one by one between a begin and a commit
+//! marker, which are frames with no bytes. They're only replayed once the
+//! commit marker after them is read, and not at all if any of them is damaged.
+//! A log that ends inside one is cut back to its begin marker when it's
+//! opened, so later records aren't taken for part of it.
+
+use std::{collections::BTreeMap, fs, hash::Hash, path::Path};
+
+use anyhow::Result;
+use bincode::{Decode, Encode};
+
+use crate::{
+    format::{self, config},
+    Error,
+};
+
+/// Records a log has to reach before it's worth coalescing.
+pub(crate) const COALESCE_MIN_RECORDS: usize = 64;
+
+/// Capacity an `Encoder` keeps between records, beyond its hint; one
+/// outsized record shouldn't pin its memory for good.
+const ENCODER_KEEP: usize = 1 << 20;
+
+#[derive(Encode, Decode, Debug)]
+pub(crate) struct LogEntry<
+    K: Encode + Decode + Hash + Ord + 'static,
+    V: Encode + Decode + Hash + Ord + 'static,
+> {
+    pub(crate) seq: u64,
+    pub(crate) key: K,
+    // None is a tombstone
+    pub(crate) value: Option<V>,
+}
+
+/// How a frame's bytes are stored, or which transaction marker it is.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Raw,
+    // lz4 block with the uncompressed size up front
+    Lz4,
+    TxnBegin,
+    TxnCommit,
+}
+
+#[derive(Encode, Decode, Debug)]
+struct Frame {
+    codec: Codec,
+    crc: u32,
+    bytes: Vec<u8>,
+}
+
+/// A log record that was skipped during replay because its CRC didn't match, or
+/// its length couldn't be right.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedRecord {
+    /// position of the record in the log, counting from 0
+    pub index: usize,
+    /// byte offset of the record in the file
+    pub offset: usize,
+}
+
+/// A write read back out of the log by `Lsm::wal_stream`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord<K, V> {
+    pub seq: u64,
+    pub key: K,
+    /// the value written, or `None` for a delete
+    pub value: Option<V>,
+}
+
+/// What `replay_wal` recovered from a log.
+#[derive(Debug)]
+pub struct WalReplay<K, V> {
+    /// the state the log rebuilds, without the keys it deletes
+    pub entries: BTreeMap<K, V>,
+    pub skipped: Vec<SkippedRecord>,
+    /// bytes at the end that don't frame a whole record, e.g. from a torn write
+    pub trailing_bytes: usize,
+}
+
+/// Every record in a log, split into the ones that check out and the ones that don't.
+pub(crate) struct Records<
+    K: Encode + Decode + Hash + Ord + 'static,
+    V: Encode + Decode + Hash + Ord + 'static,
+> {
+    pub(crate) entries: Vec<LogEntry<K, V>>,
+    pub(crate) skipped: Vec<SkippedRecord>,
+    pub(crate) trailing_bytes: usize,
+    // just past the last whole record, where the next one should go
+    pub(crate) end: usize,
+    // a frame with a length no frame can have, where reading stopped
+    pub(crate) unreadable: Option<SkippedRecord>,
+}
+
+impl<K, V> LogEntry<K, V>
+where
+    K: Encode + Decode + Hash + Ord + 'static,
+    V: Encode + Decode + Hash + Ord + 'static,
+{
+    pub(crate) fn new(seq: u64, key: K, value: Option<V>) -> LogEntry<K, V> {
+        LogEntry { seq, key, value }
+    }
+
+    /// The entry framed as it goes in the log.
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        frame(bincode::encode_to_vec(self, config())?)
+    }
+}
+
+/// Buffers for framing log records that are cleared rather than freed
+/// between records, so a stream of writes stops allocating for them once
+/// they've grown to fit.
+#[derive(Default)]
+pub(crate) struct Encoder {
+    // the entry, before framing
+    body: Vec<u8>,
+    // the framed record
+    record: Vec<u8>,
+    hint: usize,
+}
+
+impl Encoder {
+    pub(crate) fn new(hint: usize) -> Encoder {
+        Encoder {
+            body: Vec::with_capacity(hint),
+            record: Vec::with_capacity(hint),
+            hint,
+        }
+    }
+
+    /// `entry` framed the same as `LogEntry::encode` frames it.
+    pub(crate) fn encode<K, V>(&mut self, entry: &LogEntry<K, V>) -> Result<&[u8]>
+    where
+        K: Encode + Decode + Hash + Ord + 'static,
+        V: Encode + Decode + Hash + Ord + 'static,
+    {
+        let keep = self.hint.max(ENCODER_KEEP);
+        for buffer in [&mut self.body, &mut self.record] {
+            buffer.clear();
+            buffer.shrink_to(keep);
+        }
+        bincode::encode_into_std_write(entry, &mut self.body, config())?;
+        frame_into(&self.body, &mut self.record)?;
+        Ok(&self.record)
+    }
+}
+
+/// `entries` framed together as one record.
+pub(crate) fn encode_batch<K, V>(entries: &[LogEntry<K, V>]) -> Result<Vec<u8>>
+where
+    K: Encode + Decode + Hash + Ord + 'static,
+    V: Encode + Decode + Hash + Ord + 'static,
+{
+    let mut bytes = vec![];
+    for entry in entries {
+        bytes.extend(bincode::encode_to_vec(entry, config())?);
+    }
+    frame(bytes)
+}
+
+/// `entries` framed one by one between transaction markers.
+pub(crate) fn encode_txn<K, V>(entries: &[LogEntry<K, V>]) -> Result<Vec<u8>>
+where
+    K: Encode + Decode + Hash + Ord + 'static,
+    V: Encode + Decode + Hash + Ord + 'static,
+{
+    let mut bytes = marker(Codec::TxnBegin)?;
+    for entry in entries {
+        bytes.extend(entry.encode()?);
+    }
+    bytes.extend(marker(Codec::TxnCommit)?);
+    Ok(bytes)
+}
+
+fn marker(codec: Codec) -> Result<Vec<u8>> {
+    let frame = Frame {
+        codec,
+        crc: crc32fast::hash(&[]),
+        bytes: vec![],
+    };
+    Ok(bincode::encode_to_vec(frame, config())?)
+}
+
+fn frame(bytes: Vec<u8>) -> Result<Vec<u8>> {
+    let mut record = vec![];
+    frame_into(&bytes, &mut record)?;
+    Ok(record)
+}
+
+/// Appends `bytes` to `out` as a `Frame`, encoded field by field as its
+/// derive would, without copying them into one first.
+fn frame_into(bytes: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    let compressed = compress(bytes);
+    let (codec, bytes) = match &compressed {
+        Some(compressed) => (Codec::Lz4, compressed.as_slice()),
+        None => (Codec::Raw, bytes),
+    };
+    bincode::encode_into_std_write(codec, out, config())?;
+    bincode::encode_into_std_write(crc32fast::hash(bytes), out, config())?;
+    bincode::encode_into_std_write(bytes, out, config())?;
+    Ok(())
+}
+
+// only worth it when it actually shrinks the record, which small ones often don't
+#[cfg(feature = "wal-compression")]
+fn compress(bytes: &[u8]) -> Option<Vec<u8>> {
+    let compressed = lz4_flex::compress_prepend_size(bytes);
+    (compressed.len() < bytes.len()).then_some(compressed)
+}
+
+#[cfg(not(feature = "wal-compression"))]
+fn compress(_: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+fn decompress(codec: Codec, bytes: Vec<u8>) -> Result<Vec<u8>> {
+    match codec {
+        Codec::Raw | Codec::TxnBegin | Codec::TxnCommit => Ok(bytes),
+        #[cfg(feature = "wal-compression")]
+        Codec::Lz4 => Ok(lz4_flex::decompress_size_prepended(&bytes)?),
+        #[cfg(not(feature = "wal-compression"))]
+        Codec::Lz4 => {
+            anyhow::bail!("log holds compressed records; build with the `wal-compression` feature")
+        }
+    }
+}
+
+/// Reads the records in `content`, the log at `path`, until the end of the log
+/// or the first frame that can't be decoded, after which there's no telling
+/// where the next record would start. One with a length past
+/// `format::DECODE_LIMIT` is damaged rather than cut off, so it's listed in
+/// `skipped` like a record that fails its CRC, and left in `unreadable`.
+///
+/// A record that passes its CRC but can't be decompressed or decoded is an
+/// error rather than skipped, since that's a mismatch in how it was written
+/// and not damage. Transactions without a commit marker, or with a skipped
+/// record, are left out. One still open at the end of the log was cut short,
+/// so `end` is put back at its begin marker.
+pub(crate) fn read_records<K, V>(content: &[u8], path: &Path) -> Result<Records<K, V>>
+where
+    K: Encode + Decode + Hash + Ord + 'static,
+    V: Encode + Decode + Hash + Ord + 'static,
+{
+    let mut records = Records {
+        entries: vec![],
+        skipped: vec![],
+        trailing_bytes: 0,
+        end: 0,
+        unreadable: None,
+    };
+    // a log that was never written to may not even have its header, or only
+    // the part of it a torn write got in
+    if content.len() < format::HEADER_LEN && format::header(format::WAL).starts_with(content) {
+        records.trailing_bytes = content.len();
+        return Ok(records);
+    }
+    format::check_header(format::WAL, content, path)?;
+
+    let mut offset = format::HEADER_LEN;
+    let mut index = 0;
+    // entries since a begin marker that hasn't been committed yet, and
+    // whether any of its records were skipped
+    let mut txn: Option<(Vec<LogEntry<K, V>>, bool)> = None;
+    // where the last begin marker is
+    let mut begin = 0;
+    // a `Frame` read field by field, so its length is checked against what's
+    // left before anything is allocated for it
+    while let Ok(((codec, crc, len), read)) =
+        bincode::decode_from_slice::<(Codec, u32, u64), _>(&content[offset..], config())
+    {
+        if len > format::DECODE_LIMIT as u64 {
+            records.skipped.push(SkippedRecord { index, offset });
+            records.unreadable = Some(SkippedRecord { index, offset });
+            break;
+        }
+        let start = offset + read;
+        // cut off, as by a torn write
+        let Some(bytes) = content[start..].get(..len as usize) else {
+            break;
+        };
+        let frame = Frame {
+            codec,
+            crc,
+            bytes: bytes.to_vec(),
+        };
+        if frame.crc != crc32fast::hash(&frame.bytes) {
+            records.skipped.push(SkippedRecord { index, offset });
+            if let Some((_, damaged)) = &mut txn {
+                *damaged = true;
+            }
+        } else if frame.codec == Codec::TxnBegin {
+            // an earlier one left open never committed
+            txn = Some((vec![], false));
+            begin = offset;
+        } else if frame.codec == Codec::TxnCommit {
+            if let Some((entries, false)) = txn.take() {
+                records.entries.extend(entries);
+            }
+        } else {
+            let into = match &mut txn {
+                Some((entries, _)) => entries,
+                None => &mut records.entries,
+            };
+            let bytes = decompress(frame.codec, frame.bytes)?;
+            let mut at = 0;
+            while at < bytes.len() {
+                let (entry, read) =
+                    bincode::decode_from_slice::<LogEntry<K, V>, _>(&bytes[at..], config())?;
+                into.push(entry);
+                at += read;
+            }
+        }
+        offset = start + bytes.len();
+        index += 1;
+    }
+    records.trailing_bytes = content.len() - offset;
+    records.end = if txn.is_some() { begin } else { offset };
+
+    Ok(records)
+}
+
+///
+/// Rebuilds the state held in a log file, without opening the database it
+/// belongs to.
+///
+/// Meant for inspecting orphaned or suspect logs. Records that fail their CRC
+/// are left out and listed in `skipped`. A record whose length no record can
+/// have leaves no telling where the rest are, and fails with
+/// `Error::CorruptRecord`.
+///
+pub fn replay_wal<K, V>(path: &Path) -> Result<WalReplay<K, V>>
+where
+    K: Encode + Decode + Hash + Ord + 'static,
+    V: Encode + Decode + Hash + Ord + 'static,
+{
+    let records = read_records::<K, V>(&fs::read(path)?, path)?;
+    if let Some(record) = records.unreadable {
+        return Err(Error::CorruptRecord {
+            path: path.to_path_buf(),
+            index: record.index,
+        }
+        .into());
+    }
+
+    let mut entries = BTreeMap::new();
+    for entry in records.entries {
+        match entry.value {
+            Some(value) => entries.insert(entry.key, value),
+            None => entries.remove(&entry.key),
+        };
+    }
+
+    Ok(WalReplay {
+        entries,
+        skipped: records.skipped,
+        trailing_bytes: records.trailing_bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_reports_skipped_records() -> Result<()> {
+        let path = std::env::temp_dir().join("mini-lsm-replay-wal.log");
+
+        let records = [
+            LogEntry::new(1, "a".to_string(), Some(1u32)),
+            LogEntry::new(2, "b".to_string(), Some(2)),
+            LogEntry::new(3, "c".to_string(), Some(3)),
+            LogEntry::new(4, "a".to_string(), None),
+            LogEntry::new(5, "d".to_string(), Some(5)),
+        ];
+
+        let mut content = format::header(format::WAL).to_vec();
+        let mut offsets = vec![];
+        for (i, record) in records.iter().enumerate() {
+            offsets.push(content.len());
+            let mut frame = record.encode()?;
+            if i == 1 {
+                // the frame still decodes, but its bytes no longer match the CRC
+                *frame.last_mut().unwrap() ^= 1;
+            }
+            content.extend(frame);
+        }
+        // half of one more record, as if the write was torn
+        let torn = LogEntry::new(6, "e".to_string(), Some(6u32)).encode()?;
+        content.extend(&torn[..torn.len() / 2]);
+        fs::write(&path, content)?;
+
+        let replay = replay_wal::<String, u32>(&path)?;
+        assert_eq!(
+            replay.entries,
+            BTreeMap::from([("c".to_string(), 3), ("d".to_string(), 5)])
+        );
+        assert_eq!(
+            replay.skipped,
+            vec![SkippedRecord {
+                index: 1,
+                offset: offsets[1],
+            }]
+        );
+        assert_eq!(replay.trailing_bytes, torn.len() / 2);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_length_fails_to_decode_rather_than_allocate() -> Result<()> {
+        let path = std::env::temp_dir().join("mini-lsm-corrupt-length.log");
+
+        let mut content = format::header(format::WAL).to_vec();
+        content.extend(LogEntry::new(1, "a".to_string(), Some(1u32)).encode()?);
+        // a raw frame whose length claims about 2^60 bytes
+        let damaged = content.len();
+        content.extend([0, 0, 0xFD]);
+        content.extend([0xFF; 7]);
+        content.extend([0x0F, 1, 2, 3]);
+        fs::write(&path, &content)?;
+
+        let err = replay_wal::<String, u32>(&path).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::CorruptRecord { index: 1, .. })
+        ));
+        let records = read_records::<String, u32>(&content, &path)?;
+        assert_eq!(records.entries.len(), 1);
+        assert_eq!(records.end, damaged);
+
+        // short of the limit but past the end of the log, it's a torn write
+        content.truncate(damaged);
+        content.extend([0, 0, 0x40]);
+        fs::write(&path, &content)?;
+        let replay = replay_wal::<String, u32>(&path)?;
+        assert_eq!(replay.entries, BTreeMap::from([("a".to_string(), 1)]));
+        assert_eq!(replay.trailing_bytes, 3);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
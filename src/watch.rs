@@ -0,0 +1,90 @@
+//! Subscriptions to the writes on a range of keys.
+
+use std::sync::{
+    mpsc::{self, Receiver, SyncSender, TrySendError},
+    Mutex,
+};
+
+/// Changes a subscriber can fall behind by before they start being dropped.
+pub const WATCH_CAPACITY: usize = 256;
+
+/// What a `Lsm::watch` receiver gets, in the order the writes were made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change<K, V> {
+    /// a write to a watched key, with the new value or `None` for a delete
+    Write(K, Option<V>),
+    /// this many changes were dropped here because the receiver fell behind
+    Lagged(u64),
+}
+
+/// Called on a key to see whether a subscriber watches it.
+type Matches<K> = Box<dyn Fn(&K) -> bool + Send + Sync>;
+
+struct Watcher<K, V> {
+    matches: Matches<K>,
+    sender: SyncSender<Change<K, V>>,
+    // changes dropped since the last one that got through
+    missed: u64,
+}
+
+pub(crate) struct Watchers<K, V> {
+    list: Mutex<Vec<Watcher<K, V>>>,
+}
+
+impl<K, V> Default for Watchers<K, V> {
+    fn default() -> Watchers<K, V> {
+        Watchers {
+            list: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl<K: Clone, V: Clone> Watchers<K, V> {
+    pub(crate) fn add(&self, matches: Matches<K>) -> Receiver<Change<K, V>> {
+        let (sender, receiver) = mpsc::sync_channel(WATCH_CAPACITY);
+        self.list().push(Watcher {
+            matches,
+            sender,
+            missed: 0,
+        });
+        receiver
+    }
+
+    /// Tells every subscriber watching `key` about its new value. Never
+    /// blocks: a full buffer drops the change, and the subscriber hears how
+    /// many it missed once there's room. Subscribers whose receiver is gone
+    /// are forgotten.
+    pub(crate) fn notify(&self, key: &K, value: Option<&V>) {
+        let mut list = self.list();
+        list.retain_mut(|watcher| {
+            if !(watcher.matches)(key) {
+                return true;
+            }
+            if watcher.missed > 0 {
+                match watcher.sender.try_send(Change::Lagged(watcher.missed)) {
+                    Ok(()) => watcher.missed = 0,
+                    Err(TrySendError::Full(_)) => {
+                        watcher.missed += 1;
+                        return true;
+                    }
+                    Err(TrySendError::Disconnected(_)) => return false,
+                }
+            }
+            match watcher
+                .sender
+                .try_send(Change::Write(key.clone(), value.cloned()))
+            {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    watcher.missed += 1;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    fn list(&self) -> std::sync::MutexGuard<'_, Vec<Watcher<K, V>>> {
+        self.list.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}